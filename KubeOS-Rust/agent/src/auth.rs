@@ -0,0 +1,184 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    fs,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use serde::Deserialize;
+
+const ALL_METHODS: &str = "*";
+/// Methods that only read the state of os-agent and the node. The other reading methods are left to root, and to the
+/// peers granted them by a rule of the policy file:
+/// - get_config, get_drift and plan_configure reveal the values in the configuration files, which may contain
+///   credentials
+/// - preflight makes os-agent connect to the image URL and registry given by the caller, with the certificates of
+///   the node
+pub const READ_ONLY_METHODS: [&str; 5] =
+    ["get_status", "get_progress", "get_history", "get_capabilities", "list_slots"];
+
+/// PeerInfo is the identity of the process on the other side of a connection, taken from SO_PEERCRED
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+    pub exe: Option<PathBuf>,
+}
+
+impl PeerInfo {
+    pub fn from_stream(stream: &UnixStream) -> Result<Self> {
+        let cred = getsockopt(stream.as_raw_fd(), PeerCredentials).context("Failed to get peer credentials")?;
+        let exe = fs::read_link(format!("/proc/{}/exe", cred.pid())).ok();
+        Ok(PeerInfo { pid: cred.pid(), uid: cred.uid(), gid: cred.gid(), exe })
+    }
+}
+
+/// AuthRule grants the methods to the peers matching any of its uids, gids or executable paths, a rule without any
+/// of them matches every peer
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthRule {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+    #[serde(default)]
+    pub exes: Vec<PathBuf>,
+    pub methods: Vec<String>,
+}
+
+impl AuthRule {
+    fn matches(&self, peer: &PeerInfo) -> bool {
+        if self.uids.is_empty() && self.gids.is_empty() && self.exes.is_empty() {
+            return true;
+        }
+        self.uids.contains(&peer.uid)
+            || self.gids.contains(&peer.gid)
+            || peer.exe.as_ref().is_some_and(|exe| self.exes.contains(exe))
+    }
+
+    fn allows(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == ALL_METHODS || m == method)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthPolicy {
+    #[serde(default)]
+    pub rules: Vec<AuthRule>,
+}
+
+impl Default for AuthPolicy {
+    // root is allowed to call every method, other peers able to connect to the socket can only read
+    fn default() -> Self {
+        AuthPolicy {
+            rules: vec![
+                AuthRule { uids: vec![0], methods: vec![ALL_METHODS.to_string()], ..Default::default() },
+                AuthRule { methods: READ_ONLY_METHODS.iter().map(|m| m.to_string()).collect(), ..Default::default() },
+            ],
+        }
+    }
+}
+
+impl AuthPolicy {
+    /// Load the policy from a toml file, the default policy is used if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            info!("Authorization policy {} not found, use the default policy", path.display());
+            return Ok(AuthPolicy::default());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read authorization policy {}", path.display()))?;
+        let policy: AuthPolicy = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse authorization policy {}", path.display()))?;
+        debug!("Loaded authorization policy: {:?}", policy);
+        Ok(policy)
+    }
+
+    pub fn is_allowed(&self, peer: &PeerInfo, method: &str) -> bool {
+        self.rules.iter().any(|rule| rule.matches(peer) && rule.allows(method))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = AuthPolicy::default();
+        let root = PeerInfo { pid: 1, uid: 0, gid: 0, exe: None };
+        let monitor = PeerInfo { pid: 2, uid: 1000, gid: 0, exe: Some("/usr/bin/monitor".into()) };
+        assert!(policy.is_allowed(&root, "upgrade"));
+        assert!(policy.is_allowed(&monitor, "get_status"));
+        assert!(!policy.is_allowed(&monitor, "upgrade"));
+        assert!(!policy.is_allowed(&monitor, "rollback"));
+        for method in ["get_config", "get_drift", "plan_configure", "preflight"] {
+            assert!(!policy.is_allowed(&monitor, method));
+            assert!(policy.is_allowed(&root, method));
+        }
+    }
+
+    #[test]
+    fn test_load_policy() {
+        let policy = AuthPolicy::load("/tmp/not-exist-auth.toml").unwrap();
+        assert_eq!(policy, AuthPolicy::default());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"
+[[rules]]
+exes = ["/usr/bin/proxy"]
+methods = ["*"]
+
+[[rules]]
+uids = [1000]
+gids = [1001]
+methods = ["get_status"]
+"#
+        )
+        .unwrap();
+        let policy = AuthPolicy::load(file.path()).unwrap();
+        assert_eq!(policy.rules.len(), 2);
+        let proxy = PeerInfo { pid: 1, uid: 0, gid: 0, exe: Some("/usr/bin/proxy".into()) };
+        let root = PeerInfo { pid: 2, uid: 0, gid: 0, exe: Some("/usr/bin/bash".into()) };
+        let monitor = PeerInfo { pid: 3, uid: 1002, gid: 1001, exe: None };
+        assert!(policy.is_allowed(&proxy, "upgrade"));
+        assert!(!policy.is_allowed(&root, "get_status"));
+        assert!(policy.is_allowed(&monitor, "get_status"));
+        assert!(!policy.is_allowed(&monitor, "get_progress"));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "[[rules]]\nuser = \"root\"\nmethods = [\"*\"]").unwrap();
+        assert!(AuthPolicy::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_peer_info() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let peer = PeerInfo::from_stream(&a).unwrap();
+        assert_eq!(peer.pid, std::process::id() as i32);
+        assert_eq!(peer.uid, nix::unistd::getuid().as_raw());
+        assert!(peer.exe.is_some());
+    }
+}
@@ -0,0 +1,367 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{fs, net::SocketAddr, path::Path};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use log::{info, warn};
+use manager::sys_mgmt::{
+    CERTS_PATH, DEFAULT_GRUBENV_PATH, DEFAULT_GRUB_CFG_PATH, MAX_BOOT_ATTEMPTS, NEED_BYTES, PERSIST_DIR,
+};
+use serde::Deserialize;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/KubeOS/os-agent/config.toml";
+pub const DEFAULT_SOCK_PATH: &str = "/run/os-agent/os-agent.sock";
+pub const DEFAULT_AUTH_POLICY_PATH: &str = "/etc/KubeOS/os-agent/auth.toml";
+pub const DEFAULT_HOOKS_DIR: &str = "/etc/KubeOS/hooks";
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+pub const DEFAULT_STALL_TIMEOUT: u64 = 3600;
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 600;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+pub const DEFAULT_DRIFT_CHECK_INTERVAL: u64 = 600;
+
+/// Options are the command-line options of os-agent, which override the values in the config file
+#[derive(Parser, Debug, Default)]
+#[clap(name = "os-agent", version, about = "KubeOS os-agent serving the upgrade and configuration requests")]
+pub struct Options {
+    /// Path of the config file, /etc/KubeOS/os-agent/config.toml is loaded if it exists
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Path of the unix socket to listen on
+    #[arg(long, value_name = "PATH")]
+    pub socket_path: Option<String>,
+    /// Path of the authorization policy of the socket
+    #[arg(long = "auth-policy", value_name = "PATH")]
+    pub auth_policy_path: Option<String>,
+    /// Directory of the persist partition to stage upgrade images
+    #[arg(long, value_name = "PATH")]
+    pub persist_dir: Option<String>,
+    /// Directory of the certificates to download disk images
+    #[arg(long, value_name = "PATH")]
+    pub certs_path: Option<String>,
+    /// Path of grub.cfg modified by grub.cmdline configurations
+    #[arg(long, value_name = "PATH")]
+    pub grub_cfg_path: Option<String>,
+    /// Path of grubenv to switch the boot partition
+    #[arg(long, value_name = "PATH")]
+    pub grubenv_path: Option<String>,
+    /// Free space required in the persist directory to prepare an upgrade
+    #[arg(long, value_name = "BYTES", allow_negative_numbers = true)]
+    pub need_bytes: Option<i64>,
+    /// Directory of {pre,post}-{prepare-upgrade,upgrade,rollback,configure}.d hooks [default: /etc/KubeOS/hooks]
+    #[arg(long, value_name = "PATH")]
+    pub hooks_dir: Option<String>,
+    /// Kill a hook running longer than this [default: 300]
+    #[arg(long, value_name = "SECS")]
+    pub hook_timeout: Option<u64>,
+    /// Do not reboot after upgrade or rollback
+    #[arg(long)]
+    pub disable_reboot: bool,
+    /// Boot the next partition by kexec after upgrade or rollback, skipping the firmware
+    #[arg(long)]
+    pub kexec: bool,
+    /// Stop the systemd watchdog keep-alives if an upgrade preparation makes no progress for this long
+    /// [default: 3600]
+    #[arg(long, value_name = "SECS")]
+    pub stall_timeout: Option<u64>,
+    /// Serve metrics on http://ADDR/metrics, such as 127.0.0.1:9101 [default: disabled]
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_address: Option<String>,
+    /// Boot the new partition at most N times after upgrade until the health checks pass, 0 switches the partition
+    /// without health checks [default: 3, max: 3]
+    #[arg(long, value_name = "N")]
+    pub boot_attempts: Option<u32>,
+    /// Refuse to roll back to a partition older than this version [default: disabled]
+    #[arg(long, value_name = "VERSION")]
+    pub min_rollback_version: Option<String>,
+    /// Compare the configured values with the current ones every SECS seconds, 0 disables the check [default: 600]
+    #[arg(long, value_name = "SECS")]
+    pub drift_check_interval: Option<u64>,
+}
+
+/// AgentConfig is loaded from the config file, the command-line options override the values in the file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    pub socket_path: String,
+    pub auth_policy_path: String,
+    pub persist_dir: String,
+    pub certs_path: String,
+    pub grub_cfg_path: String,
+    pub grubenv_path: String,
+    pub need_bytes: i64,
+    pub hooks_dir: String,
+    pub hook_timeout: u64,
+    pub disable_reboot: bool,
+    pub kexec: bool,
+    pub stall_timeout: u64,
+    pub metrics_address: Option<String>,
+    pub boot_attempts: u32,
+    pub min_rollback_version: Option<String>,
+    pub drift_check_interval: u64,
+    pub health_check: HealthCheckConfig,
+}
+
+/// HealthCheckConfig is the checks run after booting the new partition of an upgrade, which are retried until all of
+/// them pass or the timeout is reached
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// systemd units which must be active
+    pub units: Vec<String>,
+    /// URL of the kubelet healthz endpoint, such as http://127.0.0.1:10248/healthz
+    pub kubelet_healthz: Option<String>,
+    /// executables which must exit with 0
+    pub scripts: Vec<String>,
+    pub timeout: u64,
+    pub interval: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            units: Vec::new(),
+            kubelet_healthz: None,
+            scripts: Vec::new(),
+            timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
+            interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+        }
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            socket_path: DEFAULT_SOCK_PATH.to_string(),
+            auth_policy_path: DEFAULT_AUTH_POLICY_PATH.to_string(),
+            persist_dir: PERSIST_DIR.to_string(),
+            certs_path: CERTS_PATH.to_string(),
+            grub_cfg_path: DEFAULT_GRUB_CFG_PATH.to_string(),
+            grubenv_path: DEFAULT_GRUBENV_PATH.to_string(),
+            need_bytes: NEED_BYTES,
+            hooks_dir: DEFAULT_HOOKS_DIR.to_string(),
+            hook_timeout: DEFAULT_HOOK_TIMEOUT,
+            disable_reboot: false,
+            kexec: false,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            metrics_address: None,
+            boot_attempts: MAX_BOOT_ATTEMPTS,
+            min_rollback_version: None,
+            drift_check_interval: DEFAULT_DRIFT_CHECK_INTERVAL,
+            health_check: HealthCheckConfig::default(),
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Load the config from the file given by --config or the default config file, then apply the other command-line
+    /// options and validate the result. A missing default config file is not an error.
+    pub fn load(options: Options) -> Result<Self> {
+        let file = match &options.config {
+            Some(path) => AgentConfig::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => AgentConfig::from_file(DEFAULT_CONFIG_PATH)?,
+            None => AgentConfig::default(),
+        };
+        let config = AgentConfig {
+            socket_path: options.socket_path.unwrap_or(file.socket_path),
+            auth_policy_path: options.auth_policy_path.unwrap_or(file.auth_policy_path),
+            persist_dir: options.persist_dir.unwrap_or(file.persist_dir),
+            certs_path: options.certs_path.unwrap_or(file.certs_path),
+            grub_cfg_path: options.grub_cfg_path.unwrap_or(file.grub_cfg_path),
+            grubenv_path: options.grubenv_path.unwrap_or(file.grubenv_path),
+            need_bytes: options.need_bytes.unwrap_or(file.need_bytes),
+            hooks_dir: options.hooks_dir.unwrap_or(file.hooks_dir),
+            hook_timeout: options.hook_timeout.unwrap_or(file.hook_timeout),
+            disable_reboot: options.disable_reboot || file.disable_reboot,
+            kexec: options.kexec || file.kexec,
+            stall_timeout: options.stall_timeout.unwrap_or(file.stall_timeout),
+            metrics_address: options.metrics_address.or(file.metrics_address),
+            boot_attempts: options.boot_attempts.unwrap_or(file.boot_attempts),
+            min_rollback_version: options.min_rollback_version.or(file.min_rollback_version),
+            drift_check_interval: options.drift_check_interval.unwrap_or(file.drift_check_interval),
+            health_check: file.health_check,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        info!("Load config file {}", path.display());
+        let content = fs::read_to_string(path).with_context(|| format!("Failed to read config {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Failed to parse config {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, path) in [
+            ("socket_path", &self.socket_path),
+            ("auth_policy_path", &self.auth_policy_path),
+            ("persist_dir", &self.persist_dir),
+            ("certs_path", &self.certs_path),
+            ("grub_cfg_path", &self.grub_cfg_path),
+            ("grubenv_path", &self.grubenv_path),
+            ("hooks_dir", &self.hooks_dir),
+        ] {
+            if !Path::new(path).is_absolute() {
+                bail!("{} must be an absolute path, got \"{}\"", name, path);
+            }
+        }
+        if !Path::new(&self.persist_dir).is_dir() {
+            bail!("persist_dir {} is not a directory", self.persist_dir);
+        }
+        if self.need_bytes <= 0 {
+            bail!("need_bytes must be positive, got {}", self.need_bytes);
+        }
+        if self.stall_timeout == 0 {
+            bail!("stall_timeout must be positive");
+        }
+        if self.hook_timeout == 0 {
+            bail!("hook_timeout must be positive");
+        }
+        if let Some(address) = &self.metrics_address {
+            address
+                .parse::<SocketAddr>()
+                .with_context(|| format!("metrics_address must be an IP address with port, got \"{}\"", address))?;
+        }
+        if self.boot_attempts > MAX_BOOT_ATTEMPTS {
+            bail!("boot_attempts must be at most {}, got {}", MAX_BOOT_ATTEMPTS, self.boot_attempts);
+        }
+        self.health_check.validate()?;
+        // grub files depend on the boot mode, they are checked when used
+        for path in [&self.grub_cfg_path, &self.grubenv_path] {
+            if !Path::new(path).exists() {
+                warn!("{} does not exist", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HealthCheckConfig {
+    fn validate(&self) -> Result<()> {
+        if self.timeout == 0 || self.interval == 0 {
+            bail!("health_check.timeout and health_check.interval must be positive");
+        }
+        for script in self.scripts.iter() {
+            if !Path::new(script).is_absolute() {
+                bail!("health_check.scripts must be absolute paths, got \"{}\"", script);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<AgentConfig> {
+        let options = Options::try_parse_from(std::iter::once("os-agent").chain(args.iter().copied()))?;
+        AgentConfig::load(options)
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options =
+            Options::try_parse_from(["os-agent", "--persist-dir", "/data", "--need-bytes=1024", "--disable-reboot"])
+                .unwrap();
+        assert_eq!(options.persist_dir.as_deref(), Some("/data"));
+        assert_eq!(options.need_bytes, Some(1024));
+        assert!(options.disable_reboot);
+        assert!(!options.kexec);
+        assert_eq!(options.socket_path, None);
+        assert!(Options::try_parse_from(["os-agent", "persist-dir"]).is_err());
+        assert!(Options::try_parse_from(["os-agent", "--persist-dir"]).is_err());
+        assert!(Options::try_parse_from(["os-agent", "--disable-reboot=true"]).is_err());
+    }
+
+    #[test]
+    fn test_load_config() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let persist_dir = tmp_dir.path().to_str().unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "persist_dir = \"{}\"\ngrub_cfg_path = \"/boot/efi/EFI/vendor/grub.cfg\"\nneed_bytes = 1024\n\n\
+             [health_check]\nunits = [\"kubelet.service\"]\nscripts = [\"/usr/bin/true\"]\ntimeout = 60",
+            persist_dir
+        )
+        .unwrap();
+        let config_path = file.path().to_str().unwrap();
+
+        let config = load(&["--config", config_path]).unwrap();
+        assert_eq!(config.persist_dir, persist_dir);
+        assert_eq!(config.grub_cfg_path, "/boot/efi/EFI/vendor/grub.cfg");
+        assert_eq!(config.need_bytes, 1024);
+        assert_eq!(config.socket_path, DEFAULT_SOCK_PATH);
+        assert!(!config.disable_reboot);
+        assert_eq!(config.boot_attempts, MAX_BOOT_ATTEMPTS);
+        assert_eq!(config.drift_check_interval, DEFAULT_DRIFT_CHECK_INTERVAL);
+        assert_eq!(config.health_check.units, vec!["kubelet.service".to_string()]);
+        assert_eq!(config.health_check.scripts, vec!["/usr/bin/true".to_string()]);
+        assert_eq!(config.health_check.timeout, 60);
+        assert_eq!(config.health_check.interval, DEFAULT_HEALTH_CHECK_INTERVAL);
+
+        // command-line options override the config file
+        let config = load(&[
+            "--grubenv-path=/boot/efi/EFI/vendor/grubenv",
+            "--config",
+            config_path,
+            "--need-bytes",
+            "2048",
+            "--disable-reboot",
+            "--kexec",
+            "--stall-timeout=600",
+            "--hooks-dir=/etc/KubeOS/site-hooks",
+            "--metrics-address",
+            "127.0.0.1:9101",
+            "--boot-attempts=0",
+            "--min-rollback-version",
+            "KubeOS 1.0.2",
+            "--drift-check-interval=0",
+        ])
+        .unwrap();
+        assert_eq!(config.grubenv_path, "/boot/efi/EFI/vendor/grubenv");
+        assert_eq!(config.need_bytes, 2048);
+        assert!(config.disable_reboot);
+        assert!(config.kexec);
+        assert_eq!(config.stall_timeout, 600);
+        assert_eq!(config.hooks_dir, "/etc/KubeOS/site-hooks");
+        assert_eq!(config.hook_timeout, DEFAULT_HOOK_TIMEOUT);
+        assert_eq!(config.metrics_address.as_deref(), Some("127.0.0.1:9101"));
+        assert_eq!(config.boot_attempts, 0);
+        assert_eq!(config.min_rollback_version.as_deref(), Some("KubeOS 1.0.2"));
+        assert_eq!(config.drift_check_interval, 0);
+
+        assert!(load(&["--config", config_path, "--need-bytes", "-1"]).is_err());
+        assert!(load(&["--config", config_path, "--need-bytes", "xxx"]).is_err());
+        assert!(load(&["--config", config_path, "--stall-timeout", "0"]).is_err());
+        assert!(load(&["--config", config_path, "--boot-attempts", "4"]).is_err());
+        assert!(load(&["--config", config_path, "--hook-timeout", "0"]).is_err());
+        assert!(load(&["--config", config_path, "--metrics-address", "localhost"]).is_err());
+        assert!(load(&["--config", config_path, "--socket-path", "os-agent.sock"]).is_err());
+        assert!(load(&["--config", config_path, "--persist-dir", "/not/exist/dir"]).is_err());
+        assert!(load(&["--config", config_path, "--unknown", "xxx"]).is_err());
+        assert!(load(&["--config", "/not/exist/config.toml"]).is_err());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "persist = \"/data\"").unwrap();
+        assert!(AgentConfig::from_file(file.path()).is_err());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "persist_dir = \"{}\"\n[health_check]\nscripts = [\"check.sh\"]", persist_dir).unwrap();
+        assert!(load(&["--config", file.path().to_str().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use manager::utils::CommandExecutor;
+use reqwest::blocking::Client;
+
+use crate::config::HealthCheckConfig;
+
+const HEALTHZ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run all the checks once, returns the first failure
+pub fn check_health<T: CommandExecutor>(config: &HealthCheckConfig, command_executor: &T) -> Result<()> {
+    for unit in config.units.iter() {
+        command_executor
+            .run_command("systemctl", &["is-active", "--quiet", unit])
+            .with_context(|| format!("Unit {} is not active", unit))?;
+    }
+    if let Some(url) = &config.kubelet_healthz {
+        check_healthz(url)?;
+    }
+    for script in config.scripts.iter() {
+        command_executor.run_command(script, &[]).with_context(|| format!("Health check {} failed", script))?;
+    }
+    Ok(())
+}
+
+fn check_healthz(url: &str) -> Result<()> {
+    let client = Client::builder().timeout(HEALTHZ_TIMEOUT).build()?;
+    let resp = client.get(url).send().with_context(|| format!("Failed to request kubelet healthz {}", url))?;
+    let status = resp.status();
+    let body = resp.text().unwrap_or_default();
+    if !status.is_success() || body.trim() != "ok" {
+        bail!("kubelet healthz {} returned {}: {}", url, status, body.trim());
+    }
+    Ok(())
+}
+
+/// Retry the checks every interval until all of them pass or the timeout is reached
+pub fn wait_healthy<T: CommandExecutor>(config: &HealthCheckConfig, command_executor: &T) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    loop {
+        match check_health(config, command_executor) {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(e.context(format!("Health checks did not pass within {}s", config.timeout)));
+            },
+            Err(e) => {
+                debug!("Health checks did not pass yet: {:#}", e);
+                thread::sleep(Duration::from_secs(config.interval));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::mock;
+
+    use super::*;
+
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    #[test]
+    fn test_check_health() {
+        let _healthz = mockito::mock("GET", "/healthz").with_status(200).with_body("ok").create();
+        let config = HealthCheckConfig {
+            units: vec!["kubelet.service".to_string()],
+            kubelet_healthz: Some(format!("{}/healthz", mockito::server_url())),
+            scripts: vec!["/usr/bin/check.sh".to_string()],
+            ..Default::default()
+        };
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command()
+            .withf(|name, args| name == "systemctl" && args == ["is-active", "--quiet", "kubelet.service"])
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock.expect_run_command().withf(|name, _| name == "/usr/bin/check.sh").times(1).returning(|_, _| Ok(()));
+        check_health(&config, &mock).unwrap();
+
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command().returning(|_, _| bail!("inactive"));
+        let config = HealthCheckConfig { timeout: 1, interval: 1, ..config };
+        let err = wait_healthy(&config, &mock).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unit kubelet.service is not active"));
+
+        let _unhealthy = mockito::mock("GET", "/unhealthy").with_status(500).with_body("[-]ping failed").create();
+        let config = HealthCheckConfig {
+            units: Vec::new(),
+            kubelet_healthz: Some(format!("{}/unhealthy", mockito::server_url())),
+            scripts: Vec::new(),
+            ..config
+        };
+        assert!(check_health(&config, &MockCommandExec::new()).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{error, info};
+use manager::utils::{result_label, CommandExecutor};
+
+pub const OPERATION_PREPARE_UPGRADE: &str = "prepare-upgrade";
+pub const OPERATION_UPGRADE: &str = "upgrade";
+pub const OPERATION_ROLLBACK: &str = "rollback";
+pub const OPERATION_CONFIGURE: &str = "configure";
+
+// hooks ignoring SIGTERM are killed after the grace period
+const KILL_AFTER: &str = "--kill-after=10s";
+
+/// Hooks are the executables in <dir>/{pre,post}-<operation>.d/, which run in the order of their file names. Every
+/// hook gets KUBEOS_OPERATION, KUBEOS_HOOK_STAGE and the metadata of the request as environment variables.
+#[derive(Clone, Debug)]
+pub struct Hooks {
+    dir: PathBuf,
+    timeout: u64,
+}
+
+impl Hooks {
+    pub fn new<P: AsRef<Path>>(dir: P, timeout: u64) -> Self {
+        Hooks { dir: dir.as_ref().to_path_buf(), timeout }
+    }
+
+    /// Run the operation between its pre-hooks and post-hooks, the operation is aborted if any pre-hook fails
+    pub fn wrap<T: CommandExecutor, R, F: FnOnce() -> Result<R>>(
+        &self,
+        executor: &T,
+        operation: &str,
+        env: &[(&str, String)],
+        f: F,
+    ) -> Result<R> {
+        self.run_pre(executor, operation, env)?;
+        let result = f();
+        self.run_post(executor, operation, env, &result);
+        result
+    }
+
+    /// Run the pre-hooks of the operation, the operation must be aborted if any of them fails
+    pub fn run_pre<T: CommandExecutor>(&self, executor: &T, operation: &str, env: &[(&str, String)]) -> Result<()> {
+        self.run(executor, "pre", operation, env).with_context(|| format!("Pre-{} hook failed", operation))
+    }
+
+    /// Run the post-hooks of the operation with its result in KUBEOS_RESULT, failures are only logged as the
+    /// operation is already done
+    pub fn run_post<T: CommandExecutor, R>(
+        &self,
+        executor: &T,
+        operation: &str,
+        env: &[(&str, String)],
+        result: &Result<R>,
+    ) {
+        let mut env = env.to_vec();
+        env.push(("KUBEOS_RESULT", result_label(result).to_string()));
+        if let Err(e) = self.run(executor, "post", operation, &env) {
+            error!("Post-{} hook failed: {:#}", operation, e);
+        }
+    }
+
+    fn run<T: CommandExecutor>(
+        &self,
+        executor: &T,
+        stage: &str,
+        operation: &str,
+        env: &[(&str, String)],
+    ) -> Result<()> {
+        let timeout = format!("{}s", self.timeout);
+        let mut vars = vec![format!("KUBEOS_OPERATION={}", operation), format!("KUBEOS_HOOK_STAGE={}", stage)];
+        vars.extend(env.iter().map(|(key, value)| format!("{}={}", key, value)));
+        for hook in self.list(stage, operation)? {
+            let hook_str = hook.to_str().context("Failed to convert hook path to string")?;
+            info!("Run {}-{} hook {}", stage, operation, hook_str);
+            let mut args = vec![KILL_AFTER, &timeout, "env"];
+            args.extend(vars.iter().map(|var| var.as_str()));
+            args.push(hook_str);
+            executor.run_command("timeout", &args).with_context(|| format!("Hook {} failed", hook_str))?;
+        }
+        Ok(())
+    }
+
+    // list returns the executable files in the hook directory sorted by file name, non-executable files such as
+    // README are skipped
+    fn list(&self, stage: &str, operation: &str) -> Result<Vec<PathBuf>> {
+        let dir = self.dir.join(format!("{}-{}.d", stage, operation));
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut hooks = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read hook directory {}", dir.display()))? {
+            let path = entry?.path();
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+                hooks.push(path);
+            }
+        }
+        hooks.sort();
+        Ok(hooks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+    use manager::utils::RealCommandExecutor;
+    use mockall::mock;
+
+    use super::*;
+
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    fn write_hook(path: &Path, content: &str, mode: u32) {
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let hook_dir = tmp_dir.path().join("pre-upgrade.d");
+        fs::create_dir(&hook_dir).unwrap();
+        write_hook(&hook_dir.join("20-second"), "#!/bin/sh\n", 0o755);
+        write_hook(&hook_dir.join("10-first"), "#!/bin/sh\n", 0o755);
+        write_hook(&hook_dir.join("README"), "hooks\n", 0o644);
+        let hooks = Hooks::new(tmp_dir.path(), 30);
+
+        let mut seq = mockall::Sequence::new();
+        let mut mock = MockCommandExec::new();
+        for name in ["10-first", "20-second"] {
+            let hook = hook_dir.join(name).to_str().unwrap().to_string();
+            mock.expect_run_command()
+                .withf(move |cmd, args| {
+                    cmd == "timeout"
+                        && args
+                            == [
+                                KILL_AFTER,
+                                "30s",
+                                "env",
+                                "KUBEOS_OPERATION=upgrade",
+                                "KUBEOS_HOOK_STAGE=pre",
+                                "KUBEOS_NEXT_PARTITION=B",
+                                hook.as_str(),
+                            ]
+                })
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_, _| Ok(()));
+        }
+        hooks.run_pre(&mock, OPERATION_UPGRADE, &[("KUBEOS_NEXT_PARTITION", "B".to_string())]).unwrap();
+
+        // hooks after the failed one are not run
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command().times(1).returning(|_, _| bail!("exit status 1"));
+        assert!(hooks.run_pre(&mock, OPERATION_UPGRADE, &[]).is_err());
+
+        // no hook directory
+        hooks.run_pre(&MockCommandExec::new(), OPERATION_ROLLBACK, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_run_real_hooks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let output = tmp_dir.path().join("output");
+        let hook_dir = tmp_dir.path().join("post-configure.d");
+        fs::create_dir(&hook_dir).unwrap();
+        write_hook(
+            &hook_dir.join("10-record"),
+            &format!("#!/bin/sh\necho \"$KUBEOS_OPERATION $KUBEOS_RESULT\" > {}\n", output.display()),
+            0o755,
+        );
+        let hooks = Hooks::new(tmp_dir.path(), 30);
+        hooks.run_post(&RealCommandExecutor {}, OPERATION_CONFIGURE, &[], &Ok(()));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "configure success\n");
+
+        let hook_dir = tmp_dir.path().join("pre-configure.d");
+        fs::create_dir(&hook_dir).unwrap();
+        write_hook(&hook_dir.join("10-sleep"), "#!/bin/sh\nsleep 10\n", 0o755);
+        let hooks = Hooks::new(tmp_dir.path(), 1);
+        assert!(hooks.run_pre(&RealCommandExecutor {}, OPERATION_CONFIGURE, &[]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use log::info;
+use manager::api::LockHolder;
+
+/// OperationLock serializes the operations changing the node. It records which operation holds it since when, and
+/// lets the callers wait for it to be released instead of failing at once.
+#[derive(Debug, Clone, Default)]
+pub struct OperationLock {
+    inner: Arc<(Mutex<Option<LockHolder>>, Condvar)>,
+}
+
+/// OperationGuard releases the lock and wakes up the waiting callers when it is dropped
+#[derive(Debug)]
+pub struct OperationGuard {
+    lock: OperationLock,
+}
+
+impl OperationLock {
+    /// Acquire the lock, or fail with the current holder if it is not released within the timeout
+    pub fn acquire(&self, operation: &str, timeout: Duration) -> Result<OperationGuard> {
+        self.acquire_until(operation, Some(Instant::now() + timeout))
+    }
+
+    pub fn acquire_blocking(&self, operation: &str) -> OperationGuard {
+        self.acquire_until(operation, None).expect("waiting for the lock without deadline never fails")
+    }
+
+    pub fn holder(&self) -> Option<LockHolder> {
+        self.inner.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn acquire_until(&self, operation: &str, deadline: Option<Instant>) -> Result<OperationGuard> {
+        let (mutex, condvar) = &*self.inner;
+        let mut holder = mutex.lock().unwrap_or_else(|e| e.into_inner());
+        let mut waited = false;
+        while let Some(current) = holder.as_ref() {
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                bail!(
+                    "os-agent is processing another request: {} since {} ({}s ago)",
+                    current.operation,
+                    current.since,
+                    unix_now().saturating_sub(current.since)
+                );
+            }
+            if !waited {
+                info!("Wait for {} to finish before {}", current.operation, operation);
+                waited = true;
+            }
+            holder = match deadline {
+                Some(deadline) => condvar.wait_timeout(holder, deadline - now).unwrap_or_else(|e| e.into_inner()).0,
+                None => condvar.wait(holder).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        *holder = Some(LockHolder { operation: operation.to_string(), since: unix_now() });
+        Ok(OperationGuard { lock: self.clone() })
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        let (mutex, condvar) = &*self.lock.inner;
+        *mutex.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        condvar.notify_all();
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_operation_lock() {
+        let lock = OperationLock::default();
+        assert_eq!(lock.holder(), None);
+        let guard = lock.acquire("upgrade", Duration::ZERO).unwrap();
+        assert_eq!(lock.holder().unwrap().operation, "upgrade");
+
+        let err = lock.acquire("configure", Duration::from_millis(10)).unwrap_err();
+        assert!(err.to_string().starts_with("os-agent is processing another request: upgrade since"));
+
+        // the waiting caller gets the lock once it is released
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.acquire("configure", Duration::from_secs(10)).map(|_| ()))
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        waiter.join().unwrap().unwrap();
+        assert_eq!(lock.holder(), None);
+
+        let _guard = lock.acquire_blocking("cancel");
+        assert_eq!(lock.holder().unwrap().operation, "cancel");
+    }
+}
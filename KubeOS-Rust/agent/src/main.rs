@@ -12,55 +12,100 @@
 
 use std::{
     fs::{self, DirBuilder, Permissions},
-    os::unix::fs::{DirBuilderExt, PermissionsExt},
-    path::Path,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::PathBuf,
 };
 
-use env_logger::{Builder, Env, Target};
+use clap::Parser;
 use jsonrpc_core::{IoHandler, IoHandlerExtension};
-use jsonrpc_ipc_server::ServerBuilder;
 
+mod auth;
+mod config;
 mod function;
+mod health;
+mod hooks;
+mod lock;
 mod rpc;
+mod server;
 
-use log::info;
+use auth::AuthPolicy;
+use config::{AgentConfig, Options};
+use log::{error, info, warn};
+use manager::utils::{
+    init_logger, sd_notify, serve_metrics, spawn_watchdog, Metric, CONFIGURE_CALLS, DOWNLOAD_BYTES, DOWNLOAD_DURATION,
+    IMAGE_BUILD_DURATION, IMAGE_INSTALL_DURATION,
+};
 use rpc::{Agent, AgentImpl};
+use server::RPC_CALLS;
 
-const SOCK_PATH: &str = "/run/os-agent/os-agent.sock";
 const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+static AGENT_METRICS: [&dyn Metric; 6] =
+    [&RPC_CALLS, &DOWNLOAD_BYTES, &DOWNLOAD_DURATION, &IMAGE_BUILD_DURATION, &IMAGE_INSTALL_DURATION, &CONFIGURE_CALLS];
 
-fn start_and_run(sock_path: &str) {
-    let socket_path = Path::new(sock_path);
+fn start_and_run(config: AgentConfig) {
+    let socket_path = PathBuf::from(&config.socket_path);
+    let policy = AuthPolicy::load(&config.auth_policy_path).expect("Couldn't load authorization policy");
 
     // Create directory for socket if it doesn't exist
     if let Some(dir_path) = socket_path.parent() {
         if !dir_path.exists() {
-            DirBuilder::new().mode(0o750).create(dir_path).expect("Couldn't create directory for socket");
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o750)
+                .create(dir_path)
+                .expect("Couldn't create directory for socket");
         }
     }
 
+    if let Some(address) = &config.metrics_address {
+        serve_metrics(address, &AGENT_METRICS).expect("Couldn't serve metrics");
+    }
+
     // Add RPC methods to IoHandler
     let mut io = IoHandler::new();
-    AgentImpl::default().to_delegate().augment(&mut io);
+    let agent = AgentImpl::new(config);
+    let watchdog_check = agent.watchdog_check();
+    agent.report_prepare_state();
+    agent.start_boot_check().expect("Couldn't start checking the boot partition");
+    agent.start_drift_check().expect("Couldn't start checking the configuration drift");
+    agent.to_delegate().augment(&mut io);
 
-    // Build and start server
-    let builder = ServerBuilder::new(io);
-    let server = builder.start(sock_path).expect("Couldn't open socket");
+    // Remove the socket left by the previous run and start listening
+    if socket_path.exists() {
+        fs::remove_file(&socket_path).expect("Couldn't remove existing socket");
+    }
+    let listener = UnixListener::bind(&socket_path).expect("Couldn't open socket");
 
     let gid = nix::unistd::getgid();
-    nix::unistd::chown(socket_path, Some(nix::unistd::ROOT), Some(gid)).expect("Couldn't set socket group");
+    nix::unistd::chown(&socket_path, Some(nix::unistd::ROOT), Some(gid)).expect("Couldn't set socket group");
 
     // Set socket permissions to 0640
     let socket_permissions = Permissions::from_mode(0o640);
-    fs::set_permissions(socket_path, socket_permissions).expect("Couldn't set socket permissions");
+    fs::set_permissions(&socket_path, socket_permissions).expect("Couldn't set socket permissions");
 
     info!("os-agent started, waiting for requests...");
-    server.wait();
+    if let Err(e) = sd_notify("READY=1\nSTATUS=waiting for requests") {
+        warn!("{:#}", e);
+    }
+    spawn_watchdog(watchdog_check).expect("Couldn't start systemd watchdog");
+    server::serve(listener, io, policy);
 }
 
 fn main() {
-    Builder::from_env(Env::default().default_filter_or("info")).target(Target::Stdout).init();
+    let options = Options::parse();
+    init_logger("info");
 
     info!("os-agent version is: {}", CARGO_PKG_VERSION.unwrap_or("NOT FOUND"));
-    start_and_run(SOCK_PATH);
+    let config = match AgentConfig::load(options) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        },
+    };
+    info!("os-agent config: {:?}", config);
+    start_and_run(config);
 }
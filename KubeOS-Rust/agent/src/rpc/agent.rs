@@ -10,21 +10,63 @@
  * See the Mulan PSL v2 for more details.
  */
 
-use manager::api::{ConfigureRequest, Response, UpgradeRequest};
+use manager::api::{
+    CapabilitiesResponse, ConfigureRequest, DiagnosticsResponse, DriftResponse, GetConfigRequest, HistoryResponse,
+    ListSlotsResponse, PlanConfigureResponse, PreflightResponse, ProgressResponse, Response, StatusResponse, Sysconfig,
+    UpgradeRequest, VerifySlotResponse,
+};
 
 use super::function::{rpc, RpcResult};
 
+// The trailing wait of the requests changing the node is the seconds to wait for the running request to finish, the
+// request fails at once if it is omitted
 #[rpc(server)]
 pub trait Agent {
     #[rpc(name = "prepare_upgrade")]
-    fn prepare_upgrade(&self, req: UpgradeRequest) -> RpcResult<Response>;
+    fn prepare_upgrade(&self, req: UpgradeRequest, wait: Option<u64>) -> RpcResult<Response>;
 
     #[rpc(name = "upgrade")]
-    fn upgrade(&self) -> RpcResult<Response>;
+    fn upgrade(&self, wait: Option<u64>) -> RpcResult<Response>;
 
     #[rpc(name = "configure")]
-    fn configure(&self, req: ConfigureRequest) -> RpcResult<Response>;
+    fn configure(&self, req: ConfigureRequest, wait: Option<u64>) -> RpcResult<Response>;
 
     #[rpc(name = "rollback")]
-    fn rollback(&self) -> RpcResult<Response>;
+    fn rollback(&self, wait: Option<u64>) -> RpcResult<Response>;
+
+    #[rpc(name = "get_status")]
+    fn get_status(&self) -> RpcResult<StatusResponse>;
+
+    #[rpc(name = "get_progress")]
+    fn get_progress(&self) -> RpcResult<ProgressResponse>;
+
+    #[rpc(name = "cancel")]
+    fn cancel(&self) -> RpcResult<Response>;
+
+    #[rpc(name = "get_history")]
+    fn get_history(&self) -> RpcResult<HistoryResponse>;
+
+    #[rpc(name = "plan_configure")]
+    fn plan_configure(&self, req: ConfigureRequest) -> RpcResult<PlanConfigureResponse>;
+
+    #[rpc(name = "get_config")]
+    fn get_config(&self, req: GetConfigRequest) -> RpcResult<Sysconfig>;
+
+    #[rpc(name = "get_drift")]
+    fn get_drift(&self) -> RpcResult<DriftResponse>;
+
+    #[rpc(name = "get_capabilities")]
+    fn get_capabilities(&self) -> RpcResult<CapabilitiesResponse>;
+
+    #[rpc(name = "preflight")]
+    fn preflight(&self, req: UpgradeRequest) -> RpcResult<PreflightResponse>;
+
+    #[rpc(name = "list_slots")]
+    fn list_slots(&self) -> RpcResult<ListSlotsResponse>;
+
+    #[rpc(name = "collect_diagnostics")]
+    fn collect_diagnostics(&self) -> RpcResult<DiagnosticsResponse>;
+
+    #[rpc(name = "verify_slot")]
+    fn verify_slot(&self) -> RpcResult<VerifySlotResponse>;
 }
@@ -10,180 +10,848 @@
  * See the Mulan PSL v2 for more details.
  */
 
-use std::{io::Write, sync::Mutex, thread, time::Duration};
+use std::{
+    cmp::Ordering,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use manager::{
-    api::{AgentStatus, ConfigureRequest, ImageType, Response, UpgradeRequest},
-    sys_mgmt::{CtrImageHandler, DiskImageHandler, DockerImageHandler, CONFIG_TEMPLATE, DEFAULT_GRUBENV_PATH},
-    utils::{get_partition_info, is_dmv_mode, switch_boot_menuentry, CommandExecutor, RealCommandExecutor},
+    api::{
+        AgentStatus, CapabilitiesResponse, ConfigDrift, ConfigureRequest, DiagnosticsResponse, DriftResponse,
+        GetConfigRequest, HistoryResponse, ImageType, ListSlotsResponse, ModelPlan, PlanConfigureResponse,
+        PreflightResponse, ProgressResponse, Response, SlotMetadata, SlotState, StatusResponse, Sysconfig,
+        UpgradeRequest, VerifySlotResponse, FEATURE_CANCEL, FEATURE_DIAGNOSTICS, FEATURE_DM_VERITY, FEATURE_DRIFT,
+        FEATURE_GET_CONFIG, FEATURE_HISTORY, FEATURE_PLAN_CONFIGURE, FEATURE_PREFLIGHT, FEATURE_PROGRESS,
+        FEATURE_SLOTS, FEATURE_VERIFY_SLOT, FEATURE_WAIT, IMAGE_TYPE_CONTAINERD, IMAGE_TYPE_DISK, IMAGE_TYPE_DOCKER,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    sys_mgmt::{
+        check_drift, config_template, AppliedConfigStore, ConfigTemplate, ConfigTransaction, CtrImageHandler,
+        Diagnostics, DiskImageHandler, DockerImageHandler, Preflight, APPLIED_CONFIG_FILE, JOURNAL_FILE,
+        KEXEC_MOUNT_DIR, MAX_JOURNAL_SIZE, OS_RELEASE_PATH, PREPARE_STATE_FILE, SLOTS_FILE,
+    },
+    utils::{
+        compare_versions, end_boot_trial, get_boot_mode, get_boot_trial, get_os_version, get_partition_info,
+        is_dmv_mode, is_image_built, kexec_load, list_slots, redact, request_id, result_label, slot_state,
+        start_boot_trial, switch_boot_menuentry, verify_partition, Checkpoint, CommandExecutor, Journal, PartitionInfo,
+        PreparePath, PrepareStep, ProgressTracker, RealCommandExecutor, RequestScope, SlotStore, CONFIGURE_CALLS,
+    },
 };
 use nix::{sys::reboot::RebootMode, unistd::sync};
+use serde_json::{json, Value};
 
 use super::{
     agent::Agent,
     function::{RpcFunction, RpcResult},
 };
+use crate::{
+    config::{AgentConfig, DEFAULT_CONFIG_PATH},
+    health::wait_healthy,
+    hooks::{Hooks, OPERATION_CONFIGURE, OPERATION_PREPARE_UPGRADE, OPERATION_ROLLBACK, OPERATION_UPGRADE},
+    lock::OperationLock,
+};
 
 pub struct AgentImpl {
-    mutex: Mutex<()>,
-    disable_reboot: bool,
+    lock: OperationLock,
+    config: AgentConfig,
+    config_template: ConfigTemplate,
+    progress: ProgressTracker,
+    journal: Arc<Journal>,
+    hooks: Hooks,
+    slots: SlotStore,
+    checkpoint: Checkpoint,
+    applied: AppliedConfigStore,
+    // the result of the last drift check, returned by get_drift
+    drifts: Arc<Mutex<Vec<ConfigDrift>>>,
 }
 
 impl Agent for AgentImpl {
-    fn prepare_upgrade(&self, req: UpgradeRequest) -> RpcResult<Response> {
-        RpcFunction::call(|| self.prepare_upgrade_impl(req))
+    fn prepare_upgrade(&self, req: UpgradeRequest, wait: Option<u64>) -> RpcResult<Response> {
+        RpcFunction::call(|| self.prepare_upgrade_impl(req, lock_timeout(wait)))
+    }
+
+    fn upgrade(&self, wait: Option<u64>) -> RpcResult<Response> {
+        RpcFunction::call(|| self.journal.record("upgrade", Value::Null, || self.upgrade_impl(lock_timeout(wait))))
+    }
+
+    fn configure(&self, req: ConfigureRequest, wait: Option<u64>) -> RpcResult<Response> {
+        let params = serde_json::to_value(&req).unwrap_or_default();
+        RpcFunction::call(|| self.journal.record("configure", params, || self.configure_impl(req, lock_timeout(wait))))
+    }
+
+    fn rollback(&self, wait: Option<u64>) -> RpcResult<Response> {
+        RpcFunction::call(|| self.journal.record("rollback", Value::Null, || self.rollback_impl(lock_timeout(wait))))
+    }
+
+    fn get_status(&self) -> RpcResult<StatusResponse> {
+        RpcFunction::call(|| self.get_status_impl(&RealCommandExecutor {}))
+    }
+
+    fn get_progress(&self) -> RpcResult<ProgressResponse> {
+        RpcFunction::call(|| Ok(self.progress.get()))
+    }
+
+    fn cancel(&self) -> RpcResult<Response> {
+        RpcFunction::call(|| self.journal.record("cancel", Value::Null, || self.cancel_impl()))
+    }
+
+    fn get_history(&self) -> RpcResult<HistoryResponse> {
+        RpcFunction::call(|| Ok(HistoryResponse { entries: self.journal.history()? }))
+    }
+
+    fn plan_configure(&self, req: ConfigureRequest) -> RpcResult<PlanConfigureResponse> {
+        RpcFunction::call(|| self.plan_configure_impl(req))
     }
 
-    fn upgrade(&self) -> RpcResult<Response> {
-        RpcFunction::call(|| self.upgrade_impl())
+    fn get_config(&self, req: GetConfigRequest) -> RpcResult<Sysconfig> {
+        RpcFunction::call(|| self.get_config_impl(req))
     }
 
-    fn configure(&self, req: ConfigureRequest) -> RpcResult<Response> {
-        RpcFunction::call(|| self.configure_impl(req))
+    fn get_drift(&self) -> RpcResult<DriftResponse> {
+        RpcFunction::call(|| self.get_drift_impl())
     }
 
-    fn rollback(&self) -> RpcResult<Response> {
-        RpcFunction::call(|| self.rollback_impl())
+    fn get_capabilities(&self) -> RpcResult<CapabilitiesResponse> {
+        RpcFunction::call(|| Ok(self.get_capabilities_impl(&RealCommandExecutor {})))
+    }
+
+    fn preflight(&self, req: UpgradeRequest) -> RpcResult<PreflightResponse> {
+        RpcFunction::call(|| Ok(self.preflight_impl(req, &RealCommandExecutor {})))
+    }
+
+    fn list_slots(&self) -> RpcResult<ListSlotsResponse> {
+        RpcFunction::call(|| self.list_slots_impl(&RealCommandExecutor {}))
+    }
+
+    fn collect_diagnostics(&self) -> RpcResult<DiagnosticsResponse> {
+        RpcFunction::call(|| self.collect_diagnostics_impl(&RealCommandExecutor {}))
+    }
+
+    fn verify_slot(&self) -> RpcResult<VerifySlotResponse> {
+        RpcFunction::call(|| self.verify_slot_impl(&RealCommandExecutor {}))
     }
 }
 
 impl Default for AgentImpl {
     fn default() -> Self {
-        Self { mutex: Mutex::new(()), disable_reboot: false }
+        AgentImpl::new(AgentConfig::default())
     }
 }
 
 impl AgentImpl {
-    fn prepare_upgrade_impl(&self, req: UpgradeRequest) -> Result<Response> {
-        let lock = self.mutex.try_lock();
-        if lock.is_err() {
-            bail!("os-agent is processing another request");
+    /// Build the check for the systemd watchdog, which reports the running preparation and becomes unhealthy if the
+    /// preparation makes no progress within the stall timeout
+    pub fn watchdog_check(&self) -> impl Fn() -> (String, bool) + Send + 'static {
+        let progress = self.progress.clone();
+        let stall_timeout = Duration::from_secs(self.config.stall_timeout);
+        move || match (progress.status(), progress.stalled_for()) {
+            (Some(status), Some(stalled)) if stalled > stall_timeout => {
+                (format!("{}, no progress for {}s", status, stalled.as_secs()), false)
+            },
+            (Some(status), _) => (status, true),
+            _ => ("waiting for requests".to_string(), true),
+        }
+    }
+
+    /// Check the partition booted on trial by an upgrade in the background. The partition is committed if the health
+    /// checks pass, otherwise os-agent switches back to the previous partition and reboots.
+    pub fn start_boot_check(&self) -> Result<()> {
+        let lock = self.lock.clone();
+        let journal = self.journal.clone();
+        let config = self.config.clone();
+        thread::Builder::new().name("boot-check".to_string()).spawn(move || {
+            // requests are refused until the partition is committed or rolled back
+            let _guard = lock.acquire_blocking("boot-check");
+            match boot_check(&config, &journal, &RealCommandExecutor {}) {
+                Ok(true) => {
+                    if let Err(e) = reboot(&journal, config.disable_reboot, RebootMode::RB_AUTOBOOT) {
+                        error!("Failed to reboot: {:#}", e);
+                    }
+                },
+                Ok(false) => {},
+                Err(e) => error!("Failed to check the boot partition: {:#}", e),
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Compare the configured values with the current ones periodically, the result is stored for get_drift and the
+    /// drift is logged when it changes
+    pub fn start_drift_check(&self) -> Result<()> {
+        if self.config.drift_check_interval == 0 {
+            return Ok(());
+        }
+        let lock = self.lock.clone();
+        let applied = self.applied.clone();
+        let drifts = self.drifts.clone();
+        let template = config_template(&self.config.grub_cfg_path);
+        let interval = Duration::from_secs(self.config.drift_check_interval);
+        thread::Builder::new().name("drift-check".to_string()).spawn(move || loop {
+            // the running request may be changing the configuration
+            if lock.holder().is_none() {
+                update_drift(&template, &applied, &drifts);
+            }
+            thread::sleep(interval);
+        })?;
+        Ok(())
+    }
+
+    /// Report the preparation interrupted by the last os-agent exit, which resumes when the same request is retried
+    pub fn report_prepare_state(&self) {
+        match self.checkpoint.load() {
+            Ok(Some(state)) => info!(
+                "Recovered the preparation for upgrading to version {} ({} image) completed up to step {:?}, it \
+                 resumes from there if the same request is retried",
+                state.version, state.image_type, state.step
+            ),
+            Ok(None) => {},
+            Err(e) => warn!("{:#}", e),
         }
+    }
+
+    pub fn new(config: AgentConfig) -> Self {
+        Self {
+            lock: OperationLock::default(),
+            config_template: config_template(&config.grub_cfg_path),
+            progress: ProgressTracker::default(),
+            journal: Arc::new(Journal::new(Path::new(&config.persist_dir).join(JOURNAL_FILE), MAX_JOURNAL_SIZE)),
+            hooks: Hooks::new(&config.hooks_dir, config.hook_timeout),
+            slots: SlotStore::new(Path::new(&config.persist_dir).join(SLOTS_FILE)),
+            checkpoint: Checkpoint::new(Path::new(&config.persist_dir).join(PREPARE_STATE_FILE)),
+            applied: AppliedConfigStore::new(Path::new(&config.persist_dir).join(APPLIED_CONFIG_FILE)),
+            drifts: Arc::new(Mutex::new(Vec::new())),
+            config,
+        }
+    }
+
+    fn prepare_upgrade_impl(&self, req: UpgradeRequest, timeout: Duration) -> Result<Response> {
         debug!("Received an 'prepare upgrade' request: {:?}", req);
-        info!("Start preparing for upgrading to version: {}", req.version);
+        // the preparation is recorded as finished by the background job once it has started
+        let journal_id = self.journal.begin("prepare_upgrade", serde_json::to_value(&req).unwrap_or_default());
+        let result = self.start_prepare_upgrade(req, journal_id, timeout);
+        if result.is_err() {
+            self.journal.end(journal_id, &result);
+        }
+        result
+    }
 
+    fn start_prepare_upgrade(&self, req: UpgradeRequest, journal_id: u64, timeout: Duration) -> Result<Response> {
         let dmv_mode = is_dmv_mode(&RealCommandExecutor {});
         info!("dm-verity mode: {}", dmv_mode);
+        let progress = self.progress.clone();
+        let paths = PreparePath::new(&self.config.persist_dir);
+        let need_bytes = self.config.need_bytes;
+        let checkpoint = self.checkpoint.clone();
         let handler: Box<ImageType<RealCommandExecutor>> = match req.image_type.as_str() {
-            "containerd" => Box::new(ImageType::Containerd(CtrImageHandler { dmv: dmv_mode, ..Default::default() })),
-            "docker" => Box::new(ImageType::Docker(DockerImageHandler { dmv: dmv_mode, ..Default::default() })),
-            "disk" => Box::new(ImageType::Disk(DiskImageHandler { dmv: dmv_mode, ..Default::default() })),
+            IMAGE_TYPE_CONTAINERD => Box::new(ImageType::Containerd(CtrImageHandler {
+                paths,
+                dmv: dmv_mode,
+                need_bytes,
+                progress,
+                checkpoint,
+                ..Default::default()
+            })),
+            IMAGE_TYPE_DOCKER => Box::new(ImageType::Docker(DockerImageHandler {
+                paths,
+                dmv: dmv_mode,
+                need_bytes,
+                progress,
+                checkpoint,
+                ..Default::default()
+            })),
+            IMAGE_TYPE_DISK => Box::new(ImageType::Disk(DiskImageHandler {
+                paths,
+                certs_path: self.config.certs_path.clone(),
+                dmv: dmv_mode,
+                progress,
+                checkpoint,
+                ..Default::default()
+            })),
             _ => bail!("Invalid image type \"{}\"", req.image_type),
         };
 
-        let image_manager = handler.download_image(&req)?;
-        info!("Ready to install image: {:?}", image_manager.paths.image_path.display());
-        image_manager.install()?;
-
-        Ok(Response { status: AgentStatus::UpgradeReady })
+        // The preparation job holds the lock until it finishes, the request returns as soon as the job has started
+        let guard = self.lock.acquire(OPERATION_PREPARE_UPGRADE, timeout)?;
+        info!("Start preparing for upgrading to version: {}", req.version);
+        self.progress.start(&req.version);
+        let progress = self.progress.clone();
+        let journal = self.journal.clone();
+        let hooks = self.hooks.clone();
+        let slots = self.slots.clone();
+        let checkpoint = self.checkpoint.clone();
+        let request_id = request_id();
+        let spawned = thread::Builder::new().name("prepare-upgrade".to_string()).spawn(move || {
+            let _guard = guard;
+            // the logs of the job belong to the request starting it
+            let _scope = RequestScope::enter(request_id);
+            let env = [("KUBEOS_VERSION", req.version.clone()), ("KUBEOS_IMAGE_TYPE", req.image_type.clone())];
+            let result = hooks.wrap(&RealCommandExecutor {}, OPERATION_PREPARE_UPGRADE, &env, || {
+                prepare_upgrade_job(&handler, &req, &slots, &checkpoint)
+            });
+            journal.end(journal_id, &result);
+            match result {
+                Ok(_) => {
+                    progress.finish();
+                    info!("Prepare upgrading to version {} successfully", req.version);
+                },
+                Err(e) => {
+                    error!("Failed to prepare upgrading to version {}: {:#}", req.version, e);
+                    if progress.is_cancelled() {
+                        info!("Clean up the environment of the cancelled preparation");
+                        if let Err(e) = handler.clean_env() {
+                            error!("Failed to clean up the environment: {:#}", e);
+                        }
+                        checkpoint.clear();
+                    }
+                    progress.fail(format!("{:#}", e));
+                },
+            }
+        });
+        if let Err(e) = spawned {
+            self.progress.fail(format!("Failed to start the preparation: {}", e));
+            return Err(e.into());
+        }
+        Ok(Response { status: AgentStatus::UpgradePreparing })
     }
 
-    fn upgrade_impl(&self) -> Result<Response> {
-        let lock = self.mutex.try_lock();
-        if lock.is_err() {
-            bail!("os-agent is processing another request");
+    fn cancel_impl(&self) -> Result<Response> {
+        info!("Start to cancel the upgrade preparation");
+        self.progress.cancel()?;
+        // the running download or command of the preparation job is stopped, then the job cleans up the environment
+        // and releases the lock. The request waits for it shortly, otherwise the caller polls get_progress until the
+        // stage is Cancelled
+        match self.lock.acquire("cancel", CANCEL_WAIT) {
+            Ok(_guard) => {
+                info!("Upgrade preparation is cancelled");
+                Ok(Response { status: AgentStatus::UpgradeCancelled })
+            },
+            Err(_) => {
+                info!("Upgrade preparation is being cancelled");
+                Ok(Response { status: AgentStatus::UpgradeCancelling })
+            },
         }
+    }
+
+    fn upgrade_impl(&self, timeout: Duration) -> Result<Response> {
+        let _guard = self.lock.acquire(OPERATION_UPGRADE, timeout)?;
         info!("Start to upgrade");
+        let next_partition =
+            self.hooks.wrap(&RealCommandExecutor {}, OPERATION_UPGRADE, &[], || self.switch_partition(true))?;
+        self.reboot_next(next_partition)?;
+        Ok(Response { status: AgentStatus::Upgraded })
+    }
+
+    // switch_partition switches the partition to boot next time, and returns it unless in dm-verity mode. The switch of
+    // an upgrade is a boot trial, while the one of a rollback ends the boot trial as the previous partition is trusted.
+    fn switch_partition(&self, upgrade: bool) -> Result<Option<PartitionInfo>> {
         let command_executor = RealCommandExecutor {};
         let dmv_mode = is_dmv_mode(&command_executor);
         info!("dm-verity mode: {}", dmv_mode);
         if dmv_mode {
             command_executor.run_command("/usr/bin/kubeos-dmv", &["switch"])?;
+            self.checkpoint.clear();
             info!("Switch to next boot partition and reboot");
-            self.reboot()?;
-            return Ok(Response { status: AgentStatus::Upgraded });
+            return Ok(None);
         }
-        let (_, next_partition_info) = get_partition_info(&command_executor)?;
+        let (current_partition_info, next_partition_info) = get_partition_info(&command_executor)?;
 
         // based on boot mode use different command to switch boot partition
         let device = next_partition_info.device.as_str();
         let menuentry = next_partition_info.menuentry.as_str();
-        switch_boot_menuentry(&command_executor, DEFAULT_GRUBENV_PATH, menuentry)?;
+        if upgrade && self.config.boot_attempts > 0 {
+            start_boot_trial(
+                &command_executor,
+                &self.config.grubenv_path,
+                menuentry,
+                &current_partition_info.menuentry,
+                self.config.boot_attempts,
+            )?;
+        } else {
+            switch_boot_menuentry(&command_executor, &self.config.grubenv_path, menuentry)?;
+            if !upgrade {
+                end_boot_trial(&command_executor, &self.config.grubenv_path)?;
+            }
+        }
+        // the installed partition is not the next one any more
+        self.checkpoint.clear();
         info!("Switch to boot partition: {}, device: {}", menuentry, device);
-        self.reboot()?;
-        Ok(Response { status: AgentStatus::Upgraded })
+        Ok(Some(next_partition_info))
     }
 
-    fn configure_impl(&self, mut req: ConfigureRequest) -> Result<Response> {
-        let lock = self.mutex.try_lock();
-        if lock.is_err() {
-            bail!("os-agent is processing another request");
-        }
+    fn configure_impl(&self, mut req: ConfigureRequest, timeout: Duration) -> Result<Response> {
+        let _guard = self.lock.acquire(OPERATION_CONFIGURE, timeout)?;
         debug!("Received a 'configure' request: {:?}", req);
         info!("Start to configure");
-        let config_map = &*CONFIG_TEMPLATE;
+        let models: Vec<&str> = req.configs.iter().map(|config| config.model.as_str()).collect();
+        let env = [("KUBEOS_CONFIG_MODELS", models.join(","))];
+        self.hooks.wrap(&RealCommandExecutor {}, OPERATION_CONFIGURE, &env, || self.set_configs(&mut req))?;
+        // the stored drift is outdated by the configured values
+        if self.config.drift_check_interval != 0 {
+            update_drift(&self.config_template, &self.applied, &self.drifts);
+        }
+        Ok(Response { status: AgentStatus::Configured })
+    }
+
+    // set_configs applies all configs or none of them, the files and kernel parameters changed are restored when any
+    // model fails
+    fn set_configs(&self, req: &mut ConfigureRequest) -> Result<()> {
+        let transaction = ConfigTransaction::begin(&self.config_template, &req.configs)?;
+        // set_config consumes the contents
+        let configs = req.configs.clone();
+        if let Err(e) = self.apply_configs(req) {
+            if let Err(restore_err) = transaction.restore() {
+                error!("{:#}", restore_err);
+            }
+            return Err(e);
+        }
+        if let Err(e) = self.applied.record(&self.config_template, &configs) {
+            warn!("{:#}", e);
+        }
+        Ok(())
+    }
+
+    fn apply_configs(&self, req: &mut ConfigureRequest) -> Result<()> {
+        let config_map = &self.config_template;
         for config in req.configs.iter_mut() {
-            let config_type = &config.model;
-            if let Some(configuration) = config_map.get(config_type) {
-                debug!("Found configuration type: \"{}\"", config_type);
-                configuration.set_config(config)?;
+            // take the model name from the template to keep the label values bounded
+            if let Some((model, configuration)) = config_map.get_key_value(&config.model) {
+                debug!("Found configuration type: \"{}\"", model);
+                let result = configuration.set_config(config);
+                CONFIGURE_CALLS.inc(&[model, result_label(&result)]);
+                result?;
             } else {
-                bail!("Unknown configuration type: \"{}\"", config_type);
+                CONFIGURE_CALLS.inc(&["unknown", "failure"]);
+                bail!("Unknown configuration type: \"{}\"", config.model);
             }
         }
-        Ok(Response { status: AgentStatus::Configured })
+        Ok(())
     }
 
-    fn rollback_impl(&self) -> Result<Response> {
-        let lock = self.mutex.try_lock();
-        if lock.is_err() {
-            bail!("os-agent is processing another request");
+    // plan_configure_impl computes the changes of every model against the current files, without writing anything.
+    // Models configuring the same file are planned independently.
+    fn plan_configure_impl(&self, req: ConfigureRequest) -> Result<PlanConfigureResponse> {
+        debug!("Received a 'plan configure' request: {:?}", req);
+        let mut plans = Vec::new();
+        for config in req.configs.iter() {
+            let Some(configuration) = self.config_template.get(&config.model) else {
+                bail!("Unknown configuration type: \"{}\"", config.model);
+            };
+            plans.push(ModelPlan { model: config.model.clone(), diffs: configuration.plan_config(config)? });
         }
+        Ok(PlanConfigureResponse { plans })
+    }
+
+    // get_drift_impl returns the result of the last periodic drift check, the drift is checked on request only if the
+    // periodic check is disabled
+    fn get_drift_impl(&self) -> Result<DriftResponse> {
+        if self.config.drift_check_interval == 0 {
+            return Ok(DriftResponse { drifts: check_drift(&self.config_template, &self.applied)? });
+        }
+        let drifts = self.drifts.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        Ok(DriftResponse { drifts })
+    }
+
+    fn get_config_impl(&self, req: GetConfigRequest) -> Result<Sysconfig> {
+        debug!("Received a 'get config' request: {:?}", req);
+        let Some(configuration) = self.config_template.get(&req.model) else {
+            bail!("Unknown configuration type: \"{}\"", req.model);
+        };
+        let contents = configuration.get_config(&req.config_path, &req.keys)?;
+        Ok(Sysconfig { model: req.model, config_path: req.config_path, contents })
+    }
+
+    fn rollback_impl(&self, timeout: Duration) -> Result<Response> {
+        let _guard = self.lock.acquire(OPERATION_ROLLBACK, timeout)?;
         info!("Start to rollback");
-        let command_executor = RealCommandExecutor {};
-        let dmv_mode = is_dmv_mode(&command_executor);
-        info!("dm-verity mode: {}", dmv_mode);
-        if dmv_mode {
-            command_executor.run_command("/usr/bin/kubeos-dmv", &["switch"])?;
-            info!("Switch to next boot partition and reboot");
-            self.reboot()?;
-            return Ok(Response { status: AgentStatus::Upgraded });
-        }
-        let (_, next_partition_info) = get_partition_info(&command_executor)?;
-        switch_boot_menuentry(
-            &command_executor,
-            manager::sys_mgmt::DEFAULT_GRUBENV_PATH,
-            &next_partition_info.menuentry,
-        )?;
-        info!("Switch to boot partition: {}, device: {}", next_partition_info.menuentry, next_partition_info.device);
-        self.reboot()?;
+        self.check_rollback_slot(&RealCommandExecutor {})?;
+        let next_partition =
+            self.hooks.wrap(&RealCommandExecutor {}, OPERATION_ROLLBACK, &[], || self.switch_partition(false))?;
+        self.reboot_next(next_partition)?;
         Ok(Response { status: AgentStatus::Rollbacked })
     }
 
-    fn reboot(&self) -> Result<()> {
-        info!("Wait to reboot");
-        std::io::stdout().flush()?;
-        thread::sleep(Duration::from_secs(1));
-        sync();
-        if self.disable_reboot {
+    // check_rollback_slot refuses to roll back to a partition which is empty, corrupt or older than the minimum
+    // rollback version. Partitions without metadata are allowed as they are installed by older versions of os-agent.
+    fn check_rollback_slot<T: CommandExecutor>(&self, command_executor: &T) -> Result<()> {
+        if is_dmv_mode(command_executor) {
             return Ok(());
         }
-        nix::sys::reboot::reboot(RebootMode::RB_AUTOBOOT)?;
+        let (_, next_partition) = get_partition_info(command_executor)?;
+        // broken metadata should not block rolling back
+        let metadata = self.slots.get(&next_partition.menuentry).unwrap_or_else(|e| {
+            warn!("{:#}", e);
+            None
+        });
+        match slot_state(command_executor, &next_partition.device, false, metadata.as_ref())? {
+            SlotState::Empty => bail!("Partition {} on {} is empty", next_partition.menuentry, next_partition.device),
+            SlotState::Installing => bail!(
+                "Partition {} is corrupt, installing version {} did not finish",
+                next_partition.menuentry,
+                metadata.map(|m| m.version).unwrap_or_default()
+            ),
+            _ => {},
+        }
+        let Some(min_version) = &self.config.min_rollback_version else {
+            return Ok(());
+        };
+        match metadata {
+            Some(m) if compare_versions(&m.version, min_version) == Ordering::Less => bail!(
+                "Version {} of partition {} is older than the minimum rollback version {}",
+                m.version,
+                next_partition.menuentry,
+                min_version
+            ),
+            Some(_) => {},
+            None => warn!(
+                "Version of partition {} is unknown, it can't be checked against the minimum rollback version {}",
+                next_partition.menuentry, min_version
+            ),
+        }
         Ok(())
     }
+
+    fn list_slots_impl<T: CommandExecutor>(&self, command_executor: &T) -> Result<ListSlotsResponse> {
+        debug!("Received a 'list slots' request");
+        if is_dmv_mode(command_executor) {
+            bail!("Slots are managed by kubeos-dmv in dm-verity mode");
+        }
+        Ok(ListSlotsResponse { slots: list_slots(command_executor, &self.slots)? })
+    }
+
+    // verify_slot_impl reads the next partition back and compares it with the image recorded by prepare_upgrade, the
+    // partition is locked against a preparation writing it meanwhile
+    fn verify_slot_impl<T: CommandExecutor>(&self, command_executor: &T) -> Result<VerifySlotResponse> {
+        debug!("Received a 'verify slot' request");
+        if is_dmv_mode(command_executor) {
+            bail!("Slots are managed by kubeos-dmv in dm-verity mode");
+        }
+        let _guard = self.lock.acquire("verify-slot", Duration::ZERO)?;
+        let (_, next_partition) = get_partition_info(command_executor)?;
+        let Some(metadata) = self.slots.get(&next_partition.menuentry)? else {
+            bail!("No image is recorded for partition {}", next_partition.menuentry);
+        };
+        let res = verify_partition(&next_partition, &metadata)?;
+        if res.matched {
+            info!("Partition {} matches the image of version {}", res.menuentry, metadata.version);
+        } else {
+            warn!(
+                "Partition {} is corrupt, sha256 {} mismatches the image {} of version {}",
+                res.menuentry, res.actual_sha256, res.expected_sha256, metadata.version
+            );
+        }
+        Ok(res)
+    }
+
+    // collect_diagnostics_impl collects the logs, boot configuration, config files, partitions and the state of
+    // os-agent into a tarball under the persist directory
+    fn collect_diagnostics_impl<T: CommandExecutor>(&self, command_executor: &T) -> Result<DiagnosticsResponse> {
+        debug!("Received a 'collect diagnostics' request");
+        let persist_dir = Path::new(&self.config.persist_dir);
+        let mut files: Vec<PathBuf> =
+            [&self.config.grubenv_path, &self.config.grub_cfg_path, DEFAULT_CONFIG_PATH, &self.config.auth_policy_path]
+                .iter()
+                .map(PathBuf::from)
+                .collect();
+        files.extend(self.config_template.values().flat_map(|c| c.default_paths()).map(PathBuf::from));
+        files.extend([JOURNAL_FILE, SLOTS_FILE, PREPARE_STATE_FILE].iter().map(|f| persist_dir.join(f)));
+        files.sort();
+        files.dedup();
+        let diagnostics = Diagnostics {
+            executor: command_executor.clone(),
+            persist_dir: persist_dir.to_path_buf(),
+            files,
+            slots: self.slots.clone(),
+            dmv: is_dmv_mode(command_executor),
+        };
+        diagnostics.collect()
+    }
+
+    fn get_status_impl<T: CommandExecutor>(&self, command_executor: &T) -> Result<StatusResponse> {
+        debug!("Received a 'get status' request");
+        let (current_partition, next_partition) = get_partition_info(command_executor)?;
+        let dmv_mode = is_dmv_mode(command_executor);
+        let paths = PreparePath::new(&self.config.persist_dir);
+        let image_staged = is_image_built(&paths, dmv_mode);
+        let lock_holder = self.lock.holder();
+        Ok(StatusResponse {
+            version: get_os_version(OS_RELEASE_PATH)?,
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            current_partition,
+            next_partition,
+            boot_mode: get_boot_mode(),
+            dmv_mode,
+            image_staged,
+            busy: lock_holder.is_some(),
+            lock_holder,
+        })
+    }
+
+    fn get_capabilities_impl<T: CommandExecutor>(&self, command_executor: &T) -> CapabilitiesResponse {
+        debug!("Received a 'get capabilities' request");
+        let dmv_mode = is_dmv_mode(command_executor);
+        let mut image_types = vec![IMAGE_TYPE_CONTAINERD.to_string(), IMAGE_TYPE_DOCKER.to_string()];
+        // disk images can't be verified by dm-verity
+        if !dmv_mode {
+            image_types.push(IMAGE_TYPE_DISK.to_string());
+        }
+        let mut config_models: Vec<String> = self.config_template.keys().cloned().collect();
+        config_models.sort();
+        let mut features: Vec<String> = [
+            FEATURE_PROGRESS,
+            FEATURE_CANCEL,
+            FEATURE_HISTORY,
+            FEATURE_PLAN_CONFIGURE,
+            FEATURE_PREFLIGHT,
+            FEATURE_SLOTS,
+            FEATURE_WAIT,
+            FEATURE_DIAGNOSTICS,
+            FEATURE_VERIFY_SLOT,
+            FEATURE_GET_CONFIG,
+            FEATURE_DRIFT,
+        ]
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+        if dmv_mode {
+            features.push(FEATURE_DM_VERITY.to_string());
+        }
+        CapabilitiesResponse {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            image_types,
+            config_models,
+            features,
+        }
+    }
+
+    // preflight_impl checks the request without taking the lock, as nothing is changed on the node
+    fn preflight_impl<T: CommandExecutor>(&self, req: UpgradeRequest, command_executor: &T) -> PreflightResponse {
+        debug!("Received a 'preflight' request: {:?}", req);
+        let preflight = Preflight {
+            paths: PreparePath::new(&self.config.persist_dir),
+            executor: command_executor.clone(),
+            certs_path: self.config.certs_path.clone(),
+            need_bytes: self.config.need_bytes,
+            dmv: is_dmv_mode(command_executor),
+            os_release_path: OS_RELEASE_PATH.to_string(),
+        };
+        let failures = preflight.check(&req);
+        for failure in failures.iter() {
+            warn!("Preflight check {} failed: {}", failure.check, failure.message);
+        }
+        PreflightResponse { failures }
+    }
+
+    fn reboot(&self) -> Result<()> {
+        reboot(&self.journal, self.config.disable_reboot, RebootMode::RB_AUTOBOOT)
+    }
+
+    fn reboot_next(&self, next_partition: Option<PartitionInfo>) -> Result<()> {
+        match next_partition {
+            Some(partition) => self.reboot_into(&partition),
+            None => self.reboot(),
+        }
+    }
+
+    // reboot_into boots the partition by kexec if enabled, and falls back to a normal reboot if the kernel of the
+    // partition can't be loaded
+    fn reboot_into(&self, partition: &PartitionInfo) -> Result<()> {
+        if !self.config.kexec {
+            return self.reboot();
+        }
+        let mount_path = PreparePath::new(&self.config.persist_dir).update_path.join(KEXEC_MOUNT_DIR);
+        let mode = match kexec_load(&RealCommandExecutor {}, &self.config.grub_cfg_path, partition, mount_path) {
+            Ok(_) => RebootMode::RB_KEXEC,
+            Err(e) => {
+                warn!("Failed to load the kernel for kexec, fall back to a normal reboot: {:#}", e);
+                RebootMode::RB_AUTOBOOT
+            },
+        };
+        reboot(&self.journal, self.config.disable_reboot, mode)
+    }
+}
+
+// CANCEL_WAIT is how long cancel waits for the preparation job to stop before returning
+#[cfg(not(test))]
+const CANCEL_WAIT: Duration = Duration::from_secs(10);
+#[cfg(test)]
+const CANCEL_WAIT: Duration = Duration::from_millis(100);
+
+// update_drift checks the drift and stores the result for get_drift, the drift is logged when it changes
+fn update_drift(template: &ConfigTemplate, applied: &AppliedConfigStore, store: &Mutex<Vec<ConfigDrift>>) {
+    let drifts = match check_drift(template, applied) {
+        Ok(drifts) => drifts,
+        Err(e) => {
+            warn!("Failed to check the configuration drift: {:#}", e);
+            return;
+        },
+    };
+    let mut last = store.lock().unwrap_or_else(|e| e.into_inner());
+    if *last == drifts {
+        return;
+    }
+    if drifts.is_empty() {
+        info!("The configuration matches the values configured last");
+    }
+    for drift in drifts.iter() {
+        warn!(
+            "Configuration {} of {} drifted, expected {:?}, actual {:?}",
+            drift.key, drift.model, drift.expected, drift.actual
+        );
+    }
+    *last = drifts;
+}
+
+// lock_timeout is how long the request waits for the running request to finish, it doesn't wait by default
+fn lock_timeout(wait: Option<u64>) -> Duration {
+    Duration::from_secs(wait.unwrap_or_default())
+}
+
+fn reboot(journal: &Journal, disable_reboot: bool, mode: RebootMode) -> Result<()> {
+    info!("Wait to reboot");
+    std::io::stdout().flush()?;
+    thread::sleep(Duration::from_secs(1));
+    journal.end_running();
+    sync();
+    if disable_reboot {
+        return Ok(());
+    }
+    if mode == RebootMode::RB_KEXEC {
+        let Err(e) = nix::sys::reboot::reboot(RebootMode::RB_KEXEC);
+        warn!("Failed to reboot by kexec, fall back to a normal reboot: {}", e);
+    }
+    nix::sys::reboot::reboot(RebootMode::RB_AUTOBOOT)?;
+    Ok(())
+}
+
+// boot_check returns whether os-agent switched back to the previous partition and needs to reboot
+fn boot_check<T: CommandExecutor>(config: &AgentConfig, journal: &Journal, command_executor: &T) -> Result<bool> {
+    let Some(trial) = get_boot_trial(command_executor, &config.grubenv_path)? else {
+        return Ok(false);
+    };
+    let (current_partition_info, _) = get_partition_info(command_executor)?;
+    let menuentry = current_partition_info.menuentry;
+    if menuentry == trial.fallback_menuentry {
+        info!("Boot partition {} is not the one on trial, end the boot trial", menuentry);
+        end_boot_trial(command_executor, &config.grubenv_path)?;
+        return Ok(false);
+    }
+    info!(
+        "Boot partition {} is on trial with {} boot attempts remaining, start health checks",
+        menuentry, trial.remaining_attempts
+    );
+    let params = json!({ "menuentry": menuentry, "remaining_attempts": trial.remaining_attempts });
+    let result = journal.record("boot_check", params, || wait_healthy(&config.health_check, command_executor));
+    if let Err(e) = result {
+        error!("Health checks of boot partition {} failed: {:#}", menuentry, e);
+        switch_boot_menuentry(command_executor, &config.grubenv_path, &trial.fallback_menuentry)?;
+        end_boot_trial(command_executor, &config.grubenv_path)?;
+        info!("Switch back to boot partition: {} and reboot", trial.fallback_menuentry);
+        return Ok(true);
+    }
+    end_boot_trial(command_executor, &config.grubenv_path)?;
+    info!("Health checks passed, commit boot partition {}", menuentry);
+    Ok(false)
+}
+
+fn prepare_upgrade_job<T: CommandExecutor>(
+    handler: &ImageType<T>,
+    req: &UpgradeRequest,
+    slots: &SlotStore,
+    checkpoint: &Checkpoint,
+) -> Result<()> {
+    if checkpoint.resume_step(req) == Some(PrepareStep::Installed) {
+        info!("Version {} is installed to the next partition already", req.version);
+        return Ok(());
+    }
+    let image_manager = handler.download_image(req)?;
+    info!("Ready to install image: {:?}", image_manager.paths.image_path.display());
+    // the partitions of dm-verity mode are installed by kubeos-dmv, which are not recorded
+    if image_manager.dmv {
+        image_manager.install()?;
+        checkpoint.save(req, PrepareStep::Installed);
+        return Ok(());
+    }
+    let menuentry = image_manager.next_partition.menuentry.as_str();
+    let source = if req.image_type == IMAGE_TYPE_DISK { &req.image_url } else { &req.container_image };
+    let mut metadata = SlotMetadata {
+        version: req.version.clone(),
+        check_sum: req.check_sum.clone(),
+        image_type: req.image_type.clone(),
+        image_source: redact(Value::String(source.clone())).as_str().unwrap_or_default().to_string(),
+        ..Default::default()
+    };
+    // failing to record the metadata should not fail the upgrade
+    if let Err(e) = slots.begin_install(menuentry, metadata.clone()) {
+        warn!("Failed to record slot {}: {:#}", menuentry, e);
+    }
+    if let Some(digest) = image_manager.install()? {
+        metadata.image_sha256 = digest.sha256;
+        metadata.image_bytes = digest.bytes;
+    }
+    checkpoint.save(req, PrepareStep::Installed);
+    if let Err(e) = slots.finish_install(menuentry, metadata) {
+        warn!("Failed to record slot {}: {:#}", menuentry, e);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
 
-    use manager::api::{CertsInfo, Sysconfig};
+    use manager::api::{
+        CertsInfo, ConfigDiff, KeyInfo, OperationOutcome, Sysconfig, UpgradeStage, CHECK_IMAGE_TYPE, CHECK_PARTITION,
+    };
+    use mockall::mock;
 
     use super::*;
 
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    fn test_agent(persist_dir: &Path) -> AgentImpl {
+        AgentImpl::new(AgentConfig {
+            persist_dir: persist_dir.to_str().unwrap().to_string(),
+            hooks_dir: persist_dir.join("hooks").to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+    }
+
     #[test]
     fn test_reboot() {
-        let mut agent = AgentImpl::default();
-        agent.disable_reboot = true;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut agent = test_agent(tmp_dir.path());
+        agent.config.disable_reboot = true;
         let res = agent.reboot();
         assert!(res.is_ok());
+
+        // fall back to a normal reboot if the kernel can't be loaded
+        agent.config.kexec = true;
+        agent.config.grub_cfg_path = tmp_dir.path().join("grub.cfg").to_str().unwrap().to_string();
+        let res = agent.reboot_into(&PartitionInfo { menuentry: "B".to_string(), ..Default::default() });
+        assert!(res.is_ok());
     }
 
     #[test]
     fn test_configure() {
-        let agent = AgentImpl::default();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
         let req = ConfigureRequest {
             configs: vec![Sysconfig {
                 model: "kernel.sysctl".to_string(),
@@ -191,7 +859,7 @@ mod test {
                 contents: HashMap::new(),
             }],
         };
-        let res = agent.configure(req).unwrap();
+        let res = agent.configure(req, None).unwrap();
         assert_eq!(res, Response { status: AgentStatus::Configured });
 
         let req = ConfigureRequest {
@@ -201,26 +869,178 @@ mod test {
                 contents: HashMap::new(),
             }],
         };
-        let res = agent.configure(req);
+        let res = agent.configure(req, None);
         assert!(res.is_err());
 
+        let history = agent.get_history().unwrap().entries;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].operation, "configure");
+        assert_eq!(history[0].outcome, OperationOutcome::Succeeded);
+        assert_eq!(history[1].outcome, OperationOutcome::Failed);
+        assert_eq!(history[1].error, Some("Unknown configuration type: \"invalid\"".to_string()));
+
         // test lock
-        let _lock = agent.mutex.lock().unwrap();
-        let req = ConfigureRequest {
+        let guard = agent.lock.acquire_blocking("test");
+        let req = || ConfigureRequest {
             configs: vec![Sysconfig {
                 model: "kernel.sysctl".to_string(),
                 config_path: "".to_string(),
                 contents: HashMap::new(),
             }],
         };
-        let res = agent.configure(req);
-        assert!(res.is_err());
+        let res = agent.configure(req(), None);
+        assert!(res.unwrap_err().message.contains("processing another request: test since"));
+
+        // the request waits for the running one to finish
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+        assert!(agent.configure(req(), Some(10)).is_ok());
+        releaser.join().unwrap();
+
+        // the models configured before the failing one are restored
+        let config_path = tmp_dir.path().join("sysctl.conf");
+        std::fs::write(&config_path, "a=0\n").unwrap();
+        let req = ConfigureRequest {
+            configs: vec![
+                Sysconfig {
+                    model: "kernel.sysctl.persist".to_string(),
+                    config_path: config_path.to_str().unwrap().to_string(),
+                    contents: HashMap::from([(
+                        "a".to_string(),
+                        KeyInfo { value: serde_json::json!(1), operation: "".to_string() },
+                    )]),
+                },
+                Sysconfig { model: "invalid".to_string(), config_path: "".to_string(), contents: HashMap::new() },
+            ],
+        };
+        assert!(agent.configure(req, None).is_err());
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), "a=0\n");
+    }
+
+    #[test]
+    fn test_configure_hooks() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let hook_dir = tmp_dir.path().join("hooks/pre-configure.d");
+        fs::create_dir_all(&hook_dir).unwrap();
+        let hook = hook_dir.join("10-check");
+        fs::write(&hook, "#!/bin/sh\n[ \"$KUBEOS_CONFIG_MODELS\" = \"kernel.sysctl\" ]\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let req = |model: &str| ConfigureRequest {
+            configs: vec![Sysconfig {
+                model: model.to_string(),
+                config_path: "".to_string(),
+                contents: HashMap::new(),
+            }],
+        };
+        assert!(agent.configure(req("kernel.sysctl"), None).is_ok());
+        // the failing pre-hook aborts the configuration
+        let res = agent.configure(req("kernel.sysctl.persist"), None);
+        assert!(res.unwrap_err().message.contains("Pre-configure hook failed"));
+    }
+
+    #[test]
+    fn test_get_config() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let config_path = tmp_dir.path().join("sysctl.conf");
+        std::fs::write(&config_path, "# comment\na = 0\nb=1\n").unwrap();
+        let req = |model: &str, keys: &[&str]| GetConfigRequest {
+            model: model.to_string(),
+            config_path: config_path.to_str().unwrap().to_string(),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+        };
+        let res = agent.get_config(req("kernel.sysctl.persist", &["a", "c"])).unwrap();
+        assert_eq!(res.model, "kernel.sysctl.persist");
+        assert_eq!(res.contents.len(), 1);
+        assert_eq!(res.contents["a"].value, serde_json::json!("0"));
+        let res = agent.get_config(req("kernel.sysctl.persist", &[])).unwrap();
+        assert_eq!(res.contents.len(), 2);
+        assert!(agent.get_config(req("invalid", &[])).is_err());
+    }
+
+    #[test]
+    fn test_get_drift() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut agent = test_agent(tmp_dir.path());
+        let config_path = tmp_dir.path().join("sysctl.conf");
+        std::fs::write(&config_path, "a=0\n").unwrap();
+        assert!(agent.get_drift().unwrap().drifts.is_empty());
+        let req = || ConfigureRequest {
+            configs: vec![Sysconfig {
+                model: "kernel.sysctl.persist".to_string(),
+                config_path: config_path.to_str().unwrap().to_string(),
+                contents: HashMap::from([(
+                    "a".to_string(),
+                    KeyInfo { value: serde_json::json!(1), operation: "".to_string() },
+                )]),
+            }],
+        };
+        agent.configure(req(), None).unwrap();
+        assert!(agent.get_drift().unwrap().drifts.is_empty());
+
+        std::fs::write(&config_path, "a=2\n").unwrap();
+        // the result of the last check is returned until the next one
+        assert!(agent.get_drift().unwrap().drifts.is_empty());
+        update_drift(&agent.config_template, &agent.applied, &agent.drifts);
+        let drifts = agent.get_drift().unwrap().drifts;
+        assert_eq!(drifts.len(), 1);
+        assert_eq!((drifts[0].expected.as_deref(), drifts[0].actual.as_deref()), (Some("1"), Some("2")));
+        // configuring the key again refreshes the result
+        agent.configure(req(), None).unwrap();
+        assert!(agent.get_drift().unwrap().drifts.is_empty());
+        std::fs::write(&config_path, "a=2\n").unwrap();
+        agent.config.drift_check_interval = 0;
+        assert_eq!(agent.get_drift().unwrap().drifts.len(), 1);
+        assert!(agent.get_capabilities_impl(&RealCommandExecutor {}).has_feature(FEATURE_DRIFT));
+    }
+
+    #[test]
+    fn test_plan_configure() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let config_path = tmp_dir.path().join("sysctl.conf");
+        std::fs::write(&config_path, "a=0\n").unwrap();
+        let req = ConfigureRequest {
+            configs: vec![Sysconfig {
+                model: "kernel.sysctl.persist".to_string(),
+                config_path: config_path.to_str().unwrap().to_string(),
+                contents: HashMap::from([(
+                    "a".to_string(),
+                    KeyInfo { value: serde_json::json!(1), operation: "".to_string() },
+                )]),
+            }],
+        };
+        let res = agent.plan_configure(req).unwrap();
+        assert_eq!(res.plans.len(), 1);
+        assert_eq!(res.plans[0].model, "kernel.sysctl.persist");
+        assert_eq!(
+            res.plans[0].diffs,
+            vec![ConfigDiff { key: "a".to_string(), before: Some("0".to_string()), after: Some("1".to_string()) }]
+        );
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), "a=0\n");
+        // planning is neither recorded nor blocked by other requests
+        let _guard = agent.lock.acquire_blocking("test");
+        let req = ConfigureRequest {
+            configs: vec![Sysconfig {
+                model: "invalid".to_string(),
+                config_path: "".to_string(),
+                contents: HashMap::new(),
+            }],
+        };
+        assert!(agent.plan_configure(req).is_err());
+        assert!(agent.get_history().unwrap().entries.is_empty());
     }
 
     #[test]
     fn test_prepare_upgrade() {
-        let agent = AgentImpl::default();
-        let req = UpgradeRequest {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let mut req = UpgradeRequest {
             version: "v2".into(),
             check_sum: "xxx".into(),
             image_type: "xxx".into(),
@@ -230,7 +1050,356 @@ mod test {
             mtls: false,
             certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
         };
-        let res = agent.prepare_upgrade(req);
+        let res = agent.prepare_upgrade(req, None);
         assert!(res.is_err());
+        assert_eq!(agent.get_progress().unwrap().stage, UpgradeStage::Idle);
+
+        // the preparation job fails in background because of the unsafe http url
+        req = UpgradeRequest {
+            version: "v2".into(),
+            check_sum: "xxx".into(),
+            image_type: "disk".into(),
+            container_image: "".into(),
+            image_url: "http://localhost:8080/aaa.txt".to_string(),
+            flag_safe: false,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        };
+        let res = agent.prepare_upgrade(req, None).unwrap();
+        assert_eq!(res, Response { status: AgentStatus::UpgradePreparing });
+        let _guard = agent.lock.acquire_blocking("test");
+        let progress = agent.get_progress().unwrap();
+        assert_eq!(progress.version, "v2");
+        assert_eq!(progress.stage, UpgradeStage::Failed);
+        assert!(progress.error.is_some());
+
+        // test lock
+        let req = UpgradeRequest {
+            version: "v3".into(),
+            check_sum: "xxx".into(),
+            image_type: "disk".into(),
+            container_image: "".into(),
+            image_url: "".to_string(),
+            flag_safe: false,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        };
+        let res = agent.prepare_upgrade(req, None);
+        assert!(res.is_err());
+        assert_eq!(agent.get_progress().unwrap().version, "v2");
+    }
+
+    #[test]
+    fn test_cancel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        assert!(agent.cancel().is_err());
+
+        agent.progress.start("v2");
+        agent.progress.set_stage(UpgradeStage::Download, 0).unwrap();
+        let res = agent.cancel().unwrap();
+        assert_eq!(res, Response { status: AgentStatus::UpgradeCancelled });
+        assert!(agent.progress.check_cancelled().is_err());
+        agent.progress.fail("cancelled".to_string());
+        assert_eq!(agent.get_progress().unwrap().stage, UpgradeStage::Cancelled);
+
+        // the job stuck in a command doesn't block cancel
+        agent.progress.start("v4");
+        agent.progress.set_stage(UpgradeStage::Download, 0).unwrap();
+        let guard = agent.lock.acquire_blocking("prepare_upgrade");
+        let res = agent.cancel().unwrap();
+        assert_eq!(res, Response { status: AgentStatus::UpgradeCancelling });
+        assert_eq!(agent.get_progress().unwrap().stage, UpgradeStage::Cancelling);
+        drop(guard);
+
+        agent.progress.start("v3");
+        agent.progress.set_stage(UpgradeStage::Install, 0).unwrap();
+        assert!(agent.cancel().is_err());
+    }
+
+    #[test]
+    fn test_watchdog_check() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut agent = test_agent(tmp_dir.path());
+        let check = agent.watchdog_check();
+        assert_eq!(check(), ("waiting for requests".to_string(), true));
+        agent.progress.start("v2");
+        agent.progress.set_stage(UpgradeStage::Download, 100).unwrap();
+        agent.progress.add_bytes(40);
+        assert_eq!(check(), ("downloading update v2 40%".to_string(), true));
+
+        agent.config.stall_timeout = 1;
+        let check = agent.watchdog_check();
+        thread::sleep(Duration::from_millis(1100));
+        let (status, healthy) = check();
+        assert!(status.starts_with("downloading update v2 40%, no progress for"));
+        assert!(!healthy);
+    }
+
+    #[test]
+    fn test_get_status() {
+        let agent = AgentImpl::default();
+        let mut executor = MockCommandExec::new();
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "findmnt")
+            .returning(|_, _| Ok("/dev/vda2".to_string()));
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "lsblk")
+            .returning(|_, _| Ok("ext4    3145728000\n".to_string()));
+        executor.expect_run_command().returning(|_, _| Err(anyhow::anyhow!("veritysetup failed")));
+
+        let res = agent.get_status_impl(&executor).unwrap();
+        assert_eq!(res.current_partition.menuentry, "A");
+        assert_eq!(res.next_partition.menuentry, "B");
+        assert_eq!(res.next_partition.device, "/dev/vda3");
+        assert!(!res.dmv_mode);
+        assert!(!res.busy);
+
+        let _guard = agent.lock.acquire_blocking("test");
+        let res = agent.get_status_impl(&executor).unwrap();
+        assert!(res.busy);
+        assert_eq!(res.lock_holder.unwrap().operation, "test");
+    }
+
+    fn mock_boot_trial(grubenv: &'static str, check_result: fn() -> Result<()>) -> MockCommandExec {
+        let mut executor = MockCommandExec::new();
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, args| name == "grub2-editenv" && args.last() == Some(&"list"))
+            .returning(move |_, _| Ok(grubenv.to_string()));
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "findmnt")
+            .returning(|_, _| Ok("/dev/vda3".to_string()));
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "lsblk")
+            .returning(|_, _| Ok("ext4    3145728000\n".to_string()));
+        executor.expect_run_command().withf(|name, _| name == "systemctl").returning(move |_, _| check_result());
+        executor
+            .expect_run_command()
+            .withf(|name, args| name == "grub2-editenv" && args.get(1) == Some(&"unset"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        executor
+    }
+
+    #[test]
+    fn test_boot_check() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config =
+            AgentConfig { persist_dir: tmp_dir.path().to_str().unwrap().to_string(), ..Default::default() };
+        config.health_check.units = vec!["kubelet.service".to_string()];
+        config.health_check.timeout = 1;
+        config.health_check.interval = 1;
+        let journal = Journal::new(tmp_dir.path().join(JOURNAL_FILE), MAX_JOURNAL_SIZE);
+
+        // no boot trial
+        let mut executor = MockCommandExec::new();
+        executor.expect_run_command_with_output().returning(|_, _| Ok("saved_entry=B".to_string()));
+        assert!(!boot_check(&config, &journal, &executor).unwrap());
+
+        // B is booted on trial and healthy
+        let executor = mock_boot_trial("saved_entry=B\nboot_counter=2\nboot_fallback_entry=A", || Ok(()));
+        assert!(!boot_check(&config, &journal, &executor).unwrap());
+
+        // the fallback partition is booted
+        let executor = mock_boot_trial("saved_entry=B\nboot_counter=2\nboot_fallback_entry=B", || Ok(()));
+        assert!(!boot_check(&config, &journal, &executor).unwrap());
+
+        // B is booted on trial but unhealthy
+        let mut executor = mock_boot_trial("saved_entry=B\nboot_counter=2\nboot_fallback_entry=A", || {
+            Err(anyhow::anyhow!("inactive"))
+        });
+        executor
+            .expect_run_command()
+            .withf(|name, args| {
+                name == "grub2-set-default" && args == ["A"] || name == "grub2-editenv" && args[2] == "saved_entry=A"
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        assert!(boot_check(&config, &journal, &executor).unwrap());
+        let history = journal.history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].operation, "boot_check");
+    }
+
+    #[test]
+    fn test_get_capabilities() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let mut executor = MockCommandExec::new();
+        executor.expect_run_command().returning(|_, _| Err(anyhow::anyhow!("veritysetup failed")));
+        let res = agent.get_capabilities_impl(&executor);
+        assert_eq!(res.protocol_version, PROTOCOL_VERSION);
+        assert!(res.supports_image_type(IMAGE_TYPE_DISK));
+        assert!(res.supports_config_model("kernel.sysctl"));
+        assert!(res.supports_config_model("systemd.unit"));
+        assert!(res.supports_config_model("kernel.modules"));
+        assert!(!res.supports_config_model("invalid"));
+        assert!(res.has_feature(FEATURE_PROGRESS));
+        assert!(!res.has_feature(FEATURE_DM_VERITY));
+
+        let mut executor = MockCommandExec::new();
+        executor.expect_run_command().returning(|_, _| Ok(()));
+        let res = agent.get_capabilities_impl(&executor);
+        assert!(!res.supports_image_type(IMAGE_TYPE_DISK));
+        assert!(res.has_feature(FEATURE_DM_VERITY));
+        // every legacy model is still supported
+        assert!(CapabilitiesResponse::legacy().config_models.iter().all(|m| res.supports_config_model(m)));
+    }
+
+    #[test]
+    fn test_preflight() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let mut executor = MockCommandExec::new();
+        executor.expect_run_command().returning(|_, _| Err(anyhow::anyhow!("veritysetup failed")));
+        executor.expect_run_command_with_output().returning(|_, _| Err(anyhow::anyhow!("findmnt failed")));
+        executor.expect_clone().returning(|| {
+            let mut executor = MockCommandExec::new();
+            executor.expect_run_command_with_output().returning(|_, _| Err(anyhow::anyhow!("findmnt failed")));
+            executor
+        });
+        let req = UpgradeRequest {
+            version: "KubeOS v2".into(),
+            image_type: "invalid".into(),
+            check_sum: "".into(),
+            container_image: "".into(),
+            image_url: "".into(),
+            flag_safe: true,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        };
+        let res = agent.preflight_impl(req, &executor);
+        let checks: Vec<&str> = res.failures.iter().map(|f| f.check.as_str()).collect();
+        assert!(checks.contains(&CHECK_PARTITION));
+        assert!(checks.contains(&CHECK_IMAGE_TYPE));
+        assert!(agent.get_capabilities_impl(&executor).has_feature(FEATURE_PREFLIGHT));
+    }
+
+    #[test]
+    fn test_check_rollback_slot() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut agent = test_agent(tmp_dir.path());
+        let mock_executor = |next_fs_type: &'static str| {
+            let mut executor = MockCommandExec::new();
+            executor.expect_run_command().returning(|_, _| Err(anyhow::anyhow!("veritysetup failed")));
+            executor
+                .expect_run_command_with_output()
+                .withf(|name, _| name == "findmnt")
+                .returning(|_, _| Ok("/dev/sda2".to_string()));
+            executor
+                .expect_run_command_with_output()
+                .withf(|name, args| name == "lsblk" && args[0] == "-blno")
+                .returning(|_, _| Ok("ext4 1024".to_string()));
+            executor
+                .expect_run_command_with_output()
+                .withf(|name, args| name == "lsblk" && args == ["-no", "FSTYPE", "/dev/sda3"])
+                .returning(move |_, _| Ok(next_fs_type.to_string()));
+            executor
+        };
+        // partitions installed before recording slot metadata can be rolled back to
+        agent.check_rollback_slot(&mock_executor("ext4")).unwrap();
+        assert!(agent.check_rollback_slot(&mock_executor("")).is_err());
+
+        let metadata = SlotMetadata { version: "KubeOS 1.0.1".to_string(), ..Default::default() };
+        agent.slots.begin_install("B", metadata.clone()).unwrap();
+        assert!(agent.check_rollback_slot(&mock_executor("ext4")).is_err());
+        agent.slots.finish_install("B", metadata).unwrap();
+        agent.check_rollback_slot(&mock_executor("ext4")).unwrap();
+        agent.config.min_rollback_version = Some("KubeOS 1.0.2".to_string());
+        assert!(agent.check_rollback_slot(&mock_executor("ext4")).is_err());
+
+        let res = agent.list_slots_impl(&mock_executor("ext4")).unwrap();
+        assert_eq!(res.slots.len(), 2);
+        assert_eq!(res.slots[1].state, SlotState::Installed);
+        assert!(agent.get_capabilities_impl(&mock_executor("ext4")).has_feature(FEATURE_SLOTS));
+    }
+
+    #[test]
+    fn test_verify_slot() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let mock_executor = || {
+            let mut executor = MockCommandExec::new();
+            executor.expect_run_command().returning(|_, _| Err(anyhow::anyhow!("veritysetup failed")));
+            executor
+                .expect_run_command_with_output()
+                .withf(|name, _| name == "findmnt")
+                .returning(|_, _| Ok("/dev/sda2".to_string()));
+            executor
+                .expect_run_command_with_output()
+                .withf(|name, _| name == "lsblk")
+                .returning(|_, _| Ok("ext4 1024".to_string()));
+            executor
+        };
+        let err = agent.verify_slot_impl(&mock_executor()).unwrap_err();
+        assert_eq!(err.to_string(), "No image is recorded for partition B");
+
+        let metadata = SlotMetadata { version: "KubeOS 1.0.1".to_string(), ..Default::default() };
+        agent.slots.finish_install("B", metadata).unwrap();
+        let err = agent.verify_slot_impl(&mock_executor()).unwrap_err();
+        assert_eq!(err.to_string(), "No image checksum is recorded for partition B");
+
+        let _guard = agent.lock.acquire("prepare-upgrade", Duration::ZERO).unwrap();
+        assert!(agent.verify_slot_impl(&mock_executor()).is_err());
+        assert!(agent.get_capabilities_impl(&mock_executor()).has_feature(FEATURE_VERIFY_SLOT));
+    }
+
+    #[test]
+    fn test_collect_diagnostics() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut agent = test_agent(tmp_dir.path());
+        agent.config.grubenv_path = tmp_dir.path().join("grubenv").to_str().unwrap().to_string();
+        std::fs::write(&agent.config.grubenv_path, "saved_entry=A\n").unwrap();
+        let mock_executor = || {
+            let mut executor = MockCommandExec::new();
+            executor.expect_run_command_with_output().returning(|_, _| Err(anyhow::anyhow!("not found")));
+            executor
+                .expect_run_command()
+                .withf(|name, _| name == "tar")
+                .returning(|name, args| RealCommandExecutor {}.run_command(name, args));
+            executor.expect_run_command().returning(|_, _| Err(anyhow::anyhow!("veritysetup failed")));
+            executor
+        };
+        let mut executor = mock_executor();
+        executor.expect_clone().returning(mock_executor);
+
+        let res = agent.collect_diagnostics_impl(&executor).unwrap();
+        assert!(Path::new(&res.path).starts_with(tmp_dir.path()));
+        assert_eq!(res.check_sum.len(), 64);
+        assert!(agent.get_capabilities_impl(&executor).has_feature(FEATURE_DIAGNOSTICS));
+    }
+
+    #[test]
+    fn test_resume_installed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let agent = test_agent(tmp_dir.path());
+        let req = UpgradeRequest {
+            version: "KubeOS v2".into(),
+            image_type: IMAGE_TYPE_DISK.into(),
+            check_sum: "sha256".into(),
+            container_image: "".into(),
+            image_url: "http://localhost:1/os.tar".into(),
+            flag_safe: true,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        };
+        let handler = ImageType::Disk(DiskImageHandler {
+            paths: PreparePath::new(tmp_dir.path()),
+            executor: MockCommandExec::new(),
+            certs_path: "".to_string(),
+            dmv: false,
+            progress: ProgressTracker::default(),
+            checkpoint: agent.checkpoint.clone(),
+        });
+        // the download fails without the checkpoint
+        assert!(prepare_upgrade_job(&handler, &req, &agent.slots, &agent.checkpoint).is_err());
+        agent.checkpoint.save(&req, PrepareStep::Installed);
+        agent.report_prepare_state();
+        prepare_upgrade_job(&handler, &req, &agent.slots, &agent.checkpoint).unwrap();
     }
 }
@@ -0,0 +1,302 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use jsonrpc_core::{Call, Error, ErrorCode, Failure, Id, IoHandler, Output, Request, Version};
+use log::{debug, error, warn};
+use manager::utils::{Counter, RequestScope};
+use serde_json::Value;
+
+use crate::auth::{AuthPolicy, PeerInfo};
+
+const PERMISSION_DENIED: i64 = -32001;
+// the callers connect for every request, so a few connections are enough and the rest are refused
+const MAX_CONNECTIONS: usize = 16;
+// a connection is closed once the peer sends nothing or reads nothing for a while
+#[cfg(not(test))]
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(test)]
+const IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub static RPC_CALLS: Counter =
+    Counter::new("kubeos_agent_rpc_calls", "RPC calls handled by os-agent.", &["method", "result"]);
+
+/// Serve json rpc requests on the unix socket, every connection is handled in its own thread and every request is
+/// checked against the authorization policy with the credentials of the connected peer. At most MAX_CONNECTIONS
+/// connections are served at the same time.
+pub fn serve(listener: UnixListener, io: IoHandler, policy: AuthPolicy) {
+    let io = Arc::new(io);
+    let policy = Arc::new(policy);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            },
+        };
+        let Some(slot) = ConnectionSlot::take(&active) else {
+            warn!("Refused connection, {} connections are being served already", MAX_CONNECTIONS);
+            continue;
+        };
+        let io = io.clone();
+        let policy = policy.clone();
+        let spawned = thread::Builder::new().name("rpc-connection".to_string()).spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(&io, &policy, stream) {
+                warn!("Connection closed: {:#}", e);
+            }
+        });
+        if let Err(e) = spawned {
+            error!("Failed to spawn thread for connection: {}", e);
+        }
+    }
+}
+
+/// ConnectionSlot counts a connection being served until it is dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        active.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < MAX_CONNECTIONS).then_some(n + 1)).ok()?;
+        Some(ConnectionSlot(active.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_connection(io: &IoHandler, policy: &AuthPolicy, stream: UnixStream) -> Result<()> {
+    let peer = PeerInfo::from_stream(&stream)?;
+    debug!("Accepted connection from {:?}", peer);
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for request in serde_json::Deserializer::from_reader(&stream).into_iter::<Value>() {
+        let response = match request {
+            Ok(request) => {
+                let _scope = RequestScope::enter(request_id(&request));
+                let response = match check_permission(policy, &peer, &request) {
+                    Ok(_) => io.handle_request_sync(&request.to_string()),
+                    Err(denied) => denied,
+                };
+                count_calls(io, &request, response.as_deref());
+                response
+            },
+            Err(e) if e.is_eof() => break,
+            Err(e) if e.is_io() => return Err(e.into()),
+            Err(e) => {
+                // the stream can not be recovered once it is not valid json
+                let failure =
+                    Output::Failure(Failure { jsonrpc: Some(Version::V2), error: Error::parse_error(), id: Id::Null });
+                writer.write_all(serde_json::to_string(&failure)?.as_bytes())?;
+                writer.write_all(b"\n")?;
+                return Err(e.into());
+            },
+        };
+        if let Some(response) = response {
+            writer.write_all(response.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// The id of a single request, which the caller sets to correlate its logs with the logs of os-agent serving the
+/// request. Batch requests have no id to attach as they contain many calls.
+fn request_id(request: &Value) -> Option<String> {
+    match request.get("id")? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Check whether the peer is allowed to call all methods in the request, otherwise returns the response to the denied
+/// request. Denied notifications are dropped without response.
+fn check_permission(policy: &AuthPolicy, peer: &PeerInfo, request: &Value) -> Result<(), Option<String>> {
+    // leave malformed requests to the handler to report
+    let request: Request = match serde_json::from_value(request.clone()) {
+        Ok(request) => request,
+        Err(_) => return Ok(()),
+    };
+    let calls = match &request {
+        Request::Single(call) => std::slice::from_ref(call),
+        Request::Batch(calls) => calls.as_slice(),
+    };
+    let denied: Vec<&str> = calls
+        .iter()
+        .filter_map(|call| match call {
+            Call::MethodCall(c) => Some(c.method.as_str()),
+            Call::Notification(n) => Some(n.method.as_str()),
+            Call::Invalid { .. } => None,
+        })
+        .filter(|method| !policy.is_allowed(peer, method))
+        .collect();
+    if denied.is_empty() {
+        return Ok(());
+    }
+    warn!("Peer {:?} is not allowed to call {:?}", peer, denied);
+    let outputs: Vec<Output> = calls
+        .iter()
+        .filter_map(|call| match call {
+            Call::MethodCall(c) => Some(Output::Failure(Failure {
+                jsonrpc: Some(Version::V2),
+                error: Error {
+                    code: ErrorCode::ServerError(PERMISSION_DENIED),
+                    message: format!("Permission denied to call {}", c.method),
+                    data: None,
+                },
+                id: c.id.clone(),
+            })),
+            _ => None,
+        })
+        .collect();
+    let response = match request {
+        _ if outputs.is_empty() => return Err(None),
+        Request::Single(_) => serde_json::to_string(&outputs[0]),
+        Request::Batch(_) => serde_json::to_string(&outputs),
+    };
+    Err(response.ok())
+}
+
+/// Count the calls of known methods in the request by the results in the response, notifications are not counted
+/// as they have no response
+fn count_calls(io: &IoHandler, request: &Value, response: Option<&str>) {
+    let Ok(request) = serde_json::from_value::<Request>(request.clone()) else {
+        return;
+    };
+    let outputs: Vec<Output> = match response.map(serde_json::from_str::<Value>) {
+        Some(Ok(Value::Array(outputs))) => outputs.into_iter().filter_map(|o| serde_json::from_value(o).ok()).collect(),
+        Some(Ok(output)) => serde_json::from_value(output).into_iter().collect(),
+        _ => Vec::new(),
+    };
+    let calls = match &request {
+        Request::Single(call) => std::slice::from_ref(call),
+        Request::Batch(calls) => calls.as_slice(),
+    };
+    for call in calls {
+        let Call::MethodCall(call) = call else {
+            continue;
+        };
+        // unknown method names are not recorded to keep the label values bounded
+        if !io.iter().any(|(method, _)| method == &call.method) {
+            continue;
+        }
+        let result = match outputs.iter().find(|output| output.id() == &call.id) {
+            Some(Output::Success(_)) => "success",
+            Some(Output::Failure(f)) if f.error.code == ErrorCode::ServerError(PERMISSION_DENIED) => "denied",
+            _ => "failure",
+        };
+        RPC_CALLS.inc(&[&call.method, result]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use jsonrpc_core::Params;
+
+    use super::*;
+    use crate::auth::AuthRule;
+
+    fn start(policy: AuthPolicy) -> (UnixStream, BufReader<UnixStream>) {
+        let mut io = IoHandler::new();
+        io.add_sync_method("get_status", |_: Params| Ok(Value::String("ok".to_string())));
+        io.add_sync_method("upgrade", |_: Params| Ok(Value::String("upgraded".to_string())));
+        let (server, client) = UnixStream::pair().unwrap();
+        thread::spawn(move || handle_connection(&io, &policy, server));
+        let reader = BufReader::new(client.try_clone().unwrap());
+        (client, reader)
+    }
+
+    fn call(client: &mut UnixStream, reader: &mut BufReader<UnixStream>, request: &str) -> Value {
+        client.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_handle_connection() {
+        let uid = nix::unistd::getuid().as_raw();
+        let policy = AuthPolicy {
+            rules: vec![AuthRule { uids: vec![uid], methods: vec!["get_status".to_string()], ..Default::default() }],
+        };
+        let (mut client, mut reader) = start(policy);
+
+        let resp = call(&mut client, &mut reader, r#"{"jsonrpc":"2.0","method":"get_status","params":[],"id":1}"#);
+        assert_eq!(resp["result"], "ok");
+
+        let resp = call(&mut client, &mut reader, r#"{"jsonrpc":"2.0","method":"upgrade","params":[],"id":2}"#);
+        assert_eq!(resp["error"]["code"], PERMISSION_DENIED);
+        assert_eq!(resp["id"], 2);
+
+        let resp = call(
+            &mut client,
+            &mut reader,
+            r#"[{"jsonrpc":"2.0","method":"get_status","id":3},{"jsonrpc":"2.0","method":"upgrade","id":4}]"#,
+        );
+        assert_eq!(resp.as_array().unwrap().len(), 2);
+        assert_eq!(resp[1]["error"]["code"], PERMISSION_DENIED);
+
+        let resp = call(&mut client, &mut reader, r#"{"jsonrpc":"2.0","method":"unknown","id":5}"#);
+        assert_eq!(resp["error"]["code"], PERMISSION_DENIED);
+        assert!(RPC_CALLS.get(&["get_status", "success"]) >= 1);
+        assert!(RPC_CALLS.get(&["upgrade", "denied"]) >= 2);
+
+        let resp = call(&mut client, &mut reader, "{invalid");
+        assert_eq!(resp["error"]["code"], -32700);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let policy = AuthPolicy { rules: vec![AuthRule { methods: vec!["*".to_string()], ..Default::default() }] };
+        let (server, _client) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || handle_connection(&IoHandler::new(), &policy, server));
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_connection_slot() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS).map(|_| ConnectionSlot::take(&active).unwrap()).collect();
+        assert!(ConnectionSlot::take(&active).is_none());
+        drop(slots);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+        assert!(ConnectionSlot::take(&active).is_some());
+    }
+
+    #[test]
+    fn test_allow_all() {
+        let policy = AuthPolicy { rules: vec![AuthRule { methods: vec!["*".to_string()], ..Default::default() }] };
+        let (mut client, mut reader) = start(policy);
+        let resp = call(&mut client, &mut reader, r#"{"jsonrpc":"2.0","method":"upgrade","params":[],"id":1}"#);
+        assert_eq!(resp["result"], "upgraded");
+    }
+}
@@ -23,7 +23,13 @@ pub struct Client {
 
 pub struct Request<'a>(JsonRPCRequest<'a>);
 
-impl<'a> Request<'a> {}
+impl<'a> Request<'a> {
+    /// Replace the generated id with the id correlating the logs of the caller and os-agent
+    pub fn set_id(&mut self, id: &str) -> &Self {
+        self.0.id = serde_json::Value::from(id);
+        self
+    }
+}
 
 impl Client {
     pub fn new<P: AsRef<Path>>(socket_path: P) -> Self {
@@ -52,5 +58,9 @@ mod test {
         let params = vec![];
         let request = cli.send_request(cli.build_request(command, &params));
         assert!(request.is_err());
+
+        let mut request = cli.build_request(command, &params);
+        request.set_id("proxy-1-0");
+        assert_eq!(request.0.id, serde_json::json!("proxy-1-0"));
     }
 }
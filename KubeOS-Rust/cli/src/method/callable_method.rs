@@ -20,7 +20,11 @@ pub trait RpcMethod {
     fn command_name(&self) -> &'static str;
     fn command_params(&self) -> Vec<Box<RawValue>>;
     fn call(&self, client: &Client) -> Result<Self::Response, anyhow::Error> {
-        let response = request(client, self.command_name(), self.command_params())?;
+        self.call_with_request_id(client, None)
+    }
+    /// Call with the id which os-agent attaches to the logs of serving the call
+    fn call_with_request_id(&self, client: &Client, request_id: Option<&str>) -> Result<Self::Response, anyhow::Error> {
+        let response = request(client, self.command_name(), self.command_params(), request_id)?;
         response.result().map_err(parse_error)
     }
 }
@@ -50,5 +54,7 @@ mod tests {
         let client = client::Client::new("/tmp/KubeOS-test.sock");
         let result = DummyMethod::default().call(&client);
         assert!(result.is_err());
+        let result = DummyMethod::default().call_with_request_id(&client, Some("proxy-1-0"));
+        assert!(result.is_err());
     }
 }
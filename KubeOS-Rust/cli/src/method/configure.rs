@@ -17,17 +17,23 @@ use crate::method::callable_method::RpcMethod;
 
 pub struct ConfigureMethod {
     req: api::ConfigureRequest,
+    wait: Option<u64>,
 }
 
 impl ConfigureMethod {
     pub fn new(req: api::ConfigureRequest) -> Self {
-        ConfigureMethod { req }
+        ConfigureMethod { req, wait: None }
     }
 
     pub fn set_configure_request(&mut self, req: api::ConfigureRequest) -> &Self {
         self.req = req;
         self
     }
+
+    pub fn set_wait(&mut self, wait: u64) -> &Self {
+        self.wait = Some(wait);
+        self
+    }
 }
 
 impl RpcMethod for ConfigureMethod {
@@ -36,7 +42,11 @@ impl RpcMethod for ConfigureMethod {
         "configure"
     }
     fn command_params(&self) -> Vec<Box<RawValue>> {
-        vec![to_raw_value(&self.req).unwrap()]
+        let mut params = vec![to_raw_value(&self.req).unwrap()];
+        if let Some(wait) = self.wait {
+            params.push(to_raw_value(&wait).unwrap());
+        }
+        params
     }
 }
 #[cfg(test)]
@@ -68,5 +78,9 @@ mod tests {
             "RawValue({\"configs\":[{\"model\":\"model\",\"config_path\":\"config_path\",\"contents\":{}}]})";
         let actual_params = format!("{:?}", method.command_params()[0]);
         assert_eq!(actual_params, expected_params);
+        assert_eq!(method.command_params().len(), 1);
+
+        method.set_wait(30);
+        assert_eq!(format!("{:?}", method.command_params()[1]), "RawValue(30)");
     }
 }
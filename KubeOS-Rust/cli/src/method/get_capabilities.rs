@@ -0,0 +1,42 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use manager::api;
+use serde_json::value::RawValue;
+
+use crate::method::callable_method::RpcMethod;
+
+#[derive(Default)]
+pub struct GetCapabilitiesMethod {}
+
+impl RpcMethod for GetCapabilitiesMethod {
+    type Response = api::CapabilitiesResponse;
+    fn command_name(&self) -> &'static str {
+        "get_capabilities"
+    }
+    fn command_params(&self) -> Vec<Box<RawValue>> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_get_capabilities_method() {
+        let method = GetCapabilitiesMethod::default();
+        assert_eq!(method.command_name(), "get_capabilities");
+        let expected_params = "[]";
+        let actual_params = format!("{:?}", method.command_params());
+        assert_eq!(actual_params, expected_params);
+    }
+}
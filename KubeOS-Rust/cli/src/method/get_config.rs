@@ -0,0 +1,57 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use manager::api;
+use serde_json::value::{to_raw_value, RawValue};
+
+use crate::method::callable_method::RpcMethod;
+
+pub struct GetConfigMethod {
+    req: api::GetConfigRequest,
+}
+
+impl GetConfigMethod {
+    pub fn new(req: api::GetConfigRequest) -> Self {
+        GetConfigMethod { req }
+    }
+}
+
+impl RpcMethod for GetConfigMethod {
+    type Response = api::Sysconfig;
+    fn command_name(&self) -> &'static str {
+        "get_config"
+    }
+    fn command_params(&self) -> Vec<Box<RawValue>> {
+        vec![to_raw_value(&self.req).unwrap()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use manager::api::GetConfigRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_get_config_method() {
+        let method = GetConfigMethod::new(GetConfigRequest {
+            model: "kernel.sysctl".to_string(),
+            config_path: "".to_string(),
+            keys: vec!["vm.swappiness".to_string()],
+        });
+        assert_eq!(method.command_name(), "get_config");
+        let expected_params =
+            "RawValue({\"model\":\"kernel.sysctl\",\"config_path\":\"\",\"keys\":[\"vm.swappiness\"]})";
+        let actual_params = format!("{:?}", method.command_params()[0]);
+        assert_eq!(actual_params, expected_params);
+    }
+}
@@ -11,8 +11,20 @@
  */
 
 pub mod callable_method;
+pub mod cancel;
+pub mod collect_diagnostics;
 pub mod configure;
+pub mod get_capabilities;
+pub mod get_config;
+pub mod get_drift;
+pub mod get_history;
+pub mod get_progress;
+pub mod get_status;
+pub mod list_slots;
+pub mod plan_configure;
+pub mod preflight;
 pub mod prepare_upgrade;
 pub mod request;
 pub mod rollback;
 pub mod upgrade;
+pub mod verify_slot;
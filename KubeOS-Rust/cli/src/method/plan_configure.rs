@@ -0,0 +1,52 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use manager::api;
+use serde_json::value::{to_raw_value, RawValue};
+
+use crate::method::callable_method::RpcMethod;
+
+pub struct PlanConfigureMethod {
+    req: api::ConfigureRequest,
+}
+
+impl PlanConfigureMethod {
+    pub fn new(req: api::ConfigureRequest) -> Self {
+        PlanConfigureMethod { req }
+    }
+}
+
+impl RpcMethod for PlanConfigureMethod {
+    type Response = api::PlanConfigureResponse;
+    fn command_name(&self) -> &'static str {
+        "plan_configure"
+    }
+    fn command_params(&self) -> Vec<Box<RawValue>> {
+        vec![to_raw_value(&self.req).unwrap()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use manager::api::ConfigureRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_plan_configure_method() {
+        let method = PlanConfigureMethod::new(ConfigureRequest { configs: vec![] });
+        assert_eq!(method.command_name(), "plan_configure");
+        let expected_params = "RawValue({\"configs\":[]})";
+        let actual_params = format!("{:?}", method.command_params()[0]);
+        assert_eq!(actual_params, expected_params);
+    }
+}
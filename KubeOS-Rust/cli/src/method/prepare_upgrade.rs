@@ -17,17 +17,24 @@ use crate::method::callable_method::RpcMethod;
 
 pub struct PrepareUpgradeMethod {
     req: api::UpgradeRequest,
+    wait: Option<u64>,
 }
 
 impl PrepareUpgradeMethod {
     pub fn new(req: api::UpgradeRequest) -> Self {
-        PrepareUpgradeMethod { req }
+        PrepareUpgradeMethod { req, wait: None }
     }
 
     pub fn set_prepare_upgrade_request(&mut self, req: api::UpgradeRequest) -> &Self {
         self.req = req;
         self
     }
+
+    /// Wait up to the seconds for the running request to finish, only os-agent with the wait feature accepts it
+    pub fn set_wait(&mut self, wait: u64) -> &Self {
+        self.wait = Some(wait);
+        self
+    }
 }
 
 impl RpcMethod for PrepareUpgradeMethod {
@@ -36,7 +43,11 @@ impl RpcMethod for PrepareUpgradeMethod {
         "prepare_upgrade"
     }
     fn command_params(&self) -> Vec<Box<RawValue>> {
-        vec![to_raw_value(&self.req).unwrap()]
+        let mut params = vec![to_raw_value(&self.req).unwrap()];
+        if let Some(wait) = self.wait {
+            params.push(to_raw_value(&wait).unwrap());
+        }
+        params
     }
 }
 #[cfg(test)]
@@ -74,5 +85,9 @@ mod tests {
         let expected_params = "RawValue({\"version\":\"v2\",\"check_sum\":\"xxx\",\"image_type\":\"xxx\",\"container_image\":\"xxx\",\"image_url\":\"\",\"flag_safe\":false,\"mtls\":false,\"certs\":{\"ca_cert\":\"\",\"client_cert\":\"\",\"client_key\":\"\"}})";
         let actual_params = format!("{:?}", method.command_params()[0]);
         assert_eq!(actual_params, expected_params);
+        assert_eq!(method.command_params().len(), 1);
+
+        method.set_wait(30);
+        assert_eq!(format!("{:?}", method.command_params()[1]), "RawValue(30)");
     }
 }
@@ -17,8 +17,18 @@ use serde_json::value::RawValue;
 
 use crate::client::Client;
 
-pub fn request(client: &Client, command: &str, params: Vec<Box<RawValue>>) -> Result<Response, anyhow::Error> {
-    let request = client.build_request(command, &params);
+const METHOD_UNIMPLEMENTED: &str = "Method is unimplemented";
+
+pub fn request(
+    client: &Client,
+    command: &str,
+    params: Vec<Box<RawValue>>,
+    request_id: Option<&str>,
+) -> Result<Response, anyhow::Error> {
+    let mut request = client.build_request(command, &params);
+    if let Some(id) = request_id {
+        request.set_id(id);
+    }
     let response = client.send_request(request).map_err(parse_error);
     debug!("{:#?}", response);
     response
@@ -38,7 +48,7 @@ pub fn parse_error(error: Error) -> anyhow::Error {
         },
         Error::Rpc(ref e) => {
             if e.message == "Method not found" {
-                anyhow!("Method is unimplemented")
+                anyhow!(METHOD_UNIMPLEMENTED)
             } else {
                 anyhow!("{}", e.message)
             }
@@ -50,6 +60,11 @@ pub fn parse_error(error: Error) -> anyhow::Error {
     }
 }
 
+/// Whether the error is returned by os-agent of older versions without the method
+pub fn is_method_unimplemented(error: &anyhow::Error) -> bool {
+    error.to_string() == METHOD_UNIMPLEMENTED
+}
+
 #[cfg(test)]
 mod tests {
     use jsonrpc::error::RpcError;
@@ -74,6 +89,7 @@ mod tests {
         let rpc_error = Error::Rpc(RpcError { code: -32601, message: "Method not found".to_string(), data: None });
         let result = parse_error(rpc_error);
         assert_eq!(result.to_string(), "Method is unimplemented");
+        assert!(is_method_unimplemented(&result));
 
         // Test Error::Rpc with other message
         let rpc_error = Error::Rpc(RpcError { code: -32603, message: "Internal server error".to_string(), data: None });
@@ -11,12 +11,21 @@
  */
 
 use manager::api;
-use serde_json::value::RawValue;
+use serde_json::value::{to_raw_value, RawValue};
 
 use crate::method::callable_method::RpcMethod;
 
 #[derive(Default)]
-pub struct UpgradeMethod {}
+pub struct UpgradeMethod {
+    wait: Option<u64>,
+}
+
+impl UpgradeMethod {
+    pub fn set_wait(&mut self, wait: u64) -> &Self {
+        self.wait = Some(wait);
+        self
+    }
+}
 
 impl RpcMethod for UpgradeMethod {
     type Response = api::Response;
@@ -24,7 +33,7 @@ impl RpcMethod for UpgradeMethod {
         "upgrade"
     }
     fn command_params(&self) -> Vec<Box<RawValue>> {
-        vec![]
+        self.wait.iter().map(|wait| to_raw_value(wait).unwrap()).collect()
     }
 }
 
@@ -33,10 +42,13 @@ mod tests {
     use super::*;
     #[test]
     fn test_upgrade_method() {
-        let method = UpgradeMethod::default();
+        let mut method = UpgradeMethod::default();
         assert_eq!(method.command_name(), "upgrade");
         let expected_params = "[]";
         let actual_params = format!("{:?}", method.command_params());
         assert_eq!(actual_params, expected_params);
+
+        method.set_wait(30);
+        assert_eq!(format!("{:?}", method.command_params()), "[RawValue(30)]");
     }
 }
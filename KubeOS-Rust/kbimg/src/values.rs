@@ -1683,6 +1683,27 @@ else
    set default="${saved_entry}"
 fi
 
+# os-agent starts a boot trial on upgrade, and ends it once the health checks pass on the new menuentry.
+# The fallback menuentry is booted again if the trial is not ended within boot_counter boots.
+if [ "${boot_counter}" ]; then
+  if [ "${boot_counter}" = "0" ]; then
+    set default="${boot_fallback_entry}"
+    set saved_entry="${boot_fallback_entry}"
+    set boot_counter=
+    set boot_fallback_entry=
+    save_env saved_entry boot_counter boot_fallback_entry
+  else
+    if [ "${boot_counter}" = "3" ]; then
+      set boot_counter=2
+    elif [ "${boot_counter}" = "2" ]; then
+      set boot_counter=1
+    else
+      set boot_counter=0
+    fi
+    save_env boot_counter
+  fi
+fi
+
 if [ x"${feature_menuentry_id}" = xy ]; then
   menuentry_id_option="--id"
 else
@@ -14,8 +14,47 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum AgentStatus {
+    UpgradePreparing,
     UpgradeReady,
     Upgraded,
     Rollbacked,
     Configured,
+    UpgradeCancelled,
+    UpgradeCancelling,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum UpgradeStage {
+    #[default]
+    Idle,
+    Download,
+    Verify,
+    BuildImage,
+    Install,
+    Done,
+    Failed,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum OperationOutcome {
+    #[default]
+    Running,
+    Succeeded,
+    Failed,
+    Interrupted,
+}
+
+/// SlotState is the state of the image installed to an A/B partition
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SlotState {
+    /// no metadata is recorded, such as the partition installed before os-agent records slot metadata
+    #[default]
+    Unknown,
+    /// the partition has no file system
+    Empty,
+    /// an install started but never finished, the partition is corrupt
+    Installing,
+    Installed,
 }
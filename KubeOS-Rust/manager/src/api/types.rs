@@ -16,11 +16,48 @@ use serde::{Deserialize, Serialize};
 
 use super::agent_status::*;
 use crate::{
-    sys_mgmt::{CtrImageHandler, DiskImageHandler, DockerImageHandler},
-    utils::{CommandExecutor, UpgradeImageManager},
+    sys_mgmt::{
+        CtrImageHandler, DiskImageHandler, DockerImageHandler, CONTAINER_CONTAINERD, DMV_BOOT_IMG, DMV_HASH_IMG,
+        DMV_ROOT_IMG, GRUB_CMDLINE_CURRENT, GRUB_CMDLINE_NEXT, KERNEL_SYSCTL, KERNEL_SYSCTL_PERSIST,
+        KUBERNETES_KUBELET, PAM_LIMTS,
+    },
+    utils::{clean_env, delete_file_or_dir, CommandExecutor, PartitionInfo, UpgradeImageManager},
 };
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Version of the shapes of requests and responses between os-proxy and os-agent, increased on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version os-agent still accepts requests of
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+
+pub const IMAGE_TYPE_CONTAINERD: &str = "containerd";
+pub const IMAGE_TYPE_DOCKER: &str = "docker";
+pub const IMAGE_TYPE_DISK: &str = "disk";
+
+/// prepare_upgrade returns once the preparation starts, whose progress is reported by get_progress
+pub const FEATURE_PROGRESS: &str = "progress";
+pub const FEATURE_CANCEL: &str = "cancel";
+pub const FEATURE_HISTORY: &str = "history";
+pub const FEATURE_PLAN_CONFIGURE: &str = "plan_configure";
+/// The node boots from dm-verity protected partitions, and can only be upgraded by container images
+pub const FEATURE_DM_VERITY: &str = "dm-verity";
+pub const FEATURE_PREFLIGHT: &str = "preflight";
+pub const FEATURE_SLOTS: &str = "slots";
+pub const FEATURE_DIAGNOSTICS: &str = "diagnostics";
+pub const FEATURE_VERIFY_SLOT: &str = "verify_slot";
+pub const FEATURE_GET_CONFIG: &str = "get_config";
+pub const FEATURE_DRIFT: &str = "drift";
+/// prepare_upgrade, upgrade, configure and rollback take the seconds to wait for the running request to finish
+pub const FEATURE_WAIT: &str = "wait";
+
+pub const CHECK_IMAGE_TYPE: &str = "image_type";
+pub const CHECK_VERSION: &str = "version";
+pub const CHECK_PARTITION: &str = "partition";
+pub const CHECK_RUNTIME: &str = "runtime";
+pub const CHECK_CERTIFICATES: &str = "certificates";
+pub const CHECK_IMAGE_SOURCE: &str = "image_source";
+pub const CHECK_DISK_SPACE: &str = "disk_space";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct UpgradeRequest {
     pub version: String,
     pub check_sum: String,
@@ -32,7 +69,7 @@ pub struct UpgradeRequest {
     pub certs: CertsInfo,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CertsInfo {
     pub ca_cert: String,
     pub client_cert: String,
@@ -45,7 +82,7 @@ pub struct KeyInfo {
     pub operation: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Sysconfig {
     pub model: String,
     pub config_path: String,
@@ -62,6 +99,213 @@ pub struct Response {
     pub status: AgentStatus,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ProgressResponse {
+    pub version: String,
+    pub stage: UpgradeStage,
+    pub current_bytes: u64,
+    pub total_bytes: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct StatusResponse {
+    pub version: String,
+    pub agent_version: String,
+    pub current_partition: PartitionInfo,
+    pub next_partition: PartitionInfo,
+    pub boot_mode: String,
+    pub dmv_mode: bool,
+    pub image_staged: bool,
+    pub busy: bool,
+    pub lock_holder: Option<LockHolder>,
+}
+
+/// VerifySlotResponse compares the data of the partition with the image installed to it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct VerifySlotResponse {
+    pub menuentry: String,
+    pub device: String,
+    pub expected_sha256: String,
+    pub actual_sha256: String,
+    pub matched: bool,
+}
+
+/// DiagnosticsResponse is the tarball of the collected diagnostics and its sha256 checksum
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticsResponse {
+    pub path: String,
+    pub check_sum: String,
+}
+
+/// LockHolder is the operation os-agent is processing, since is the unix time in seconds it started
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LockHolder {
+    pub operation: String,
+    pub since: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub operation: String,
+    pub params: serde_json::Value,
+    pub boot_id: String,
+    pub start_time: u64,
+    pub end_time: Option<u64>,
+    pub outcome: OperationOutcome,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct HistoryResponse {
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// ConfigDiff is the value of a configuration key before and after configuring, None if the key doesn't exist
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ConfigDiff {
+    pub key: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_changed(&self) -> bool {
+        self.before != self.after
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ModelPlan {
+    pub model: String,
+    pub diffs: Vec<ConfigDiff>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct PlanConfigureResponse {
+    pub plans: Vec<ModelPlan>,
+}
+
+/// ConfigDrift is a configured key whose current value differs from the value last configured. expected is None if
+/// the key was deleted, actual is None if the key isn't set now
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ConfigDrift {
+    pub model: String,
+    pub config_path: String,
+    pub key: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct DriftResponse {
+    pub drifts: Vec<ConfigDrift>,
+}
+
+/// GetConfigRequest reads the keys of a configuration model, all keys if keys is empty. The response is a Sysconfig
+/// holding the current values, which can be configured back as it is
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GetConfigRequest {
+    pub model: String,
+    #[serde(default)]
+    pub config_path: String,
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/// SlotMetadata is the image installed to an A/B partition, recorded by os-agent when installing
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SlotMetadata {
+    pub version: String,
+    pub check_sum: String,
+    pub image_type: String,
+    pub image_source: String,
+    pub install_time: u64,
+    pub state: SlotState,
+    /// The sha256 checksum and size of the partition image written to the slot, empty if not recorded
+    #[serde(default)]
+    pub image_sha256: String,
+    #[serde(default)]
+    pub image_bytes: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SlotInfo {
+    pub menuentry: String,
+    pub device: String,
+    pub current: bool,
+    pub state: SlotState,
+    pub metadata: Option<SlotMetadata>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ListSlotsResponse {
+    pub slots: Vec<SlotInfo>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PreflightFailure {
+    pub check: String,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PreflightResponse {
+    pub failures: Vec<PreflightFailure>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CapabilitiesResponse {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub agent_version: String,
+    pub image_types: Vec<String>,
+    pub config_models: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl CapabilitiesResponse {
+    /// The capabilities of os-agent released before get_capabilities is added
+    pub fn legacy() -> Self {
+        let to_strings = |items: &[&str]| items.iter().map(|i| i.to_string()).collect();
+        CapabilitiesResponse {
+            protocol_version: 0,
+            min_protocol_version: 0,
+            agent_version: String::new(),
+            image_types: to_strings(&[IMAGE_TYPE_CONTAINERD, IMAGE_TYPE_DOCKER, IMAGE_TYPE_DISK]),
+            config_models: to_strings(&[
+                KERNEL_SYSCTL,
+                KERNEL_SYSCTL_PERSIST,
+                GRUB_CMDLINE_CURRENT,
+                GRUB_CMDLINE_NEXT,
+                KUBERNETES_KUBELET,
+                CONTAINER_CONTAINERD,
+                PAM_LIMTS,
+            ]),
+            features: Vec::new(),
+        }
+    }
+
+    /// Whether the protocol versions supported by os-agent overlap with the ones supported by this build
+    pub fn is_compatible(&self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.min_protocol_version)
+            || (self.min_protocol_version..=self.protocol_version).contains(&MIN_PROTOCOL_VERSION)
+    }
+
+    pub fn supports_image_type(&self, image_type: &str) -> bool {
+        self.image_types.iter().any(|t| t == image_type)
+    }
+
+    pub fn supports_config_model(&self, model: &str) -> bool {
+        self.config_models.iter().any(|m| m == model)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
 pub enum ImageType<T: CommandExecutor> {
     Containerd(CtrImageHandler<T>),
     Docker(DockerImageHandler<T>),
@@ -76,6 +320,22 @@ impl<T: CommandExecutor> ImageType<T> {
             ImageType::Disk(handler) => handler.download_image(req),
         }
     }
+
+    /// Unmount and delete everything staged by the handler, used when the preparation is cancelled
+    pub fn clean_env(&self) -> anyhow::Result<()> {
+        let (paths, dmv) = match self {
+            ImageType::Containerd(handler) => (&handler.paths, handler.dmv),
+            ImageType::Docker(handler) => (&handler.paths, handler.dmv),
+            ImageType::Disk(handler) => (&handler.paths, handler.dmv),
+        };
+        clean_env(&paths.update_path, &paths.mount_path, &paths.image_path)?;
+        if dmv {
+            for img in [DMV_BOOT_IMG, DMV_ROOT_IMG, DMV_HASH_IMG] {
+                delete_file_or_dir(paths.persist_path.join(img))?;
+            }
+        }
+        Ok(())
+    }
 }
 pub trait ImageHandler<T: CommandExecutor> {
     fn download_image(&self, req: &UpgradeRequest) -> anyhow::Result<UpgradeImageManager<T>>;
@@ -137,4 +397,23 @@ mod tests {
         let result = image_type.download_image(&req);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_capabilities() {
+        let legacy = CapabilitiesResponse::legacy();
+        assert!(legacy.is_compatible());
+        assert!(legacy.supports_image_type(IMAGE_TYPE_DISK));
+        assert!(legacy.supports_config_model(KUBERNETES_KUBELET));
+        assert!(!legacy.supports_config_model("unknown"));
+        assert!(!legacy.has_feature(FEATURE_PROGRESS));
+
+        let newer = CapabilitiesResponse {
+            protocol_version: PROTOCOL_VERSION + 2,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            features: vec![FEATURE_PROGRESS.to_string()],
+            ..legacy
+        };
+        assert!(!newer.is_compatible());
+        assert!(newer.has_feature(FEATURE_PROGRESS));
+    }
 }
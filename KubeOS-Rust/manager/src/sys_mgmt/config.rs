@@ -11,9 +11,9 @@
  */
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
-    io::{self, BufRead, BufWriter, Write},
+    io::{self, BufRead},
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     string::String,
@@ -28,46 +28,98 @@ use toml::{map::Map, Table};
 
 use crate::{api::*, sys_mgmt::values, utils::*};
 
+pub type ConfigTemplate = HashMap<String, Box<dyn Configuration + Send + Sync>>;
+
+const GRUB_LINUX_LINE: &str = r"^\s*linux.*root=.*";
+const SYSTEMD_UNIT_STATES: [&str; 3] = ["enabled", "disabled", "masked"];
+const KERNEL_MODULES_HEADER: &str = "# Generated by KubeOS, changes to this file will be overwritten";
+const SYSTEMD_DROPIN_HEADER: &str = "# Generated by KubeOS, changes to this file will be overwritten\n";
+
 lazy_static! {
-    pub static ref CONFIG_TEMPLATE: HashMap<String, Box<dyn Configuration + Sync>> = {
-        let mut config_map = HashMap::new();
-        config_map.insert(
-            values::KERNEL_SYSCTL.to_string(),
-            Box::new(KernelSysctl::new(values::DEFAULT_PROC_PATH)) as Box<dyn Configuration + Sync>,
-        );
-        config_map.insert(
-            values::KERNEL_SYSCTL_PERSIST.to_string(),
-            Box::new(KernelSysctlPersist) as Box<dyn Configuration + Sync>,
-        );
-        config_map.insert(
-            values::GRUB_CMDLINE_CURRENT.to_string(),
-            Box::new(GrubCmdline { grub_path: values::DEFAULT_GRUB_CFG_PATH.to_string(), is_cur_partition: true })
-                as Box<dyn Configuration + Sync>,
-        );
-        config_map.insert(
-            values::GRUB_CMDLINE_NEXT.to_string(),
-            Box::new(GrubCmdline { grub_path: values::DEFAULT_GRUB_CFG_PATH.to_string(), is_cur_partition: false })
-                as Box<dyn Configuration + Sync>,
-        );
-        config_map.insert(
-            values::KUBERNETES_KUBELET.to_string(),
-            Box::new(KubernetesKubelet) as Box<dyn Configuration + Sync>,
-        );
-        config_map.insert(
-            values::CONTAINER_CONTAINERD.to_string(),
-            Box::new(ContainerContainerd) as Box<dyn Configuration + Sync>,
-        );
-        config_map.insert(
-            values::PAM_LIMTS.to_string(),
-            Box::new(PamLimits { config_path: values::DEFAULT_PAM_LIMITS_PATH.to_string() })
-                as Box<dyn Configuration + Sync>,
-        );
-        config_map
-    };
+    pub static ref CONFIG_TEMPLATE: ConfigTemplate = config_template(values::DEFAULT_GRUB_CFG_PATH);
+}
+
+/// Build the configuration models, grub.cmdline models modify the grub.cfg in grub_cfg_path
+pub fn config_template(grub_cfg_path: &str) -> ConfigTemplate {
+    let mut config_map: ConfigTemplate = HashMap::new();
+    config_map.insert(
+        values::KERNEL_SYSCTL.to_string(),
+        Box::new(KernelSysctl::new(values::DEFAULT_PROC_PATH)) as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::KERNEL_SYSCTL_PERSIST.to_string(),
+        Box::new(KernelSysctlPersist) as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::GRUB_CMDLINE_CURRENT.to_string(),
+        Box::new(GrubCmdline { grub_path: grub_cfg_path.to_string(), is_cur_partition: true })
+            as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::GRUB_CMDLINE_NEXT.to_string(),
+        Box::new(GrubCmdline { grub_path: grub_cfg_path.to_string(), is_cur_partition: false })
+            as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::KUBERNETES_KUBELET.to_string(),
+        Box::new(KubernetesKubelet) as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::CONTAINER_CONTAINERD.to_string(),
+        Box::new(ContainerContainerd) as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::PAM_LIMTS.to_string(),
+        Box::new(PamLimits { config_path: values::DEFAULT_PAM_LIMITS_PATH.to_string() })
+            as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::KERNEL_MODULES.to_string(),
+        Box::new(KernelModules {
+            load_path: values::DEFAULT_MODULES_LOAD_PATH.to_string(),
+            modprobe_path: values::DEFAULT_MODPROBE_PATH.to_string(),
+            executor: RealCommandExecutor {},
+        }) as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map.insert(
+        values::SYSTEMD_UNIT.to_string(),
+        Box::new(SystemdUnit {
+            unit_dir: values::DEFAULT_SYSTEMD_UNIT_DIR.to_string(),
+            executor: RealCommandExecutor {},
+        }) as Box<dyn Configuration + Send + Sync>,
+    );
+    config_map
 }
 
 pub trait Configuration {
     fn set_config(&self, config: &mut Sysconfig) -> Result<()>;
+    /// Compute the value of every key in config before and after set_config, without writing anything
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>>;
+    /// The files configured when the request sets no config_path
+    fn default_paths(&self) -> Vec<String>;
+    /// Read the current values of keys in config_path, or the default path if it is empty. All keys are read if keys
+    /// is empty, the keys which are not set are left out
+    fn get_config(&self, config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>>;
+    /// The files set_config writes for config
+    fn config_paths(&self, _config: &Sysconfig) -> Vec<String> {
+        self.default_paths()
+    }
+    /// Whether set_config changes the running kernel, whose values are saved by get_config before configuring
+    fn changes_runtime(&self) -> bool {
+        false
+    }
+    /// Whether the value read by get_config is what set_config configured with expected
+    fn value_matches(&self, expected: &serde_json::Value, actual: &serde_json::Value) -> bool {
+        display_value(expected) == display_value(actual)
+    }
+}
+
+/// The value of a key as it is written in the configuration, with the whitespaces between the fields collapsed
+pub fn display_value(value: &serde_json::Value) -> String {
+    match convert_json_value_to_string(value) {
+        (value, true) => value.split_whitespace().collect::<Vec<&str>>().join(" "),
+        _ => value.to_string(),
+    }
 }
 
 pub struct KernelSysctl {
@@ -87,6 +139,17 @@ pub struct PamLimits {
     pub config_path: String,
 }
 
+pub struct SystemdUnit<T: CommandExecutor> {
+    pub unit_dir: String,
+    pub executor: T,
+}
+
+pub struct KernelModules<T: CommandExecutor> {
+    pub load_path: String,
+    pub modprobe_path: String,
+    pub executor: T,
+}
+
 impl Configuration for KernelSysctl {
     fn set_config(&self, config: &mut Sysconfig) -> Result<()> {
         info!("Start setting kernel.sysctl");
@@ -114,6 +177,60 @@ impl Configuration for KernelSysctl {
         }
         Ok(())
     }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        Ok(diff_keys(
+            &config.contents,
+            |key| fs::read_to_string(self.get_proc_path(key)).ok().map(|v| v.trim().to_string()),
+            |key| {
+                let key_info = &config.contents[key];
+                let (value, is_recognized) = convert_json_value_to_string(&key_info.value);
+                if is_recognized && !value.is_empty() && key_info.operation.is_empty() {
+                    Some(value)
+                } else {
+                    fs::read_to_string(self.get_proc_path(key)).ok().map(|v| v.trim().to_string())
+                }
+            },
+        ))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        // the values live in procfs rather than a file
+        Vec::new()
+    }
+
+    fn get_config(&self, _config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        let mut values = HashMap::new();
+        if keys.is_empty() {
+            read_proc_dir(Path::new(&self.proc_path), Path::new(&self.proc_path), &mut values);
+        } else {
+            for key in keys {
+                if let Ok(value) = fs::read_to_string(self.get_proc_path(key)) {
+                    values.insert(key.clone(), value.trim().to_string());
+                }
+            }
+        }
+        Ok(string_contents(values, keys))
+    }
+
+    fn changes_runtime(&self) -> bool {
+        true
+    }
+}
+
+// read_proc_dir reads the parameters under dir recursively, the write-only and unreadable ones are skipped
+fn read_proc_dir(root: &Path, dir: &Path, values: &mut HashMap<String, String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            read_proc_dir(root, &path, values);
+        } else if let (Ok(key), Ok(value)) = (path.strip_prefix(root), fs::read_to_string(&path)) {
+            values.insert(key.to_string_lossy().replace('/', "."), value.trim().to_string());
+        }
+    }
 }
 
 impl KernelSysctl {
@@ -141,6 +258,33 @@ impl Configuration for KernelSysctlPersist {
         write_configs_to_file(config_path, &configs).with_context(|| "Failed to write configs to file".to_string())?;
         Ok(())
     }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        let config_path = config_path_or_default(config, values::DEFAULT_KERNEL_CONFIG_PATH);
+        let before = read_config_lines(config_path)?;
+        // set_config creates the missing file, so all keys are added to an empty file
+        let after = if is_file_exist(config_path) {
+            get_and_set_configs(&mut config.contents.clone(), config_path)?
+        } else {
+            handle_add_key(&config.contents, false)
+        };
+        let (before, after) = (parse_config_lines(&before, '='), parse_config_lines(&after, '='));
+        Ok(diff_keys(&config.contents, |key| before.get(key).cloned(), |key| after.get(key).cloned()))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        vec![values::DEFAULT_KERNEL_CONFIG_PATH.to_string()]
+    }
+
+    fn get_config(&self, config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        let config_path = if config_path.is_empty() { values::DEFAULT_KERNEL_CONFIG_PATH } else { config_path };
+        let lines = read_config_lines(config_path)?;
+        Ok(string_contents(parse_config_lines(&lines, '='), keys))
+    }
+
+    fn config_paths(&self, config: &Sysconfig) -> Vec<String> {
+        vec![config_path_or_default(config, values::DEFAULT_KERNEL_CONFIG_PATH).to_string()]
+    }
 }
 
 fn create_config_file(config_path: &str) -> Result<()> {
@@ -183,22 +327,132 @@ fn get_and_set_configs(expect_configs: &mut HashMap<String, KeyInfo>, config_pat
     Ok(configs_write)
 }
 
+fn config_path_or_default<'a>(config: &'a Sysconfig, default_path: &'a str) -> &'a str {
+    if config.config_path.is_empty() {
+        default_path
+    } else {
+        &config.config_path
+    }
+}
+
+// read_config_lines returns no lines if the file doesn't exist
+fn read_config_lines(config_path: &str) -> Result<Vec<String>> {
+    if !is_file_exist(config_path) {
+        return Ok(Vec::new());
+    }
+    let content =
+        fs::read_to_string(config_path).with_context(|| format!("Failed to read config file \"{}\"", config_path))?;
+    Ok(content.lines().map(|line| line.to_string()).collect())
+}
+
+// parse_config_lines maps the keys to the values in lines like "key=value", or "domain type item value" of pam
+// limits when separator is ' ', a key without value maps to an empty string
+fn parse_config_lines(lines: &[String], separator: char) -> HashMap<String, String> {
+    let mut configs = HashMap::new();
+    for line in lines {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') || line.is_empty() {
+            continue;
+        }
+        let (key, value) = match separator {
+            ' ' => {
+                let mut fields = line.split_whitespace();
+                let key = fields.next().unwrap_or_default();
+                (key, fields.collect::<Vec<&str>>().join("."))
+            },
+            _ => match line.split_once(separator) {
+                Some((key, value)) => (key.trim(), value.trim().to_string()),
+                None => (line, String::new()),
+            },
+        };
+        // only the first line of a key is modified by configuring
+        configs.entry(key.to_string()).or_insert(value);
+    }
+    configs
+}
+
+// string_contents converts the values of keys, or all values if keys is empty, into the contents of a Sysconfig
+fn string_contents(values: HashMap<String, String>, keys: &[String]) -> HashMap<String, KeyInfo> {
+    values
+        .into_iter()
+        .filter(|(key, _)| keys.is_empty() || keys.contains(key))
+        .map(|(key, value)| (key, KeyInfo { value: serde_json::Value::String(value), operation: String::new() }))
+        .collect()
+}
+
+// structured_contents picks the values of keys like a."b.c".d from the kubelet or containerd configuration, or
+// flattens all leaf values into such keys if keys is empty
+fn structured_contents(document: &serde_json::Value, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+    let mut contents = HashMap::new();
+    if keys.is_empty() {
+        if document.is_object() {
+            flatten_value("", document, &mut contents);
+        }
+        return Ok(contents);
+    }
+    for key in keys.iter().filter(|key| !key.is_empty()) {
+        let mut value = Some(document);
+        for k in split_config_key(key)? {
+            value = value.and_then(|v| v.get(&k));
+        }
+        if let Some(value) = value {
+            contents.insert(key.clone(), KeyInfo { value: value.clone(), operation: String::new() });
+        }
+    }
+    Ok(contents)
+}
+
+fn flatten_value(prefix: &str, value: &serde_json::Value, contents: &mut HashMap<String, KeyInfo>) {
+    match value.as_object() {
+        Some(map) if !map.is_empty() => {
+            for (k, v) in map {
+                let k = if k.contains('.') { format!("\"{}\"", k) } else { k.clone() };
+                let key = if prefix.is_empty() { k } else { format!("{}.{}", prefix, k) };
+                flatten_value(&key, v, contents);
+            }
+        },
+        _ => {
+            contents.insert(prefix.to_string(), KeyInfo { value: value.clone(), operation: String::new() });
+        },
+    }
+}
+
+// diff_keys builds the sorted diffs of the keys in contents with the values got by before and after
+fn diff_keys<F, G>(contents: &HashMap<String, KeyInfo>, before: F, after: G) -> Vec<ConfigDiff>
+where
+    F: Fn(&str) -> Option<String>,
+    G: Fn(&str) -> Option<String>,
+{
+    let mut diffs: Vec<ConfigDiff> =
+        contents.keys().map(|key| ConfigDiff { key: key.clone(), before: before(key), after: after(key) }).collect();
+    diffs.sort_by(|a, b| a.key.cmp(&b.key));
+    diffs
+}
+
 fn write_configs_to_file(config_path: &str, configs: &Vec<String>) -> Result<()> {
     info!("Write configuration to file \"{}\"", config_path);
-    let f = File::create(config_path)?;
-    let mut w = BufWriter::new(f);
+    let mut content = String::new();
     for line in configs {
         if line.is_empty() {
             continue;
         }
-        writeln!(w, "{}", line.as_str())?;
+        content.push_str(line);
+        content.push('\n');
     }
-    w.flush().with_context(|| format!("Failed to flush file {}", config_path))?;
-    w.get_mut().sync_all().with_context(|| "Failed to sync".to_string())?;
+    write_config_atomically(config_path, content.as_bytes())?;
     debug!("Write configuration to file \"{}\" success", config_path);
     Ok(())
 }
 
+// write_config_atomically replaces the config file by renaming a synced temporary file, so that it is never left
+// partially written. The file keeps its permission, a new file is created with DEFAULT_KERNEL_CONFIG_PERM
+pub(crate) fn write_config_atomically(config_path: &str, content: &[u8]) -> Result<()> {
+    let mode = fs::metadata(config_path)
+        .map(|metadata| metadata.permissions().mode() & 0o7777)
+        .unwrap_or(values::DEFAULT_KERNEL_CONFIG_PERM);
+    write_file_atomically(config_path, content, mode).with_context(|| format!("Failed to write file {}", config_path))
+}
+
 fn handle_delete_key(config_kv: &[&str], new_config_info: &KeyInfo) -> String {
     let key = config_kv[0];
     let (new_config_info_value, is_recognized) = convert_json_value_to_string(&new_config_info.value);
@@ -340,11 +594,7 @@ impl Configuration for GrubCmdline {
         if !is_file_exist(&self.grub_path) {
             bail!("Failed to find grub.cfg file");
         }
-        let config_partition = if cfg!(test) {
-            self.is_cur_partition
-        } else {
-            self.get_config_partition(c).with_context(|| "Failed to get config partition".to_string())?
-        };
+        let config_partition = self.config_partition(c)?;
         debug!("Config_partition: {} (false means partition A, true means partition B)", config_partition);
         let configs = get_and_set_grubcfg(&mut config.contents, &self.grub_path, config_partition)
             .with_context(|| "Failed to set grub configs".to_string())?;
@@ -352,9 +602,50 @@ impl Configuration for GrubCmdline {
             .with_context(|| "Failed to write configs to file".to_string())?;
         Ok(())
     }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        let c = RealCommandExecutor {};
+        if is_dmv_mode(&c) {
+            warn!("dm-verity mode is enabled, grub.cmdline configuration will be skipped");
+            return Ok(Vec::new());
+        }
+        if !is_file_exist(&self.grub_path) {
+            bail!("Failed to find grub.cfg file");
+        }
+        let config_partition = self.config_partition(c)?;
+        let before = read_config_lines(&self.grub_path)?;
+        let after = get_and_set_grubcfg(&mut config.contents.clone(), &self.grub_path, config_partition)
+            .with_context(|| "Failed to set grub configs".to_string())?;
+        let before = parse_grub_cmdline(&before, config_partition)?;
+        let after = parse_grub_cmdline(&after, config_partition)?;
+        Ok(diff_keys(&config.contents, |key| before.get(key).cloned(), |key| after.get(key).cloned()))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        vec![self.grub_path.clone()]
+    }
+
+    fn get_config(&self, _config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        if !is_file_exist(&self.grub_path) {
+            bail!("Failed to find grub.cfg file");
+        }
+        let config_partition = self.config_partition(RealCommandExecutor {})?;
+        let lines = read_config_lines(&self.grub_path)?;
+        let mut params = parse_grub_cmdline(&lines, config_partition)?;
+        // the linux command and the kernel image are not kernel parameters
+        params.retain(|key, _| key != "linux" && !key.starts_with('/'));
+        Ok(string_contents(params, keys))
+    }
 }
 
 impl GrubCmdline {
+    fn config_partition<T: CommandExecutor>(&self, executor: T) -> Result<bool> {
+        if cfg!(test) {
+            return Ok(self.is_cur_partition);
+        }
+        self.get_config_partition(executor).with_context(|| "Failed to get config partition".to_string())
+    }
+
     // get_config_partition returns false if the menuentry to be configured is A, true for menuentry B
     fn get_config_partition<T: CommandExecutor>(&self, executor: T) -> Result<bool> {
         let (_, next_partition) = get_partition_info(&executor)?;
@@ -372,8 +663,7 @@ fn get_and_set_grubcfg(
     config_partition: bool,
 ) -> Result<Vec<String>> {
     let f = File::open(grub_path).with_context(|| format!("Failed to open grub.cfg \"{}\"", grub_path))?;
-    let re_find_cur_linux = r"^\s*linux.*root=.*";
-    let re = Regex::new(re_find_cur_linux)?;
+    let re = Regex::new(GRUB_LINUX_LINE)?;
     let mut configs_write = Vec::new();
     let mut match_config_partition = false;
     for line in io::BufReader::new(f).lines() {
@@ -389,6 +679,14 @@ fn get_and_set_grubcfg(
     Ok(configs_write)
 }
 
+// parse_grub_cmdline maps the kernel parameters of the partition configured to their values
+fn parse_grub_cmdline(lines: &[String], config_partition: bool) -> Result<HashMap<String, String>> {
+    let re = Regex::new(GRUB_LINUX_LINE)?;
+    let line = lines.iter().filter(|line| re.is_match(line)).nth(usize::from(config_partition));
+    let params: Vec<String> = line.map(|line| line.split(' ').map(|s| s.to_string()).collect()).unwrap_or_default();
+    Ok(parse_config_lines(&params, '='))
+}
+
 fn modify_boot_cfg(expect_configs: &mut HashMap<String, KeyInfo>, line: &String) -> Result<String> {
     trace!("Match partition that need to be configured, entering modify_boot_cfg, linux line: {}", line);
     let mut new_configs = vec!["       ".to_string()];
@@ -447,125 +745,176 @@ impl Configuration for KubernetesKubelet {
             .with_context(|| format!("Failed to open config file \"{}\"", config_path))?;
         let mut value: serde_yaml::Value = serde_yaml::from_reader(file)
             .with_context(|| format!("Failed to read from config file \"{}\"", config_path))?;
-        let pattern = Regex::new(r#"[^\."']+|"([^"]*)"|'([^']*)'"#)
-            .with_context(|| format!("Failed to create regex used by split key"))?;
         if value.is_null() {
             value = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
         }
-        for (key, key_info) in config.contents.iter() {
-            debug!("Start configuration of key={}", key);
-            if key.is_empty() {
-                warn!("Failed to add \"null\" key, key: \"{}\"", key);
-                continue;
+        update_kubelet_config(&config.contents, &mut value)?;
+        let content =
+            serde_yaml::to_string(&value).with_context(|| format!("Failed to write yaml file \"{}\"", config_path))?;
+        write_config_atomically(config_path, content.as_bytes())?;
+        return Ok(());
+    }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        let config_path = config_path_or_default(config, values::DEFAULT_KUBELET_CONFIG_PATH);
+        let before: Value = if is_file_exist(config_path) {
+            let file =
+                File::open(config_path).with_context(|| format!("Failed to open config file \"{}\"", config_path))?;
+            serde_yaml::from_reader(file)
+                .with_context(|| format!("Failed to read from config file \"{}\"", config_path))?
+        } else {
+            Value::Null
+        };
+        let mut after = if before.is_null() { Value::Mapping(serde_yaml::Mapping::new()) } else { before.clone() };
+        update_kubelet_config(&config.contents, &mut after)?;
+        let get = |value: &Value, key: &str| -> Option<String> {
+            let mut value = value;
+            for k in split_config_key(key).ok()? {
+                value = value.get(&k)?;
             }
-            let key_list: Vec<String> = pattern.find_iter(&key).map(|m| m.as_str().to_string()).collect();
-            let mut value_iter = &mut value;
-            for (i, k_tmp) in key_list.clone().iter().enumerate() {
-                let k = &k_tmp.replace("\"", "");
-                debug!("    Current part is {}, part of key {}", k, key);
-                if let Some(_) = value_iter.get(k) {
-                    // if key exsit, update or delete
-                    if i == key_list.len() - 1 {
-                        if key_info.operation == "delete" {
-                            let value_mapping = value_iter.as_mapping_mut().unwrap();
-                            let file_value = value_mapping.get(k).unwrap();
-                            info!("Delete configuration {}={}", key, serde_yaml::to_string(file_value).unwrap());
-                            value_mapping.remove(k);
-                            break;
-                        }
-                        let json_value = serde_json::to_string(&key_info.value).unwrap();
-                        let config_value: Value = serde_yaml::from_str(&json_value)?;
-                        let config_value_message = serde_yaml::to_string(&config_value).unwrap();
-                        if !key_info.operation.is_empty() {
-                            warn!(
-                                "Unknown operation \"{}\", updating key \"{}\" with value \"{}\" by default",
-                                key_info.operation,
-                                key,
-                                config_value_message.trim()
-                            );
-                        }
-                        value_iter = value_iter.get_mut(k).unwrap();
-
-                        // if value type is array need insert iteration
-                        if value_iter.is_sequence() {
-                            let value_array = match value_iter.as_sequence_mut() {
-                                Some(v) => v,
-                                None => {
-                                    warn!("Failed to convert yaml Value to sequence, skip this value");
-                                    break;
-                                },
-                            };
-                            let config_value_array = match config_value.as_sequence() {
-                                Some(v) => v,
-                                None => {
-                                    warn!("Failed to convert yaml Value to sequence, skip this value");
-                                    break;
-                                },
-                            };
-                            value_array.extend_from_slice(config_value_array);
-                            info!("Update configuration {}: {}", key, &config_value_message.trim());
-                            break;
-                        }
-                        *value_iter = config_value.clone().into();
-                        info!("Update configuration {}: {}", key, &config_value_message.trim());
-                        break;
-                    }
-                    // Has check on the condition of if, unwrap is safe
-                    value_iter = value_iter.get_mut(k).unwrap();
-                } else {
+            serde_json::to_string(value).ok()
+        };
+        Ok(diff_keys(&config.contents, |key| get(&before, key), |key| get(&after, key)))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        vec![values::DEFAULT_KUBELET_CONFIG_PATH.to_string()]
+    }
+
+    fn get_config(&self, config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        let config_path = if config_path.is_empty() { values::DEFAULT_KUBELET_CONFIG_PATH } else { config_path };
+        if !is_file_exist(config_path) {
+            return Ok(HashMap::new());
+        }
+        let file =
+            File::open(config_path).with_context(|| format!("Failed to open config file \"{}\"", config_path))?;
+        let value: Value = serde_yaml::from_reader(file)
+            .with_context(|| format!("Failed to read from config file \"{}\"", config_path))?;
+        let document = serde_json::to_value(value)
+            .with_context(|| format!("Failed to convert config file \"{}\" to json", config_path))?;
+        structured_contents(&document, keys)
+    }
+
+    fn config_paths(&self, config: &Sysconfig) -> Vec<String> {
+        vec![config_path_or_default(config, values::DEFAULT_KUBELET_CONFIG_PATH).to_string()]
+    }
+}
+
+// split_config_key splits keys like a."b.c".d of kubelet and containerd configurations into [a, b.c, d]
+fn split_config_key(key: &str) -> Result<Vec<String>> {
+    let pattern = Regex::new(r#"[^\."']+|"([^"]*)"|'([^']*)'"#).context("Failed to create regex used by split key")?;
+    Ok(pattern.find_iter(key).map(|m| m.as_str().replace('"', "")).collect())
+}
+
+fn update_kubelet_config(contents: &HashMap<String, KeyInfo>, value: &mut Value) -> Result<()> {
+    let pattern = Regex::new(r#"[^\."']+|"([^"]*)"|'([^']*)'"#)
+        .with_context(|| format!("Failed to create regex used by split key"))?;
+    for (key, key_info) in contents.iter() {
+        debug!("Start configuration of key={}", key);
+        if key.is_empty() {
+            warn!("Failed to add \"null\" key, key: \"{}\"", key);
+            continue;
+        }
+        let key_list: Vec<String> = pattern.find_iter(&key).map(|m| m.as_str().to_string()).collect();
+        let mut value_iter = &mut *value;
+        for (i, k_tmp) in key_list.clone().iter().enumerate() {
+            let k = &k_tmp.replace("\"", "");
+            debug!("    Current part is {}, part of key {}", k, key);
+            if let Some(_) = value_iter.get(k) {
+                // if key exsit, update or delete
+                if i == key_list.len() - 1 {
                     if key_info.operation == "delete" {
-                        warn!("Failed to delete inexistent key: \"{}\"", key);
-                        continue;
+                        let value_mapping = value_iter.as_mapping_mut().unwrap();
+                        let file_value = value_mapping.get(k).unwrap();
+                        info!("Delete configuration {}={}", key, serde_yaml::to_string(file_value).unwrap());
+                        value_mapping.remove(k);
+                        break;
                     }
-                    // create if not contains key
                     let json_value = serde_json::to_string(&key_info.value).unwrap();
-                    let mut config_value: Value = serde_yaml::from_str(&json_value)?;
+                    let config_value: Value = serde_yaml::from_str(&json_value)?;
                     let config_value_message = serde_yaml::to_string(&config_value).unwrap();
                     if !key_info.operation.is_empty() {
                         warn!(
-                            "Unknown operation \"{}\", adding key \"{}\" with value \"{}\" by default",
+                            "Unknown operation \"{}\", updating key \"{}\" with value \"{}\" by default",
                             key_info.operation,
                             key,
                             config_value_message.trim()
                         );
                     }
+                    value_iter = value_iter.get_mut(k).unwrap();
 
-                    let mut key_index = key_list.len() - 1;
-                    while key_index > i {
-                        let mut value_map = serde_yaml::Mapping::new();
-                        value_map.insert(Value::String(key_list[key_index].replace("\"", "")).clone(), config_value);
-                        config_value = serde_yaml::Value::Mapping(value_map);
-                        key_index = key_index - 1;
-                    }
-                    if value_iter.is_null() {
-                        *value_iter = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+                    // if value type is array need insert iteration
+                    if value_iter.is_sequence() {
+                        let value_array = match value_iter.as_sequence_mut() {
+                            Some(v) => v,
+                            None => {
+                                warn!("Failed to convert yaml Value to sequence, skip this value");
+                                break;
+                            },
+                        };
+                        let config_value_array = match config_value.as_sequence() {
+                            Some(v) => v,
+                            None => {
+                                warn!("Failed to convert yaml Value to sequence, skip this value");
+                                break;
+                            },
+                        };
+                        value_array.extend_from_slice(config_value_array);
+                        info!("Update configuration {}: {}", key, &config_value_message.trim());
+                        break;
                     }
-                    let value_mapping = match value_iter.as_mapping_mut() {
-                        Some(m) => m,
-                        None => {
-                            warn!(
-                                "Failed to convert yaml value to mapping, maybe read the file in the wrong format,
-                                 or write wrong value when handle the configuration of key {}",
-                                key
-                            );
-                            break;
-                        },
-                    };
-                    value_mapping.insert(Value::String(k.to_string()).into(), config_value);
-                    info!("Add configuration \"{}: {}\"", key, config_value_message.trim());
+                    *value_iter = config_value.clone().into();
+                    info!("Update configuration {}: {}", key, &config_value_message.trim());
                     break;
                 }
+                // Has check on the condition of if, unwrap is safe
+                value_iter = value_iter.get_mut(k).unwrap();
+            } else {
+                if key_info.operation == "delete" {
+                    warn!("Failed to delete inexistent key: \"{}\"", key);
+                    continue;
+                }
+                // create if not contains key
+                let json_value = serde_json::to_string(&key_info.value).unwrap();
+                let mut config_value: Value = serde_yaml::from_str(&json_value)?;
+                let config_value_message = serde_yaml::to_string(&config_value).unwrap();
+                if !key_info.operation.is_empty() {
+                    warn!(
+                        "Unknown operation \"{}\", adding key \"{}\" with value \"{}\" by default",
+                        key_info.operation,
+                        key,
+                        config_value_message.trim()
+                    );
+                }
+
+                let mut key_index = key_list.len() - 1;
+                while key_index > i {
+                    let mut value_map = serde_yaml::Mapping::new();
+                    value_map.insert(Value::String(key_list[key_index].replace("\"", "")).clone(), config_value);
+                    config_value = serde_yaml::Value::Mapping(value_map);
+                    key_index = key_index - 1;
+                }
+                if value_iter.is_null() {
+                    *value_iter = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+                }
+                let value_mapping = match value_iter.as_mapping_mut() {
+                    Some(m) => m,
+                    None => {
+                        warn!(
+                            "Failed to convert yaml value to mapping, maybe read the file in the wrong format,
+                                 or write wrong value when handle the configuration of key {}",
+                            key
+                        );
+                        break;
+                    },
+                };
+                value_mapping.insert(Value::String(k.to_string()).into(), config_value);
+                info!("Add configuration \"{}: {}\"", key, config_value_message.trim());
+                break;
             }
         }
-        let file = std::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(config_path)
-            .with_context(|| format!("Failed to open kubelet config file \"{}\"", config_path))?;
-        serde_yaml::to_writer(file, &value)
-            .with_context(|| format!("Failed to write yaml file \"{}\"", config_path))?;
-        return Ok(());
     }
+    Ok(())
 }
 
 impl Configuration for ContainerContainerd {
@@ -585,84 +934,92 @@ impl Configuration for ContainerContainerd {
         if value.is_empty() {
             value = toml::map::Map::new();
         }
-        let pattern = Regex::new(r#"[^\."']+|"([^"]*)"|'([^']*)'"#)
-            .with_context(|| format!("Failed to create regex used by split key"))?;
 
-        for (key, key_info) in config.contents.iter() {
-            debug!("Start configuration of key={}", key);
-            if key.is_empty() {
-                warn!("Failed to add \"null\" key, key: \"{}\"", key);
-                continue;
+        update_containerd_config(&config.contents, &mut value)?;
+        let toml_string = toml::to_string(&value).with_context(|| format!("Failed to convert value to string"))?;
+        write_config_atomically(config_path, toml_string.as_bytes())?;
+        Ok(())
+    }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        let config_path = config_path_or_default(config, values::DEFAULT_CONTAINERD_CONFIG_PATH);
+        let content = if is_file_exist(config_path) {
+            fs::read_to_string(config_path)
+                .with_context(|| format!("Failed to open config file \"{}\"", config_path))?
+        } else {
+            String::new()
+        };
+        let before: Table =
+            toml::from_str(&content).with_context(|| format!("Failed to read from config file \"{}\"", config_path))?;
+        let mut after = before.clone();
+        update_containerd_config(&config.contents, &mut after)?;
+        let get = |table: &Table, key: &str| -> Option<String> {
+            let keys = split_config_key(key).ok()?;
+            let (last, parents) = keys.split_last()?;
+            let mut table = table;
+            for k in parents {
+                table = table.get(k)?.as_table()?;
             }
-            let key_list: Vec<String> = pattern.find_iter(&key).map(|m| m.as_str().to_string()).collect();
-            let mut value_iter = &mut value;
-            for (i, k_tmp) in key_list.clone().iter().enumerate() {
-                let k = &k_tmp.replace("\"", "");
-                debug!("    Current part is {}, part of key {}", k, key);
-                if let Some(_) = value_iter.get(k) {
-                    debug!("        Key {} is exist", k);
-                    if i == key_list.len() - 1 {
-                        if key_info.operation == "delete" {
-                            let file_value = value_iter.get(k).unwrap();
-                            info!("Delete configuration {}={}", key, serde_json::to_string(file_value).unwrap());
-                            value_iter.remove(k);
-                            break;
-                        }
-                        let config_value = match convert_json_to_toml(key_info.value.clone()) {
-                            Ok(toml_config) => toml_config,
-                            Err(_) => break,
-                        };
-                        let config_value_message = config_value.to_string();
-                        if !key_info.operation.is_empty() {
-                            warn!(
-                                "Unknown operation \"{}\", updating key \"{}\" with value \"{}\" by default",
-                                key_info.operation,
-                                key,
-                                config_value_message.trim()
-                            );
-                        }
-                        let value_last = value_iter.get_mut(k).unwrap();
-
-                        // if value type is array need insert
-                        if value_last.is_array() {
-                            let value_array = match value_last.as_array_mut() {
-                                Some(v) => v,
-                                None => {
-                                    warn!("Failed to convert toml Value to sequence, skip this value");
-                                    break;
-                                },
-                            };
-                            let config_value_array = match config_value.as_array() {
-                                Some(v) => v,
-                                None => {
-                                    warn!("Failed to convert toml Value to sequence, skip this value");
-                                    break;
-                                },
-                            };
-                            value_array.extend_from_slice(config_value_array);
-                            info!("Update configuration {}: {}", key, config_value_message.trim());
-                            break;
-                        }
-                        *value_last = config_value.into();
-                        info!("Update configuration {}: {}", key, config_value_message.trim());
-                        break;
-                    }
-                    // Has check value.get() is Some() on the condition of if, value.get(k).unwrap() is safe
-                    value_iter = match value_iter.get_mut(k).unwrap().as_table_mut() {
-                        Some(value_table) => value_table,
-                        None => {
-                            warn!("Failed to convert value to table, skip this value");
-                            break;
-                        },
-                    };
-                } else {
-                    debug!("        Key {} is not exist", k);
+            serde_json::to_string(table.get(last)?).ok()
+        };
+        Ok(diff_keys(&config.contents, |key| get(&before, key), |key| get(&after, key)))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        vec![values::DEFAULT_CONTAINERD_CONFIG_PATH.to_string()]
+    }
+
+    fn get_config(&self, config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        let config_path = if config_path.is_empty() { values::DEFAULT_CONTAINERD_CONFIG_PATH } else { config_path };
+        if !is_file_exist(config_path) {
+            return Ok(HashMap::new());
+        }
+        let content = fs::read_to_string(config_path)
+            .with_context(|| format!("Failed to open config file \"{}\"", config_path))?;
+        let value: Table =
+            toml::from_str(&content).with_context(|| format!("Failed to read from config file \"{}\"", config_path))?;
+        let document = serde_json::to_value(value)
+            .with_context(|| format!("Failed to convert config file \"{}\" to json", config_path))?;
+        structured_contents(&document, keys)
+    }
+
+    fn config_paths(&self, config: &Sysconfig) -> Vec<String> {
+        vec![config_path_or_default(config, values::DEFAULT_CONTAINERD_CONFIG_PATH).to_string()]
+    }
+
+    fn value_matches(&self, expected: &serde_json::Value, actual: &serde_json::Value) -> bool {
+        match (expected.as_array(), actual.as_array()) {
+            // configuring an existing array appends to it
+            (Some(expected), Some(actual)) => expected.iter().all(|v| actual.contains(v)),
+            _ => display_value(expected) == display_value(actual),
+        }
+    }
+}
+
+fn update_containerd_config(contents: &HashMap<String, KeyInfo>, value: &mut Table) -> Result<()> {
+    let pattern = Regex::new(r#"[^\."']+|"([^"]*)"|'([^']*)'"#)
+        .with_context(|| format!("Failed to create regex used by split key"))?;
+    for (key, key_info) in contents.iter() {
+        debug!("Start configuration of key={}", key);
+        if key.is_empty() {
+            warn!("Failed to add \"null\" key, key: \"{}\"", key);
+            continue;
+        }
+        let key_list: Vec<String> = pattern.find_iter(&key).map(|m| m.as_str().to_string()).collect();
+        let mut value_iter = &mut *value;
+        for (i, k_tmp) in key_list.clone().iter().enumerate() {
+            let k = &k_tmp.replace("\"", "");
+            debug!("    Current part is {}, part of key {}", k, key);
+            if let Some(_) = value_iter.get(k) {
+                debug!("        Key {} is exist", k);
+                if i == key_list.len() - 1 {
                     if key_info.operation == "delete" {
-                        warn!("Failed to delete inexistent key: \"{}\"", key);
+                        let file_value = value_iter.get(k).unwrap();
+                        info!("Delete configuration {}={}", key, serde_json::to_string(file_value).unwrap());
+                        value_iter.remove(k);
                         break;
                     }
-                    // create if not contains key
-                    let mut config_value = match convert_json_to_toml(key_info.value.clone()) {
+                    let config_value = match convert_json_to_toml(key_info.value.clone()) {
                         Ok(toml_config) => toml_config,
                         Err(_) => break,
                     };
@@ -675,26 +1032,77 @@ impl Configuration for ContainerContainerd {
                             config_value_message.trim()
                         );
                     }
-
-                    let mut key_index = key_list.len() - 1;
-                    while key_index > i {
-                        let key_trim = key_list[key_index].replace("\"", "");
-                        debug!("Start add key {}", key_trim);
-                        let mut value_tmp = toml::Table::from(Map::new());
-                        value_tmp.insert(key_trim, config_value.into());
-                        config_value = toml::Value::Table(value_tmp);
-                        key_index = key_index - 1;
+                    let value_last = value_iter.get_mut(k).unwrap();
+
+                    // if value type is array need insert
+                    if value_last.is_array() {
+                        let value_array = match value_last.as_array_mut() {
+                            Some(v) => v,
+                            None => {
+                                warn!("Failed to convert toml Value to sequence, skip this value");
+                                break;
+                            },
+                        };
+                        let config_value_array = match config_value.as_array() {
+                            Some(v) => v,
+                            None => {
+                                warn!("Failed to convert toml Value to sequence, skip this value");
+                                break;
+                            },
+                        };
+                        value_array.extend_from_slice(config_value_array);
+                        info!("Update configuration {}: {}", key, config_value_message.trim());
+                        break;
                     }
-                    value_iter.insert(k.to_string(), config_value);
-                    info!("Add configuration \"{}: {}\"", key, config_value_message.trim());
+                    *value_last = config_value.into();
+                    info!("Update configuration {}: {}", key, config_value_message.trim());
                     break;
                 }
+                // Has check value.get() is Some() on the condition of if, value.get(k).unwrap() is safe
+                value_iter = match value_iter.get_mut(k).unwrap().as_table_mut() {
+                    Some(value_table) => value_table,
+                    None => {
+                        warn!("Failed to convert value to table, skip this value");
+                        break;
+                    },
+                };
+            } else {
+                debug!("        Key {} is not exist", k);
+                if key_info.operation == "delete" {
+                    warn!("Failed to delete inexistent key: \"{}\"", key);
+                    break;
+                }
+                // create if not contains key
+                let mut config_value = match convert_json_to_toml(key_info.value.clone()) {
+                    Ok(toml_config) => toml_config,
+                    Err(_) => break,
+                };
+                let config_value_message = config_value.to_string();
+                if !key_info.operation.is_empty() {
+                    warn!(
+                        "Unknown operation \"{}\", updating key \"{}\" with value \"{}\" by default",
+                        key_info.operation,
+                        key,
+                        config_value_message.trim()
+                    );
+                }
+
+                let mut key_index = key_list.len() - 1;
+                while key_index > i {
+                    let key_trim = key_list[key_index].replace("\"", "");
+                    debug!("Start add key {}", key_trim);
+                    let mut value_tmp = toml::Table::from(Map::new());
+                    value_tmp.insert(key_trim, config_value.into());
+                    config_value = toml::Value::Table(value_tmp);
+                    key_index = key_index - 1;
+                }
+                value_iter.insert(k.to_string(), config_value);
+                info!("Add configuration \"{}: {}\"", key, config_value_message.trim());
+                break;
             }
         }
-        let toml_string = toml::to_string(&value).with_context(|| format!("Failed to convert value to string"))?;
-        std::fs::write(config_path, toml_string).with_context(|| format!("Failed to write file {}", config_path))?;
-        Ok(())
     }
+    Ok(())
 }
 
 fn convert_json_to_toml(config: serde_json::Value) -> Result<toml::Value> {
@@ -744,6 +1152,36 @@ impl Configuration for PamLimits {
             .with_context(|| "Failed to write configs to file".to_string())?;
         Ok(())
     }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        if !is_file_exist(&self.config_path) {
+            bail!("Failed to find file {}", values::DEFAULT_PAM_LIMITS_PATH);
+        }
+        let before = read_config_lines(&self.config_path)?;
+        let after = get_and_set_pam_limits(&self.config_path, &mut config.contents.clone())
+            .with_context(|| "Failed to set pam limits configs".to_string())?;
+        let (before, after) = (parse_config_lines(&before, ' '), parse_config_lines(&after, ' '));
+        Ok(diff_keys(&config.contents, |key| before.get(key).cloned(), |key| after.get(key).cloned()))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        vec![self.config_path.clone()]
+    }
+
+    fn get_config(&self, _config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        if !is_file_exist(&self.config_path) {
+            bail!("Failed to find file {}", values::DEFAULT_PAM_LIMITS_PATH);
+        }
+        let lines = read_config_lines(&self.config_path)?;
+        Ok(string_contents(parse_config_lines(&lines, ' '), keys))
+    }
+
+    fn value_matches(&self, expected: &serde_json::Value, actual: &serde_json::Value) -> bool {
+        let (expected, actual) = (display_value(expected), display_value(actual));
+        let (expected, actual): (Vec<&str>, Vec<&str>) = (expected.split('.').collect(), actual.split('.').collect());
+        // "_" keeps the field unchanged
+        expected.len() == actual.len() && expected.iter().zip(actual.iter()).all(|(e, a)| *e == "_" || e == a)
+    }
 }
 
 fn get_and_set_pam_limits(config_path: &str, configs: &mut HashMap<String, KeyInfo>) -> Result<Vec<String>> {
@@ -823,14 +1261,545 @@ fn handle_add_key_pam_limits(new_configs: &HashMap<String, KeyInfo>) -> Vec<Stri
     configs_write
 }
 
+impl<T: CommandExecutor> Configuration for SystemdUnit<T> {
+    fn set_config(&self, config: &mut Sysconfig) -> Result<()> {
+        info!("Start setting systemd.unit");
+        let mut units: Vec<(&String, &KeyInfo)> = config.contents.iter().collect();
+        units.sort_by(|a, b| a.0.cmp(b.0));
+        let mut reload = false;
+        let mut changes = Vec::new();
+        for (unit, key_info) in units {
+            if !is_valid_unit_name(unit) {
+                warn!("Failed to parse systemd unit name \"{}\", skip this configuration", unit);
+                continue;
+            }
+            if key_info.operation == "delete" {
+                reload |= self.remove_dropin(unit)?;
+                continue;
+            }
+            if !key_info.operation.is_empty() {
+                warn!("Unknown operation \"{}\", updating systemd unit \"{}\" by default", key_info.operation, unit);
+            }
+            let spec = match parse_unit_spec(&key_info.value) {
+                Ok(spec) => spec,
+                Err(e) => {
+                    warn!("Failed to parse the value of systemd unit \"{}\": {:#}, skip this configuration", unit, e);
+                    continue;
+                },
+            };
+            if let Some(dropin) = &spec.dropin {
+                reload |= self.write_dropin(unit, dropin)?;
+            }
+            changes.push((unit, spec));
+        }
+        // the drop-ins only take effect after reloading, before the units are enabled or restarted
+        if reload {
+            self.executor.run_command("systemctl", &["daemon-reload"])?;
+            info!("Reloaded systemd manager configuration");
+        }
+        for (unit, spec) in changes {
+            if let Some(state) = &spec.state {
+                self.set_unit_state(unit, state)?;
+            }
+            if spec.restart {
+                self.executor.run_command("systemctl", &["restart", unit])?;
+                info!("Restarted systemd unit {}", unit);
+            }
+        }
+        Ok(())
+    }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        let mut before = HashMap::new();
+        for unit in config.contents.keys().filter(|unit| is_valid_unit_name(unit)) {
+            before.insert(unit.clone(), self.read_unit(unit)?);
+        }
+        let after = |unit: &str| -> Option<String> {
+            let mut value = before.get(unit)?.clone();
+            let key_info = &config.contents[unit];
+            if key_info.operation == "delete" {
+                value["dropin"] = serde_json::json!({});
+            } else if let Ok(spec) = parse_unit_spec(&key_info.value) {
+                if let Some(state) = spec.state {
+                    value["state"] = serde_json::Value::String(state);
+                }
+                if let Some(dropin) = spec.dropin {
+                    value["dropin"] = render_dropin(&dropin).map(|content| parse_dropin(&content)).ok()?;
+                }
+            }
+            Some(value.to_string())
+        };
+        Ok(diff_keys(&config.contents, |unit| before.get(unit).map(|v| v.to_string()), after))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        // the drop-in files depend on the units configured, the units are restored by set_config
+        Vec::new()
+    }
+
+    fn get_config(&self, _config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        let units = if keys.is_empty() { self.dropin_units() } else { keys.to_vec() };
+        let mut contents = HashMap::new();
+        for unit in units.into_iter().filter(|unit| is_valid_unit_name(unit)) {
+            let value = self.read_unit(&unit)?;
+            contents.insert(unit, KeyInfo { value, operation: String::new() });
+        }
+        Ok(contents)
+    }
+
+    fn changes_runtime(&self) -> bool {
+        true
+    }
+
+    fn value_matches(&self, expected: &serde_json::Value, actual: &serde_json::Value) -> bool {
+        let Ok(spec) = parse_unit_spec(expected) else {
+            return false;
+        };
+        // only the state and drop-in configured are compared, restart is a one-off action
+        let state_matches =
+            spec.state.as_ref().is_none_or(|state| actual.get("state") == Some(&serde_json::json!(state)));
+        let dropin_matches = spec.dropin.as_ref().is_none_or(|dropin| {
+            render_dropin(dropin).is_ok_and(|content| actual.get("dropin") == Some(&parse_dropin(&content)))
+        });
+        state_matches && dropin_matches
+    }
+}
+
+// UnitSpec is the value of a systemd.unit key, like {"state": "enabled", "restart": true, "dropin": {"Service":
+// {"LimitNOFILE": 1048576}}}. The state and drop-in which are not set are left unchanged
+struct UnitSpec {
+    state: Option<String>,
+    restart: bool,
+    dropin: Option<serde_json::Value>,
+}
+
+fn parse_unit_spec(value: &serde_json::Value) -> Result<UnitSpec> {
+    let Some(spec) = value.as_object() else {
+        bail!("expect an object with state, restart or dropin");
+    };
+    if let Some(key) = spec.keys().find(|k| !["state", "restart", "dropin"].contains(&k.as_str())) {
+        bail!("unknown field \"{}\"", key);
+    }
+    let state = match spec.get("state") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(state)) if SYSTEMD_UNIT_STATES.contains(&state.as_str()) => Some(state.clone()),
+        Some(state) => bail!("state {} is not one of {}", state, SYSTEMD_UNIT_STATES.join(", ")),
+    };
+    let restart = match spec.get("restart") {
+        None | Some(serde_json::Value::Null) => false,
+        Some(restart) => restart.as_bool().ok_or_else(|| anyhow!("restart {} is not a boolean", restart))?,
+    };
+    let dropin = spec.get("dropin").filter(|dropin| !dropin.is_null()).cloned();
+    if let Some(dropin) = &dropin {
+        render_dropin(dropin)?;
+    }
+    Ok(UnitSpec { state, restart, dropin })
+}
+
+// unit names like containerd.service or getty@tty1.service, which are looked up in the unit directory
+fn is_valid_unit_name(unit: &str) -> bool {
+    !unit.starts_with('.')
+        && unit.contains('.')
+        && unit.chars().all(|c| c.is_ascii_alphanumeric() || ":-_.\\@".contains(c))
+}
+
+// render_dropin converts {"Section": {"Property": value}} into the content of a drop-in file, a property with an
+// array value is written once for every element, e.g. ["", "/usr/bin/foo"] resets ExecStart before setting it. The
+// sections and properties are sorted by name, as the order of a JSON object depends on the serde_json features
+fn render_dropin(dropin: &serde_json::Value) -> Result<String> {
+    let Some(sections) = dropin.as_object() else {
+        bail!("dropin {} is not an object of sections", dropin);
+    };
+    let mut sections: Vec<(&String, &serde_json::Value)> = sections.iter().collect();
+    sections.sort_by(|a, b| a.0.cmp(b.0));
+    let mut content = String::new();
+    for (section, properties) in sections {
+        if section.is_empty() || section.contains(|c: char| c == '[' || c == ']' || c.is_control()) {
+            bail!("illegal drop-in section \"{}\"", section);
+        }
+        let Some(properties) = properties.as_object() else {
+            bail!("section \"{}\" of dropin is not an object of properties", section);
+        };
+        let mut properties: Vec<(&String, &serde_json::Value)> = properties.iter().collect();
+        properties.sort_by(|a, b| a.0.cmp(b.0));
+        content.push_str(&format!("[{}]\n", section));
+        for (property, value) in properties {
+            if property.is_empty() || property.contains(|c: char| c == '=' || c.is_whitespace() || c.is_control()) {
+                bail!("illegal drop-in property \"{}\"", property);
+            }
+            let values = match value {
+                serde_json::Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                let (value, is_recognized) = convert_json_value_to_string(value);
+                if !is_recognized || value.contains('\n') {
+                    bail!("illegal value of drop-in property \"{}\"", property);
+                }
+                content.push_str(&format!("{}={}\n", property, value));
+            }
+        }
+    }
+    Ok(content)
+}
+
+// parse_dropin reads a drop-in file into {"Section": {"Property": value}}, the values of a property set more than once
+// are collected into an array
+fn parse_dropin(content: &str) -> serde_json::Value {
+    let mut sections = serde_json::Map::new();
+    let mut section = String::new();
+    for line in content.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            section = name.to_string();
+            sections.entry(section.clone()).or_insert_with(|| serde_json::json!({}));
+            continue;
+        }
+        let (Some((property, value)), Some(properties)) =
+            (line.split_once('='), sections.get_mut(&section).and_then(|s| s.as_object_mut()))
+        else {
+            continue;
+        };
+        let value = serde_json::Value::String(value.trim().to_string());
+        match properties.get_mut(property.trim()) {
+            Some(serde_json::Value::Array(values)) => values.push(value),
+            Some(old) => *old = serde_json::Value::Array(vec![old.clone(), value]),
+            None => {
+                properties.insert(property.trim().to_string(), value);
+            },
+        }
+    }
+    serde_json::Value::Object(sections)
+}
+
+impl<T: CommandExecutor> SystemdUnit<T> {
+    fn dropin_path(&self, unit: &str) -> PathBuf {
+        Path::new(&self.unit_dir).join(format!("{}.d", unit)).join(values::SYSTEMD_DROPIN_FILE)
+    }
+
+    // dropin_units lists the units which have a drop-in written by systemd.unit
+    fn dropin_units(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(&self.unit_dir) else {
+            return Vec::new();
+        };
+        let mut units: Vec<String> = entries
+            .flatten()
+            .filter(|entry| entry.path().join(values::SYSTEMD_DROPIN_FILE).is_file())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.strip_suffix(".d")).map(String::from))
+            .collect();
+        units.sort();
+        units
+    }
+
+    // read_unit returns the enablement state of unit and its drop-in. The state is left out unless it is one that
+    // set_config can configure, like the static units or the units not found
+    fn read_unit(&self, unit: &str) -> Result<serde_json::Value> {
+        let state = self
+            .executor
+            .run_command_with_output("systemctl", &["show", "--property=UnitFileState", "--value", unit])
+            .with_context(|| format!("Failed to get the state of systemd unit {}", unit))?;
+        let dropin = match fs::read_to_string(self.dropin_path(unit)) {
+            Ok(content) => parse_dropin(&content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => serde_json::json!({}),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read the drop-in of systemd unit {}", unit)),
+        };
+        let mut value = serde_json::json!({ "dropin": dropin });
+        if SYSTEMD_UNIT_STATES.contains(&state.trim()) {
+            value["state"] = serde_json::Value::String(state.trim().to_string());
+        }
+        Ok(value)
+    }
+
+    fn set_unit_state(&self, unit: &str, state: &str) -> Result<()> {
+        match state {
+            "masked" => self.executor.run_command("systemctl", &["mask", unit])?,
+            _ => {
+                // enabling or disabling a masked unit doesn't unmask it
+                self.executor.run_command("systemctl", &["unmask", unit])?;
+                let action = if state == "enabled" { "enable" } else { "disable" };
+                self.executor.run_command("systemctl", &[action, unit])?;
+            },
+        }
+        info!("Configured systemd unit {} {}", unit, state);
+        Ok(())
+    }
+
+    // write_dropin writes the drop-in of unit, or removes it if dropin has no section. It returns whether the drop-in
+    // is changed
+    fn write_dropin(&self, unit: &str, dropin: &serde_json::Value) -> Result<bool> {
+        let sections = render_dropin(dropin)?;
+        if sections.is_empty() {
+            return self.remove_dropin(unit);
+        }
+        let content = format!("{}{}", SYSTEMD_DROPIN_HEADER, sections);
+        let path = self.dropin_path(unit);
+        if fs::read_to_string(&path).is_ok_and(|old| old == content) {
+            debug!("The drop-in of systemd unit {} is unchanged", unit);
+            return Ok(false);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        write_config_atomically(&path.to_string_lossy(), content.as_bytes())?;
+        info!("Write the drop-in of systemd unit {} to \"{}\"", unit, path.display());
+        Ok(true)
+    }
+
+    fn remove_dropin(&self, unit: &str) -> Result<bool> {
+        let path = self.dropin_path(unit);
+        if !path.exists() {
+            warn!("Failed to delete inexistent drop-in of systemd unit \"{}\"", unit);
+            return Ok(false);
+        }
+        fs::remove_file(&path).with_context(|| format!("Failed to remove file {}", path.display()))?;
+        // the drop-in directory is only removed if no other drop-in is left
+        if let Some(dir) = path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+        info!("Removed the drop-in of systemd unit {}", unit);
+        Ok(true)
+    }
+}
+
+impl<T: CommandExecutor> Configuration for KernelModules<T> {
+    fn set_config(&self, config: &mut Sysconfig) -> Result<()> {
+        info!("Start setting kernel.modules");
+        let mut modules = self.read_modules()?;
+        let mut live = Vec::new();
+        let mut keys: Vec<&String> = config.contents.keys().collect();
+        keys.sort();
+        for module in keys {
+            let (state, is_live) = module_change(module, &config.contents[module], modules.remove(module));
+            if let Some(state) = state {
+                if is_live {
+                    live.push((module.clone(), state.clone()));
+                }
+                modules.insert(module.clone(), state);
+            }
+        }
+        self.write_modules(&modules)?;
+        // the modules are loaded or unloaded after the files are written, so that modprobe uses the new options
+        for (module, state) in live {
+            if state.load {
+                self.executor
+                    .run_command("modprobe", &[&module])
+                    .with_context(|| format!("Failed to load kernel module {}", module))?;
+                info!("Loaded kernel module {}", module);
+            } else if state.blacklist {
+                // a module in use can't be unloaded, it stays until the next boot
+                match self.executor.run_command("modprobe", &["-r", &module]) {
+                    Ok(_) => info!("Unloaded kernel module {}", module),
+                    Err(e) => warn!("Failed to unload kernel module {}: {:#}", module, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn plan_config(&self, config: &Sysconfig) -> Result<Vec<ConfigDiff>> {
+        let before = self.read_modules()?;
+        let after = |module: &str| {
+            let (state, _) = module_change(module, &config.contents[module], before.get(module).cloned());
+            state.map(|s| s.to_value().to_string())
+        };
+        Ok(diff_keys(&config.contents, |module| before.get(module).map(|s| s.to_value().to_string()), after))
+    }
+
+    fn default_paths(&self) -> Vec<String> {
+        vec![self.load_path.clone(), self.modprobe_path.clone()]
+    }
+
+    fn get_config(&self, _config_path: &str, keys: &[String]) -> Result<HashMap<String, KeyInfo>> {
+        Ok(self
+            .read_modules()?
+            .into_iter()
+            .filter(|(module, _)| keys.is_empty() || keys.contains(module))
+            .map(|(module, state)| (module, KeyInfo { value: state.to_value(), operation: String::new() }))
+            .collect())
+    }
+
+    fn value_matches(&self, expected: &serde_json::Value, actual: &serde_json::Value) -> bool {
+        let Ok(spec) = parse_module_spec(expected) else {
+            return false;
+        };
+        // only the fields configured are compared, live is a one-off action
+        let actual = parse_module_spec(actual).map(|actual| actual.apply(ModuleState::default())).unwrap_or_default();
+        spec.apply(actual.clone()) == actual
+    }
+}
+
+// ModuleSpec is the value of a kernel.modules key, like {"load": true, "options": "debug=1", "live": true}. The fields
+// which are not set are left unchanged, an empty options removes the options of the module
+struct ModuleSpec {
+    load: Option<bool>,
+    options: Option<String>,
+    blacklist: Option<bool>,
+    live: bool,
+}
+
+// ModuleState is how a module is configured in the files of kernel.modules
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ModuleState {
+    load: bool,
+    options: String,
+    blacklist: bool,
+}
+
+impl ModuleSpec {
+    fn apply(&self, mut state: ModuleState) -> ModuleState {
+        if let Some(load) = self.load {
+            state.load = load;
+        }
+        if let Some(options) = &self.options {
+            state.options = options.split_whitespace().collect::<Vec<&str>>().join(" ");
+        }
+        if let Some(blacklist) = self.blacklist {
+            state.blacklist = blacklist;
+        }
+        state
+    }
+}
+
+impl ModuleState {
+    fn is_empty(&self) -> bool {
+        !self.load && !self.blacklist && self.options.is_empty()
+    }
+
+    fn to_value(&self) -> serde_json::Value {
+        serde_json::json!({ "load": self.load, "options": self.options, "blacklist": self.blacklist })
+    }
+}
+
+// module_change returns the state of module after configuring it with key_info, None if module is left unconfigured,
+// and whether the change is applied live. The configuration which can't be parsed is skipped and keeps state
+fn module_change(module: &str, key_info: &KeyInfo, state: Option<ModuleState>) -> (Option<ModuleState>, bool) {
+    if !is_valid_module_name(module) {
+        warn!("Failed to parse kernel module name \"{}\", skip this configuration", module);
+        return (state, false);
+    }
+    if key_info.operation == "delete" {
+        if state.is_none() {
+            warn!("Failed to delete inexistent kernel module \"{}\"", module);
+        }
+        return (None, false);
+    }
+    if !key_info.operation.is_empty() {
+        warn!("Unknown operation \"{}\", updating kernel module \"{}\" by default", key_info.operation, module);
+    }
+    let spec = match parse_module_spec(&key_info.value) {
+        Ok(spec) => spec,
+        Err(e) => {
+            warn!("Failed to parse the value of kernel module \"{}\": {:#}, skip this configuration", module, e);
+            return (state, false);
+        },
+    };
+    let new_state = spec.apply(state.clone().unwrap_or_default());
+    if new_state.load && new_state.blacklist {
+        warn!("Failed to load and blacklist kernel module \"{}\" at the same time, skip this configuration", module);
+        return (state, false);
+    }
+    (Some(new_state).filter(|s| !s.is_empty()), spec.live)
+}
+
+fn parse_module_spec(value: &serde_json::Value) -> Result<ModuleSpec> {
+    let Some(spec) = value.as_object() else {
+        bail!("expect an object with load, options, blacklist or live");
+    };
+    if let Some(key) = spec.keys().find(|k| !["load", "options", "blacklist", "live"].contains(&k.as_str())) {
+        bail!("unknown field \"{}\"", key);
+    }
+    let get_bool = |field: &str| -> Result<Option<bool>> {
+        match spec.get(field) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => value.as_bool().map(Some).ok_or_else(|| anyhow!("{} {} is not a boolean", field, value)),
+        }
+    };
+    let options = match spec.get("options") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(options) => match convert_json_value_to_string(options) {
+            (options, true) if !options.contains(['\n', '#']) => Some(options),
+            _ => bail!("illegal options {}", options),
+        },
+    };
+    Ok(ModuleSpec {
+        load: get_bool("load")?,
+        options,
+        blacklist: get_bool("blacklist")?,
+        live: get_bool("live")?.unwrap_or(false),
+    })
+}
+
+fn is_valid_module_name(module: &str) -> bool {
+    !module.is_empty()
+        && !module.starts_with('-')
+        && module.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl<T: CommandExecutor> KernelModules<T> {
+    // read_modules reads the modules configured in the modules-load.d and modprobe.d files of kernel.modules, the
+    // lines of other commands are ignored
+    fn read_modules(&self) -> Result<BTreeMap<String, ModuleState>> {
+        let mut modules: BTreeMap<String, ModuleState> = BTreeMap::new();
+        for line in read_config_lines(&self.load_path)? {
+            let module = line.trim();
+            if module.is_empty() || module.starts_with('#') || module.starts_with(';') {
+                continue;
+            }
+            modules.entry(module.to_string()).or_default().load = true;
+        }
+        for line in read_config_lines(&self.modprobe_path)? {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("options"), Some(module)) => {
+                    modules.entry(module.to_string()).or_default().options = fields.collect::<Vec<&str>>().join(" ")
+                },
+                (Some("blacklist"), Some(module)) => modules.entry(module.to_string()).or_default().blacklist = true,
+                _ => continue,
+            }
+        }
+        Ok(modules)
+    }
+
+    // write_modules writes the files of kernel.modules, the files which are unchanged are not rewritten
+    fn write_modules(&self, modules: &BTreeMap<String, ModuleState>) -> Result<()> {
+        let mut load = vec![KERNEL_MODULES_HEADER.to_string()];
+        let mut modprobe = vec![KERNEL_MODULES_HEADER.to_string()];
+        for (module, state) in modules {
+            if state.load {
+                load.push(module.clone());
+            }
+            if !state.options.is_empty() {
+                modprobe.push(format!("options {} {}", module, state.options));
+            }
+            if state.blacklist {
+                modprobe.push(format!("blacklist {}", module));
+            }
+        }
+        for (path, lines) in [(&self.load_path, load), (&self.modprobe_path, modprobe)] {
+            let current = read_config_lines(path)?;
+            // the missing file is not created for no module
+            if current == lines || (current.is_empty() && lines.len() == 1) {
+                continue;
+            }
+            if let Some(dir) = Path::new(path).parent() {
+                fs::create_dir_all(dir).with_context(|| format!("Failed to create directory {}", dir.display()))?;
+            }
+            write_configs_to_file(path, &lines)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use std::{fs, io::Write};
 
     use mockall::{mock, predicate::*};
     use serde_json::json;
     use tempfile::{NamedTempFile, TempDir};
-    use values::{CONTAINER_CONTAINERD, KUBERNETES_KUBELET, PAM_LIMTS};
+    use values::{CONTAINER_CONTAINERD, KERNEL_MODULES, KUBERNETES_KUBELET, PAM_LIMTS, SYSTEMD_UNIT};
 
     use super::*;
     use crate::sys_mgmt::{GRUB_CMDLINE_CURRENT, GRUB_CMDLINE_NEXT, KERNEL_SYSCTL, KERNEL_SYSCTL_PERSIST};
@@ -1395,4 +2364,388 @@ menuentry 'B' --class KubeOS --class gnu-linux --class gnu --class os --unrestri
         assert!(is_file_exist(&config.config_path));
         delete_file_or_dir(&config.config_path).unwrap();
     }
+
+    #[test]
+    fn test_plan_config() {
+        init();
+        let key_info = |value: serde_json::Value, operation: &str| KeyInfo { value, operation: operation.to_string() };
+        let diff = |key: &str, before: Option<&str>, after: Option<&str>| ConfigDiff {
+            key: key.to_string(),
+            before: before.map(|v| v.to_string()),
+            after: after.map(|v| v.to_string()),
+        };
+
+        // kernel.sysctl.persist
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "# comment\na = 0\nb=2").unwrap();
+        let config = Sysconfig {
+            model: KERNEL_SYSCTL_PERSIST.to_string(),
+            config_path: tmp_file.path().to_str().unwrap().to_string(),
+            contents: HashMap::from([
+                ("a".to_string(), key_info(json!(1), "")),
+                ("b".to_string(), key_info(json!(2), "delete")),
+                ("c".to_string(), key_info(json!("3"), "")),
+                ("d".to_string(), key_info(json!(4), "delete")),
+            ]),
+        };
+        let diffs = KernelSysctlPersist.plan_config(&config).unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                diff("a", Some("0"), Some("1")),
+                diff("b", Some("2"), None),
+                diff("c", None, Some("3")),
+                diff("d", None, None),
+            ]
+        );
+        assert!(diffs[0].is_changed() && !diffs[3].is_changed());
+        assert_eq!(fs::read_to_string(tmp_file.path()).unwrap(), "# comment\na = 0\nb=2\n");
+
+        // the missing file is not created
+        let tmp_dir = TempDir::new().unwrap();
+        let missing = tmp_dir.path().join("sysctl.conf").to_str().unwrap().to_string();
+        let config = Sysconfig {
+            model: KERNEL_SYSCTL_PERSIST.to_string(),
+            config_path: missing.clone(),
+            contents: HashMap::from([("a".to_string(), key_info(json!(1), ""))]),
+        };
+        assert_eq!(KernelSysctlPersist.plan_config(&config).unwrap(), vec![diff("a", None, Some("1"))]);
+        assert!(!is_file_exist(&missing));
+
+        // kernel.sysctl
+        fs::write(tmp_dir.path().join("a"), "0\n").unwrap();
+        let kernel_sysctl = KernelSysctl::new(&format!("{}/", tmp_dir.path().to_str().unwrap()));
+        let config = Sysconfig {
+            model: KERNEL_SYSCTL.to_string(),
+            config_path: String::new(),
+            contents: HashMap::from([
+                ("a".to_string(), key_info(json!(1), "")),
+                ("b".to_string(), key_info(json!(2), "delete")),
+            ]),
+        };
+        assert_eq!(
+            kernel_sysctl.plan_config(&config).unwrap(),
+            vec![diff("a", Some("0"), Some("1")), diff("b", None, None)]
+        );
+        assert_eq!(fs::read_to_string(tmp_dir.path().join("a")).unwrap(), "0\n");
+
+        // pam.limits
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "* soft nofile 1024").unwrap();
+        let pam_limits = PamLimits { config_path: tmp_file.path().to_str().unwrap().to_string() };
+        let config = Sysconfig {
+            model: PAM_LIMTS.to_string(),
+            config_path: String::new(),
+            contents: HashMap::from([
+                ("*".to_string(), key_info(json!("_._.2048"), "")),
+                ("root".to_string(), key_info(json!("hard.nproc.unlimited"), "")),
+            ]),
+        };
+        assert_eq!(
+            pam_limits.plan_config(&config).unwrap(),
+            vec![
+                diff("*", Some("soft.nofile.1024"), Some("soft.nofile.2048")),
+                diff("root", None, Some("hard.nproc.unlimited")),
+            ]
+        );
+
+        // kubernetes.kubelet
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "maxPods: 110\nevictionHard:\n  memory.available: 100Mi").unwrap();
+        let config = Sysconfig {
+            model: KUBERNETES_KUBELET.to_string(),
+            config_path: tmp_file.path().to_str().unwrap().to_string(),
+            contents: HashMap::from([
+                ("maxPods".to_string(), key_info(json!(220), "")),
+                ("evictionHard.\"memory.available\"".to_string(), key_info(json!(""), "delete")),
+                ("address".to_string(), key_info(json!("0.0.0.0"), "")),
+            ]),
+        };
+        assert_eq!(
+            KubernetesKubelet.plan_config(&config).unwrap(),
+            vec![
+                diff("address", None, Some("\"0.0.0.0\"")),
+                diff("evictionHard.\"memory.available\"", Some("\"100Mi\""), None),
+                diff("maxPods", Some("110"), Some("220")),
+            ]
+        );
+
+        // container.containerd
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "[grpc]\nuid = 0").unwrap();
+        let config = Sysconfig {
+            model: CONTAINER_CONTAINERD.to_string(),
+            config_path: tmp_file.path().to_str().unwrap().to_string(),
+            contents: HashMap::from([
+                ("grpc.uid".to_string(), key_info(json!(1), "")),
+                ("plugins.\"io.containerd.grpc.v1.cri\".sandbox_image".to_string(), key_info(json!("pause"), "")),
+            ]),
+        };
+        assert_eq!(
+            ContainerContainerd.plan_config(&config).unwrap(),
+            vec![
+                diff("grpc.uid", Some("0"), Some("1")),
+                diff("plugins.\"io.containerd.grpc.v1.cri\".sandbox_image", None, Some("\"pause\"")),
+            ]
+        );
+        assert_eq!(fs::read_to_string(tmp_file.path()).unwrap(), "[grpc]\nuid = 0\n");
+    }
+
+    #[test]
+    fn test_get_config() {
+        init();
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<String>>();
+
+        // kernel.sysctl
+        let tmp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(tmp_dir.path().join("vm")).unwrap();
+        fs::write(tmp_dir.path().join("vm/swappiness"), "60\n").unwrap();
+        fs::write(tmp_dir.path().join("a"), "1\t2\n").unwrap();
+        let kernel_sysctl = KernelSysctl::new(&format!("{}/", tmp_dir.path().to_str().unwrap()));
+        let contents = kernel_sysctl.get_config("", &keys(&["vm.swappiness", "b"])).unwrap();
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents["vm.swappiness"].value, json!("60"));
+        let contents = kernel_sysctl.get_config("", &[]).unwrap();
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents["a"].value, json!("1\t2"));
+
+        // grub.cmdline.current
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "menuentry 'A' {{\n        linux   /boot/vmlinuz root=UUID=1 ro panic=3\n}}").unwrap();
+        writeln!(tmp_file, "menuentry 'B' {{\n        linux   /boot/vmlinuz root=UUID=2 ro panic=5\n}}").unwrap();
+        let grub_cmdline =
+            GrubCmdline { grub_path: tmp_file.path().to_str().unwrap().to_string(), is_cur_partition: false };
+        let contents = grub_cmdline.get_config("", &[]).unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents["root"].value, json!("UUID=1"));
+        assert_eq!(contents["ro"].value, json!(""));
+        let contents = grub_cmdline.get_config("", &keys(&["panic"])).unwrap();
+        assert_eq!(contents["panic"].value, json!("3"));
+
+        // pam.limits
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "# comment\n* soft nofile 1024").unwrap();
+        let pam_limits = PamLimits { config_path: tmp_file.path().to_str().unwrap().to_string() };
+        let contents = pam_limits.get_config("", &[]).unwrap();
+        assert_eq!(contents["*"].value, json!("soft.nofile.1024"));
+
+        // kubernetes.kubelet
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(tmp_file, "maxPods: 110\nevictionHard:\n  memory.available: 100Mi\nclusterDNS:\n- 10.0.0.10").unwrap();
+        let config_path = tmp_file.path().to_str().unwrap();
+        let contents = KubernetesKubelet.get_config(config_path, &[]).unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents["evictionHard.\"memory.available\""].value, json!("100Mi"));
+        assert_eq!(contents["clusterDNS"].value, json!(["10.0.0.10"]));
+        let contents =
+            KubernetesKubelet.get_config(config_path, &keys(&["maxPods", "evictionHard", "address"])).unwrap();
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents["maxPods"].value, json!(110));
+        assert_eq!(contents["evictionHard"].value, json!({"memory.available": "100Mi"}));
+        let missing = tmp_dir.path().join("config.yaml");
+        assert!(KubernetesKubelet.get_config(missing.to_str().unwrap(), &[]).unwrap().is_empty());
+
+        // container.containerd
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            tmp_file,
+            "version = 2\n[grpc]\nuid = 0\n[plugins.\"io.containerd.grpc.v1.cri\"]\nsandbox_image = \"pause\""
+        )
+        .unwrap();
+        let config_path = tmp_file.path().to_str().unwrap();
+        let contents = ContainerContainerd.get_config(config_path, &[]).unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents["plugins.\"io.containerd.grpc.v1.cri\".sandbox_image"].value, json!("pause"));
+        let contents = ContainerContainerd.get_config(config_path, &keys(&["grpc.uid", "grpc.gid"])).unwrap();
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents["grpc.uid"].value, json!(0));
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        init();
+        let tmp_dir = TempDir::new().unwrap();
+        let unit_dir = tmp_dir.path().to_str().unwrap().to_string();
+        let dropin_path = tmp_dir.path().join("containerd.service.d").join(values::SYSTEMD_DROPIN_FILE);
+        let mut executor = MockCommandExec::new();
+        let mut seq = mockall::Sequence::new();
+        for args in [
+            vec!["daemon-reload"],
+            vec!["unmask", "containerd.service"],
+            vec!["enable", "containerd.service"],
+            vec!["restart", "containerd.service"],
+            vec!["mask", "kdump.service"],
+        ] {
+            executor
+                .expect_run_command()
+                .withf(move |name, a| name == "systemctl" && a == args.as_slice())
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_, _| Ok(()));
+        }
+        let systemd_unit = SystemdUnit { unit_dir: unit_dir.clone(), executor };
+        let mut config = Sysconfig {
+            model: SYSTEMD_UNIT.to_string(),
+            config_path: String::new(),
+            contents: HashMap::from([
+                (
+                    "containerd.service".to_string(),
+                    KeyInfo {
+                        value: json!({"state": "enabled", "restart": true, "dropin": {
+                            "Unit": {"After": "network.target"},
+                            "Service": {"LimitNOFILE": 1048576, "ExecStart": ["", "/usr/bin/containerd"]}}}),
+                        operation: String::new(),
+                    },
+                ),
+                ("kdump.service".to_string(), KeyInfo { value: json!({"state": "masked"}), operation: String::new() }),
+                ("../etc".to_string(), KeyInfo { value: json!({"state": "masked"}), operation: String::new() }),
+                ("sshd.service".to_string(), KeyInfo { value: json!({"state": "running"}), operation: String::new() }),
+            ]),
+        };
+        systemd_unit.set_config(&mut config).unwrap();
+        // the sections and properties are written in the order of their names, whatever the order given
+        let expected = "# Generated by KubeOS, changes to this file will be overwritten\n[Service]\nExecStart=\n\
+                        ExecStart=/usr/bin/containerd\nLimitNOFILE=1048576\n[Unit]\nAfter=network.target\n";
+        assert_eq!(fs::read_to_string(&dropin_path).unwrap(), expected);
+
+        // the unchanged drop-in is not rewritten and the manager is not reloaded
+        let mut executor = MockCommandExec::new();
+        executor.expect_run_command().times(0);
+        let systemd_unit = SystemdUnit { unit_dir: unit_dir.clone(), executor };
+        let dropin = json!({"Unit": {"After": "network.target"},
+            "Service": {"LimitNOFILE": 1048576, "ExecStart": ["", "/usr/bin/containerd"]}});
+        config.contents = HashMap::from([(
+            "containerd.service".to_string(),
+            KeyInfo { value: json!({ "dropin": dropin }), operation: String::new() },
+        )]);
+        systemd_unit.set_config(&mut config).unwrap();
+
+        // get_config reads the state and drop-in, which value_matches compares with the configured value
+        let mut executor = MockCommandExec::new();
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, args| name == "systemctl" && args.last() == Some(&"containerd.service"))
+            .returning(|_, _| Ok("enabled".to_string()));
+        executor.expect_run_command_with_output().returning(|_, _| Ok("static".to_string()));
+        let systemd_unit = SystemdUnit { unit_dir: unit_dir.clone(), executor };
+        let contents = systemd_unit.get_config("", &[]).unwrap();
+        assert_eq!(contents.len(), 1);
+        let actual = &contents["containerd.service"].value;
+        assert_eq!(actual["state"], json!("enabled"));
+        assert_eq!(actual["dropin"]["Service"]["ExecStart"], json!(["", "/usr/bin/containerd"]));
+        assert!(systemd_unit.value_matches(&json!({"state": "enabled", "restart": true, "dropin": dropin}), actual));
+        assert!(!systemd_unit.value_matches(&json!({"state": "disabled"}), actual));
+        assert!(!systemd_unit.value_matches(&json!({"dropin": {"Service": {"LimitNOFILE": 1024}}}), actual));
+        let contents = systemd_unit.get_config("", &["getty@tty1.service".to_string()]).unwrap();
+        assert_eq!(contents["getty@tty1.service"].value, json!({"dropin": {}}));
+
+        // plan_config shows the drop-in removed by the delete operation
+        config.contents = HashMap::from([(
+            "containerd.service".to_string(),
+            KeyInfo { value: json!(""), operation: "delete".to_string() },
+        )]);
+        let diffs = systemd_unit.plan_config(&config).unwrap();
+        assert_eq!(diffs[0].after, Some(json!({"dropin": {}, "state": "enabled"}).to_string()));
+        assert!(diffs[0].is_changed());
+
+        // delete removes the drop-in written by systemd.unit and reloads the manager
+        let mut executor = MockCommandExec::new();
+        executor
+            .expect_run_command()
+            .withf(|name, args| name == "systemctl" && args == ["daemon-reload"])
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let systemd_unit = SystemdUnit { unit_dir, executor };
+        systemd_unit.set_config(&mut config).unwrap();
+        assert!(!dropin_path.exists());
+        assert!(!tmp_dir.path().join("containerd.service.d").exists());
+    }
+
+    #[test]
+    fn test_kernel_modules() {
+        init();
+        let tmp_dir = TempDir::new().unwrap();
+        let load_path = tmp_dir.path().join("modules-load.d/kubeos.conf");
+        let modprobe_path = tmp_dir.path().join("modprobe.d/kubeos.conf");
+        let kernel_modules = |executor: MockCommandExec| KernelModules {
+            load_path: load_path.to_str().unwrap().to_string(),
+            modprobe_path: modprobe_path.to_str().unwrap().to_string(),
+            executor,
+        };
+        let contents = |items: &[(&str, serde_json::Value, &str)]| {
+            items
+                .iter()
+                .map(|(k, v, op)| (k.to_string(), KeyInfo { value: v.clone(), operation: op.to_string() }))
+                .collect::<HashMap<String, KeyInfo>>()
+        };
+
+        // nothing is written for the configurations which can't be parsed
+        let mut executor = MockCommandExec::new();
+        executor.expect_run_command().times(0);
+        let mut config = Sysconfig {
+            model: KERNEL_MODULES.to_string(),
+            config_path: String::new(),
+            contents: contents(&[
+                ("../rdma", json!({"load": true}), ""),
+                ("nouveau", json!({"load": true, "blacklist": true}), ""),
+                ("nvme", json!({"load": "yes"}), ""),
+            ]),
+        };
+        kernel_modules(executor).set_config(&mut config).unwrap();
+        assert!(!load_path.exists() && !modprobe_path.exists());
+
+        let mut executor = MockCommandExec::new();
+        let mut seq = mockall::Sequence::new();
+        executor
+            .expect_run_command()
+            .withf(|name, args| name == "modprobe" && args == ["-r", "nouveau"])
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Err(anyhow!("Module nouveau is in use")));
+        executor
+            .expect_run_command()
+            .withf(|name, args| name == "modprobe" && args == ["rdma_ucm"])
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        config.contents = contents(&[
+            ("rdma_ucm", json!({"load": true, "options": "  debug=1   ib=2", "live": true}), ""),
+            ("nvme_core", json!({"options": "multipath=Y"}), ""),
+            ("nouveau", json!({"blacklist": true, "live": true}), ""),
+            ("floppy", json!(""), "delete"),
+        ]);
+        kernel_modules(executor).set_config(&mut config).unwrap();
+        let header = "# Generated by KubeOS, changes to this file will be overwritten";
+        assert_eq!(fs::read_to_string(&load_path).unwrap(), format!("{}\nrdma_ucm\n", header));
+        assert_eq!(
+            fs::read_to_string(&modprobe_path).unwrap(),
+            format!("{}\nblacklist nouveau\noptions nvme_core multipath=Y\noptions rdma_ucm debug=1 ib=2\n", header)
+        );
+
+        // get_config reads the modules back, which value_matches compares with the configured value
+        let kernel_modules = kernel_modules(MockCommandExec::new());
+        let current = kernel_modules.get_config("", &[]).unwrap();
+        assert_eq!(current.len(), 3);
+        assert_eq!(current["nvme_core"].value, json!({"load": false, "options": "multipath=Y", "blacklist": false}));
+        let actual = &current["rdma_ucm"].value;
+        assert!(kernel_modules.value_matches(&json!({"load": true, "options": "debug=1 ib=2", "live": true}), actual));
+        assert!(!kernel_modules.value_matches(&json!({"blacklist": true}), actual));
+        let current = kernel_modules.get_config("", &["nouveau".to_string(), "sd_mod".to_string()]).unwrap();
+        assert_eq!(current.len(), 1);
+
+        // plan_config shows the changes without writing them, delete removes every line of a module
+        config.contents = contents(&[
+            ("rdma_ucm", json!(""), "delete"),
+            ("nvme_core", json!({"options": ""}), ""),
+            ("nouveau", json!({"blacklist": false, "load": true}), ""),
+        ]);
+        let diffs = kernel_modules.plan_config(&config).unwrap();
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(diffs[0].after, Some(json!({"load": true, "options": "", "blacklist": false}).to_string()));
+        assert_eq!(diffs[1].after, None);
+        assert_eq!(diffs[2].after, None);
+        kernel_modules.set_config(&mut config).unwrap();
+        assert_eq!(fs::read_to_string(&load_path).unwrap(), format!("{}\nnouveau\n", header));
+        assert_eq!(fs::read_to_string(&modprobe_path).unwrap(), format!("{}\n", header));
+    }
 }
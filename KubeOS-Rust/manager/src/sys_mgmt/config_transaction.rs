@@ -0,0 +1,161 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+
+use super::config::{write_config_atomically, ConfigTemplate};
+use crate::{
+    api::Sysconfig,
+    utils::{delete_file_or_dir, is_file_exist},
+};
+
+/// ConfigTransaction saves the files and kernel parameters which a configure request is going to change, so that
+/// they can all be restored if any model of the request fails
+pub struct ConfigTransaction<'a> {
+    template: &'a ConfigTemplate,
+    // the content of the files before configuring, None if the file didn't exist
+    files: Vec<(String, Option<Vec<u8>>)>,
+    // the values of the models changing the running kernel before configuring
+    runtime: Vec<Sysconfig>,
+}
+
+impl<'a> ConfigTransaction<'a> {
+    /// Save the state of configs, the unknown models are left to fail when configuring
+    pub fn begin(template: &'a ConfigTemplate, configs: &[Sysconfig]) -> Result<Self> {
+        let mut transaction = ConfigTransaction { template, files: Vec::new(), runtime: Vec::new() };
+        for config in configs {
+            let Some(configuration) = template.get(&config.model) else {
+                continue;
+            };
+            for path in configuration.config_paths(config) {
+                if transaction.files.iter().any(|(p, _)| *p == path) {
+                    continue;
+                }
+                let content = if is_file_exist(&path) {
+                    Some(fs::read(&path).with_context(|| format!("Failed to save config file \"{}\"", path))?)
+                } else {
+                    None
+                };
+                transaction.files.push((path, content));
+            }
+            if configuration.changes_runtime() {
+                let keys: Vec<String> = config.contents.keys().cloned().collect();
+                let contents = configuration.get_config(&config.config_path, &keys)?;
+                transaction.runtime.push(Sysconfig {
+                    model: config.model.clone(),
+                    config_path: config.config_path.clone(),
+                    contents,
+                });
+            }
+        }
+        Ok(transaction)
+    }
+
+    /// Restore every saved file and kernel parameter, carrying on after failures to restore as much as possible
+    pub fn restore(&self) -> Result<()> {
+        let mut failed = Vec::new();
+        for (path, content) in self.files.iter() {
+            if let Err(e) = restore_file(path, content.as_deref()) {
+                error!("Failed to restore config file \"{}\": {:#}", path, e);
+                failed.push(path.clone());
+            }
+        }
+        // restore the kernel parameters in the reverse order, in case a key is set by more than one config
+        for config in self.runtime.iter().rev() {
+            let Some(configuration) = self.template.get(&config.model) else {
+                continue;
+            };
+            let mut config = config.clone();
+            if let Err(e) = configuration.set_config(&mut config) {
+                error!("Failed to restore {}: {:#}", config.model, e);
+                failed.push(config.model.clone());
+            }
+        }
+        if !failed.is_empty() {
+            bail!("Failed to restore {}", failed.join(", "));
+        }
+        info!("Restored the configuration before configuring");
+        Ok(())
+    }
+}
+
+fn restore_file(path: &str, content: Option<&[u8]>) -> Result<()> {
+    match content {
+        Some(content) => {
+            if fs::read(path).is_ok_and(|current| current == content) {
+                return Ok(());
+            }
+            write_config_atomically(path, content)
+        },
+        None => delete_file_or_dir(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        api::KeyInfo,
+        sys_mgmt::{
+            KernelSysctl, KernelSysctlPersist, KubernetesKubelet, KERNEL_SYSCTL, KERNEL_SYSCTL_PERSIST,
+            KUBERNETES_KUBELET,
+        },
+    };
+
+    #[test]
+    fn test_config_transaction() {
+        let tmp_dir = TempDir::new().unwrap();
+        let proc_path = format!("{}/", tmp_dir.path().to_str().unwrap());
+        fs::write(tmp_dir.path().join("a"), "0\n").unwrap();
+        let sysctl_conf = tmp_dir.path().join("sysctl.conf").to_str().unwrap().to_string();
+        fs::write(&sysctl_conf, "a=0\n").unwrap();
+        let kubelet_conf = tmp_dir.path().join("config.yaml").to_str().unwrap().to_string();
+
+        let mut template: ConfigTemplate = HashMap::new();
+        template.insert(KERNEL_SYSCTL.to_string(), Box::new(KernelSysctl { proc_path }));
+        template.insert(KERNEL_SYSCTL_PERSIST.to_string(), Box::new(KernelSysctlPersist));
+        template.insert(KUBERNETES_KUBELET.to_string(), Box::new(KubernetesKubelet));
+        let config = |model: &str, config_path: &str, key: &str, value: serde_json::Value| Sysconfig {
+            model: model.to_string(),
+            config_path: config_path.to_string(),
+            contents: HashMap::from([(key.to_string(), KeyInfo { value, operation: "".to_string() })]),
+        };
+        let mut configs = vec![
+            config(KERNEL_SYSCTL, "", "a", json!(1)),
+            config(KERNEL_SYSCTL_PERSIST, &sysctl_conf, "a", json!(1)),
+            config(KUBERNETES_KUBELET, &kubelet_conf, "maxPods", json!(220)),
+            config("unknown", "", "a", json!(1)),
+        ];
+        let transaction = ConfigTransaction::begin(&template, &configs).unwrap();
+        assert_eq!(transaction.files.len(), 2);
+        assert_eq!(transaction.runtime.len(), 1);
+        for config in configs.iter_mut().take(3) {
+            template[&config.model].set_config(config).unwrap();
+        }
+        assert_eq!(fs::read_to_string(tmp_dir.path().join("a")).unwrap(), "1\n");
+        assert_eq!(fs::read_to_string(&sysctl_conf).unwrap(), "a=1\n");
+        assert!(is_file_exist(&kubelet_conf));
+
+        transaction.restore().unwrap();
+        assert_eq!(fs::read_to_string(tmp_dir.path().join("a")).unwrap(), "0\n");
+        assert_eq!(fs::read_to_string(&sysctl_conf).unwrap(), "a=0\n");
+        assert!(!is_file_exist(&kubelet_conf));
+    }
+}
@@ -16,7 +16,7 @@ use anyhow::{anyhow, Context, Result};
 use log::{debug, info};
 
 use crate::{
-    api::{ImageHandler, UpgradeRequest},
+    api::{ImageHandler, UpgradeRequest, UpgradeStage},
     sys_mgmt::{DMV_BOOT_IMG, DMV_HASH_IMG, DMV_ROOT_IMG, IMAGE_PERMISSION, NEED_BYTES},
     utils::*,
 };
@@ -25,53 +25,90 @@ pub struct CtrImageHandler<T: CommandExecutor> {
     pub paths: PreparePath,
     pub executor: T,
     pub dmv: bool,
+    pub need_bytes: i64,
+    pub progress: ProgressTracker,
+    pub checkpoint: Checkpoint,
 }
 
 const DEFAULT_NAMESPACE: &str = "k8s.io";
 
 impl<T: CommandExecutor> ImageHandler<T> for CtrImageHandler<T> {
     fn download_image(&self, req: &UpgradeRequest) -> Result<UpgradeImageManager<T>> {
-        perpare_env(&self.paths, NEED_BYTES, IMAGE_PERMISSION)?;
-        self.get_image(req)?;
+        let step = self.checkpoint.resume_step(req);
+        if step >= Some(PrepareStep::ImageBuilt) && is_image_built(&self.paths, self.dmv) {
+            info!("Resume with the image built from {}", req.container_image);
+            return self.image_manager();
+        }
+        perpare_env(&self.paths, self.need_bytes, IMAGE_PERMISSION)?;
+        self.get_image(req, step)?;
         self.get_rootfs_archive(req, IMAGE_PERMISSION)?;
 
-        if self.dmv {
-            return Ok(UpgradeImageManager::new(
-                self.paths.clone(),
-                PartitionInfo::default(),
-                self.executor.clone(),
-                self.dmv,
-            ));
+        let mut img_manager = self.image_manager()?;
+        if !self.dmv {
+            img_manager = img_manager.create_os_image(IMAGE_PERMISSION)?;
         }
-        let (_, next_partition_info) = get_partition_info(&self.executor)?;
-        let img_manager =
-            UpgradeImageManager::new(self.paths.clone(), next_partition_info, self.executor.clone(), false);
-        img_manager.create_os_image(IMAGE_PERMISSION)
+        self.checkpoint.save(req, PrepareStep::ImageBuilt);
+        Ok(img_manager)
     }
 }
 
 impl Default for CtrImageHandler<RealCommandExecutor> {
     fn default() -> Self {
-        Self { paths: PreparePath::default(), executor: RealCommandExecutor {}, dmv: false }
+        Self {
+            paths: PreparePath::default(),
+            executor: RealCommandExecutor {},
+            dmv: false,
+            need_bytes: NEED_BYTES,
+            progress: ProgressTracker::default(),
+            checkpoint: Checkpoint::default(),
+        }
     }
 }
 
 impl<T: CommandExecutor> CtrImageHandler<T> {
     #[cfg(test)]
     pub fn new(paths: PreparePath, executor: T, dmv: bool) -> Self {
-        Self { paths, executor, dmv }
+        Self {
+            paths,
+            executor,
+            dmv,
+            need_bytes: NEED_BYTES,
+            progress: ProgressTracker::default(),
+            checkpoint: Checkpoint::default(),
+        }
     }
 
-    fn get_image(&self, req: &UpgradeRequest) -> Result<()> {
+    fn image_manager(&self) -> Result<UpgradeImageManager<T>> {
+        // the partitions of dm-verity mode are installed by kubeos-dmv
+        let next_partition_info =
+            if self.dmv { PartitionInfo::default() } else { get_partition_info(&self.executor)?.1 };
+        Ok(UpgradeImageManager::new(self.paths.clone(), next_partition_info, self.executor.clone(), self.dmv)
+            .with_progress(self.progress.clone()))
+    }
+
+    // get_image pulls the image and checks its digest, the image pulled by the interrupted preparation is reused
+    fn get_image(&self, req: &UpgradeRequest, step: Option<PrepareStep>) -> Result<()> {
         let image_name = &req.container_image;
         is_valid_image_name(image_name)?;
         let cli: String =
             if is_command_available("crictl", &self.executor) { "crictl".to_string() } else { "ctr".to_string() };
+        if step >= Some(PrepareStep::Downloaded)
+            && check_oci_image_digest(&cli, image_name, &req.check_sum, &self.executor).is_ok()
+        {
+            info!("Resume with the pulled image {}", image_name);
+            self.checkpoint.save(req, PrepareStep::Verified);
+            return Ok(());
+        }
+        self.checkpoint.clear();
         remove_image_if_exist(&cli, image_name, &self.executor)?;
         info!("Start pulling image {}", image_name);
-        pull_image(&cli, image_name, &self.executor)?;
+        self.progress.set_stage(UpgradeStage::Download, 0)?;
+        pull_image(&cli, image_name, &self.executor, &self.progress)?;
+        self.checkpoint.save(req, PrepareStep::Downloaded);
         info!("Start checking image digest");
+        self.progress.set_stage(UpgradeStage::Verify, 0)?;
         check_oci_image_digest(&cli, image_name, &req.check_sum, &self.executor)?;
+        self.checkpoint.save(req, PrepareStep::Verified);
         Ok(())
     }
 
@@ -209,8 +246,22 @@ mod tests {
             .times(1)
             .returning(|_, _| Ok(command_output2.to_string()));
         let ctr = CtrImageHandler::new(PreparePath::default(), mock_executor, false);
-        let result = ctr.get_image(&req);
+        let result = ctr.get_image(&req, None);
         assert!(result.is_ok());
+
+        // the image pulled by the interrupted preparation is reused without pulling again
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut mock_executor = MockCommandExec::new();
+        mock_executor.expect_run_command().withf(|cmd, _| cmd == "/bin/sh").times(1).returning(|_, _| Ok(()));
+        mock_executor
+            .expect_run_command_with_output()
+            .withf(|cmd, args| cmd == "crictl" && args.contains(&"inspecti"))
+            .times(1)
+            .returning(|_, _| Ok(command_output2.to_string()));
+        let mut ctr = CtrImageHandler::new(PreparePath::default(), mock_executor, false);
+        ctr.checkpoint = Checkpoint::new(tmp_dir.path().join("prepare-state.json"));
+        ctr.get_image(&req, Some(PrepareStep::Downloaded)).unwrap();
+        assert_eq!(ctr.checkpoint.resume_step(&req), Some(PrepareStep::Verified));
     }
 
     #[test]
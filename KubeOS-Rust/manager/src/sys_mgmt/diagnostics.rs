@@ -0,0 +1,199 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+
+use crate::{api::DiagnosticsResponse, utils::*};
+
+const DIAGNOSTICS_PREFIX: &str = "kubeos-diagnostics-";
+const AGENT_LOG_LINES: &str = "10000";
+
+/// Diagnostics collects the artefacts needed by support cases into a tarball under the persist directory, with the
+/// secrets in them redacted. Every collection replaces the tarball left by the previous one.
+pub struct Diagnostics<T: CommandExecutor> {
+    pub executor: T,
+    pub persist_dir: PathBuf,
+    /// Files copied into the tarball, missing files are skipped
+    pub files: Vec<PathBuf>,
+    pub slots: SlotStore,
+    pub dmv: bool,
+}
+
+impl<T: CommandExecutor> Diagnostics<T> {
+    pub fn collect(&self) -> Result<DiagnosticsResponse> {
+        self.remove_previous()?;
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+        let name = format!("{}{}", DIAGNOSTICS_PREFIX, millis);
+        let dir = self.persist_dir.join(&name);
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        let result = self.collect_into(&dir).and_then(|_| self.archive(&name));
+        delete_file_or_dir(&dir)?;
+        let tarball = result?;
+        let check_sum = file_sha256(&tarball)?;
+        info!("Collected diagnostics to {}", tarball.display());
+        Ok(DiagnosticsResponse { path: tarball.to_string_lossy().to_string(), check_sum })
+    }
+
+    // collect_into saves the artefacts to the directory, an artefact failed to collect is recorded in errors.txt
+    // rather than failing the collection
+    fn collect_into(&self, dir: &Path) -> Result<()> {
+        let mut errors = Vec::new();
+        let persist_dir = self.persist_dir.to_string_lossy().to_string();
+        let commands: [(&str, &str, Vec<&str>); 4] = [
+            ("agent.log", "journalctl", vec!["-u", "os-agent", "--no-pager", "-n", AGENT_LOG_LINES]),
+            ("lsblk.txt", "lsblk", vec!["-o", "NAME,SIZE,TYPE,FSTYPE,LABEL,MOUNTPOINT"]),
+            ("findmnt.txt", "findmnt", vec![]),
+            ("persist.txt", "find", vec![persist_dir.as_str(), "-maxdepth", "3", "-ls"]),
+        ];
+        for (file, name, args) in commands {
+            let output = self.executor.run_command_with_output(name, &args);
+            save(dir, file, output, &mut errors)?;
+        }
+        for path in self.files.iter().filter(|path| path.exists()) {
+            let target = dir.join("files").join(path.strip_prefix("/").unwrap_or(path));
+            let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+            save(dir, &target, content.map(|c| String::from_utf8_lossy(&c).to_string()), &mut errors)?;
+        }
+        let slots = if self.dmv {
+            Ok("Slots are managed by kubeos-dmv in dm-verity mode".to_string())
+        } else {
+            list_slots(&self.executor, &self.slots).and_then(|slots| Ok(serde_json::to_string_pretty(&slots)?))
+        };
+        save(dir, "slots.json", slots, &mut errors)?;
+        if !errors.is_empty() {
+            write_private(&dir.join("errors.txt"), &errors.join("\n"))?;
+        }
+        Ok(())
+    }
+
+    fn archive(&self, name: &str) -> Result<PathBuf> {
+        let tarball = self.persist_dir.join(format!("{}.tar.gz", name));
+        let persist_dir = self.persist_dir.to_string_lossy();
+        let tarball_path = tarball.to_string_lossy();
+        self.executor.run_command("tar", &["-czf", &tarball_path, "-C", &persist_dir, name])?;
+        fs::set_permissions(&tarball, Permissions::from_mode(0o600))?;
+        Ok(tarball)
+    }
+
+    fn remove_previous(&self) -> Result<()> {
+        if !self.persist_dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&self.persist_dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(DIAGNOSTICS_PREFIX) {
+                debug!("Remove the previous diagnostics {}", entry.path().display());
+                delete_file_or_dir(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn save<P: AsRef<Path>>(dir: &Path, file: P, content: Result<String>, errors: &mut Vec<String>) -> Result<()> {
+    let path = dir.join(file);
+    match content {
+        Ok(content) => write_private(&path, &redact_text(&content)),
+        Err(e) => {
+            warn!("Failed to collect {}: {:#}", path.display(), e);
+            errors.push(format!("{}: {:#}", path.strip_prefix(dir).unwrap_or(&path).display(), e));
+            Ok(())
+        },
+    }
+}
+
+fn write_private(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).mode(0o600).open(path)?;
+    file.write_all(content.as_bytes()).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use mockall::mock;
+
+    use super::*;
+
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let persist_dir = tmp_dir.path().join("persist");
+        let grub_cfg = tmp_dir.path().join("grub.cfg");
+        fs::write(&grub_cfg, "set superusers=\"root\"\npassword_pbkdf2 root grub.pbkdf2.sha512.10000.ABCD\n").unwrap();
+        fs::create_dir_all(&persist_dir).unwrap();
+        fs::write(persist_dir.join("kubeos-diagnostics-1.tar.gz"), "").unwrap();
+
+        let mut executor = MockCommandExec::new();
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "journalctl")
+            .returning(|_, _| Ok("os-agent started, client_key: abc".to_string()));
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "findmnt")
+            .returning(|_, _| Err(anyhow!("findmnt not found")));
+        executor.expect_run_command_with_output().returning(|_, _| Ok("".to_string()));
+        executor
+            .expect_run_command()
+            .withf(|name, _| name == "tar")
+            .returning(|name, args| RealCommandExecutor {}.run_command(name, args));
+
+        let diagnostics = Diagnostics {
+            executor,
+            persist_dir: persist_dir.clone(),
+            files: vec![grub_cfg.clone(), tmp_dir.path().join("nonexist")],
+            slots: SlotStore::new(persist_dir.join("slots.json")),
+            dmv: true,
+        };
+        let res = diagnostics.collect().unwrap();
+        let tarball = PathBuf::from(&res.path);
+        assert!(tarball.starts_with(&persist_dir));
+        assert_eq!(res.check_sum, file_sha256(&tarball).unwrap());
+        assert!(!persist_dir.join("kubeos-diagnostics-1.tar.gz").exists());
+        // only the tarball is left
+        assert_eq!(fs::read_dir(&persist_dir).unwrap().count(), 1);
+
+        let extract_dir = tmp_dir.path().join("extract");
+        fs::create_dir(&extract_dir).unwrap();
+        RealCommandExecutor {}.run_command("tar", &["-xzf", &res.path, "-C", extract_dir.to_str().unwrap()]).unwrap();
+        let name = tarball.file_name().unwrap().to_str().unwrap().trim_end_matches(".tar.gz").to_string();
+        let dir = extract_dir.join(name);
+        assert_eq!(fs::read_to_string(dir.join("agent.log")).unwrap(), "os-agent started, client_key: ******");
+        let grub_cfg = fs::read_to_string(dir.join("files").join(grub_cfg.strip_prefix("/").unwrap())).unwrap();
+        assert_eq!(grub_cfg, "set superusers=\"root\"\npassword_pbkdf2 root ******\n");
+        assert!(fs::read_to_string(dir.join("errors.txt")).unwrap().contains("findmnt.txt: findmnt not found"));
+        assert!(fs::read_to_string(dir.join("slots.json")).unwrap().contains("kubeos-dmv"));
+    }
+}
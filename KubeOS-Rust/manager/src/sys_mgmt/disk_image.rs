@@ -1,7 +1,8 @@
 use std::{
-    fs,
+    fs, io,
     os::unix::fs::{DirBuilderExt, PermissionsExt},
     path::{Path, PathBuf},
+    time::Instant,
 };
 
 use anyhow::{bail, Context, Result};
@@ -10,18 +11,20 @@ use reqwest::{blocking::Client, Certificate};
 use sha2::{Digest, Sha256};
 
 use crate::{
-    api::{CertsInfo, ImageHandler, UpgradeRequest},
+    api::{CertsInfo, ImageHandler, UpgradeRequest, UpgradeStage},
     sys_mgmt::{CERTS_PATH, IMAGE_PERMISSION, PERSIST_DIR},
     utils::*,
 };
 
-const BUFFER: u64 = 1024 * 1024 * 10;
+pub(crate) const BUFFER: u64 = 1024 * 1024 * 10;
 
 pub struct DiskImageHandler<T: CommandExecutor> {
     pub paths: PreparePath,
     pub executor: T,
     pub certs_path: String,
     pub dmv: bool,
+    pub progress: ProgressTracker,
+    pub checkpoint: Checkpoint,
 }
 
 impl<T: CommandExecutor> ImageHandler<T> for DiskImageHandler<T> {
@@ -29,14 +32,36 @@ impl<T: CommandExecutor> ImageHandler<T> for DiskImageHandler<T> {
         if self.dmv {
             bail!("DM-Verity doesn't support disk image upgrade");
         }
-        clean_env(&self.paths.update_path, &self.paths.mount_path, &self.paths.image_path)?;
-        fs::DirBuilder::new().recursive(true).mode(IMAGE_PERMISSION).create(&self.paths.mount_path)?;
-        self.download(req)?;
-        self.checksum_match(self.paths.tar_path.to_str().unwrap_or_default(), &req.check_sum)?;
-        let (_, next_partition_info) = get_partition_info(&self.executor)?;
-        let img_manager =
-            UpgradeImageManager::new(self.paths.clone(), next_partition_info, self.executor.clone(), false);
-        img_manager.create_os_image(IMAGE_PERMISSION)
+        let step = self.checkpoint.resume_step(req);
+        if step >= Some(PrepareStep::ImageBuilt) && is_image_built(&self.paths, false) {
+            info!("Resume with the built image {}", self.paths.image_path.display());
+            return self.image_manager();
+        }
+        let tar_str = self.paths.tar_path.to_str().unwrap_or_default();
+        if step >= Some(PrepareStep::Downloaded) && is_file_exist(&self.paths.tar_path) {
+            info!("Resume with the downloaded tar {}", tar_str);
+            // keep the downloaded tar, only the image left by the interrupted build is removed
+            clean_env(&PathBuf::new(), &self.paths.mount_path, &self.paths.image_path)?;
+            if step == Some(PrepareStep::Downloaded) {
+                self.checksum_match(tar_str, &req.check_sum)?;
+                self.checkpoint.save(req, PrepareStep::Verified);
+            }
+        } else {
+            self.checkpoint.clear();
+            clean_env(&self.paths.update_path, &self.paths.mount_path, &self.paths.image_path)?;
+            fs::DirBuilder::new().recursive(true).mode(IMAGE_PERMISSION).create(&self.paths.mount_path)?;
+            let start = Instant::now();
+            let result = self.download(req).and_then(|_| {
+                self.checkpoint.save(req, PrepareStep::Downloaded);
+                self.checksum_match(tar_str, &req.check_sum)
+            });
+            DOWNLOAD_DURATION.observe_since(&[result_label(&result)], start);
+            result?;
+            self.checkpoint.save(req, PrepareStep::Verified);
+        }
+        let img_manager = self.image_manager()?.create_os_image(IMAGE_PERMISSION)?;
+        self.checkpoint.save(req, PrepareStep::ImageBuilt);
+        Ok(img_manager)
     }
 }
 
@@ -47,6 +72,8 @@ impl Default for DiskImageHandler<RealCommandExecutor> {
             executor: RealCommandExecutor {},
             certs_path: CERTS_PATH.to_string(),
             dmv: false,
+            progress: ProgressTracker::default(),
+            checkpoint: Checkpoint::default(),
         }
     }
 }
@@ -54,7 +81,20 @@ impl Default for DiskImageHandler<RealCommandExecutor> {
 impl<T: CommandExecutor> DiskImageHandler<T> {
     #[cfg(test)]
     pub fn new(paths: PreparePath, executor: T, certs_path: String, dmv: bool) -> Self {
-        Self { paths, executor, certs_path, dmv }
+        Self {
+            paths,
+            executor,
+            certs_path,
+            dmv,
+            progress: ProgressTracker::default(),
+            checkpoint: Checkpoint::default(),
+        }
+    }
+
+    fn image_manager(&self) -> Result<UpgradeImageManager<T>> {
+        let (_, next_partition_info) = get_partition_info(&self.executor)?;
+        Ok(UpgradeImageManager::new(self.paths.clone(), next_partition_info, self.executor.clone(), false)
+            .with_progress(self.progress.clone()))
     }
 
     fn download(&self, req: &UpgradeRequest) -> Result<()> {
@@ -63,6 +103,7 @@ impl<T: CommandExecutor> DiskImageHandler<T> {
             bail!("Failed to download upgrade tar from {}, status: {}", req.image_url, resp.status());
         }
         debug!("Received response body size: {:?}", resp.content_length().unwrap_or_default());
+        self.progress.set_stage(UpgradeStage::Download, resp.content_length().unwrap_or_default())?;
         let need_bytes = resp.content_length().unwrap_or_default() + BUFFER;
 
         check_disk_size(
@@ -74,7 +115,8 @@ impl<T: CommandExecutor> DiskImageHandler<T> {
         let mut out = fs::File::create(dst)?;
         trace!("Start to save upgrade tar to path {}", dst.display());
         out.set_permissions(fs::Permissions::from_mode(IMAGE_PERMISSION))?;
-        let bytes = resp.copy_to(&mut out)?;
+        let bytes = resp.copy_to(&mut ProgressWriter::new(&mut out, &self.progress))?;
+        DOWNLOAD_BYTES.add(&[], bytes);
         info!("Download upgrade tar successfully, upgrade tar path: {}, write bytes: {}", dst.display(), bytes);
         Ok(())
     }
@@ -82,9 +124,10 @@ impl<T: CommandExecutor> DiskImageHandler<T> {
     fn checksum_match(&self, file_path: &str, check_sum: &str) -> Result<()> {
         info!("Start checking file checksum");
         let check_sum = check_sum.to_ascii_lowercase();
-        let file = fs::read(file_path)?;
+        let mut file = fs::File::open(file_path)?;
+        self.progress.set_stage(UpgradeStage::Verify, file.metadata()?.len())?;
         let mut hasher = Sha256::new();
-        hasher.update(file);
+        io::copy(&mut file, &mut ProgressWriter::new(&mut hasher, &self.progress))?;
         let hash = hasher.finalize();
         // sha256sum -b /persist/update.img
         let cal_sum = format!("{:X}", hash).to_ascii_lowercase();
@@ -97,6 +140,12 @@ impl<T: CommandExecutor> DiskImageHandler<T> {
     }
 
     fn send_download_request(&self, req: &UpgradeRequest) -> Result<reqwest::blocking::Response> {
+        let client = self.client(req)?;
+        client.get(&req.image_url).send().with_context(|| format!("Failed to fetch from URL: {}", &req.image_url))
+    }
+
+    /// Build the client to download the image, with the certificates required by the URL loaded
+    pub(crate) fn client(&self, req: &UpgradeRequest) -> Result<Client> {
         let client: Client;
 
         if !req.image_url.starts_with("https://") {
@@ -115,8 +164,7 @@ impl<T: CommandExecutor> DiskImageHandler<T> {
             client = self.load_ca_certs(&req.certs.ca_cert).with_context(|| "Failed to load CA certificates")?;
             info!("Discover https request to: {}", &req.image_url);
         }
-
-        client.get(&req.image_url).send().with_context(|| format!("Failed to fetch from URL: {}", &req.image_url))
+        Ok(client)
     }
 
     fn load_ca_certs(&self, ca_cert: &str) -> Result<Client> {
@@ -156,8 +204,7 @@ impl<T: CommandExecutor> DiskImageHandler<T> {
     }
 
     fn get_certs_path(&self, cert: &str) -> PathBuf {
-        let cert_path = format!("{}{}", self.certs_path, cert);
-        PathBuf::from(cert_path)
+        Path::new(&self.certs_path).join(cert)
     }
 }
 
@@ -206,12 +253,49 @@ mod tests {
         assert!(handler.download_image(&req).is_err());
     }
 
+    #[test]
+    fn test_resume_built_image() {
+        init();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut mock_executor = MockCommandExec::new();
+        mock_executor
+            .expect_run_command_with_output()
+            .withf(|cmd, _| cmd == "findmnt")
+            .times(1)
+            .returning(|_, _| Ok("/dev/sda2".to_string()));
+        mock_executor
+            .expect_run_command_with_output()
+            .withf(|cmd, _| cmd == "lsblk")
+            .times(1)
+            .returning(|_, _| Ok("ext4 1024".to_string()));
+        mock_executor.expect_clone().returning(MockCommandExec::new);
+        let mut handler = DiskImageHandler::new(PreparePath::new(tmp_dir.path()), mock_executor, String::new(), false);
+        handler.checkpoint = Checkpoint::new(tmp_dir.path().join("prepare-state.json"));
+        let req = UpgradeRequest {
+            version: "v2".into(),
+            check_sum: "1327e27d600538354d93bd68cce86566dd089e240c126dc3019cafabdc65aa02".into(),
+            image_type: "disk".into(),
+            container_image: "".into(),
+            image_url: "http://localhost:1/os.tar".to_string(),
+            flag_safe: true,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        };
+        fs::write(&handler.paths.image_path, "").unwrap();
+        handler.checkpoint.save(&req, PrepareStep::ImageBuilt);
+        // neither downloaded nor built again
+        let image_manager = handler.download_image(&req).unwrap();
+        assert_eq!(image_manager.next_partition.device, "/dev/sda3");
+    }
+
     #[test]
     fn test_get_certs_path() {
         init();
         let handler = DiskImageHandler::<RealCommandExecutor>::default();
         let certs_path = handler.get_certs_path("ca.pem");
         assert_eq!(certs_path.to_str().unwrap(), "/etc/KubeOS/certs/ca.pem");
+        let handler = DiskImageHandler { certs_path: "/etc/certs".to_string(), ..Default::default() };
+        assert_eq!(handler.get_certs_path("ca.pem").to_str().unwrap(), "/etc/certs/ca.pem");
     }
 
     #[test]
@@ -420,6 +504,8 @@ mod tests {
             .with_body("This is a test txt file for KubeOS test.\n")
             .create();
         handler.download(&upgrade_request).unwrap();
+        assert_eq!(handler.progress.get().stage, UpgradeStage::Download);
+        assert_eq!(handler.progress.get().current_bytes, 41);
         assert_eq!(true, handler.paths.tar_path.exists());
         assert_eq!(
             fs::read(handler.paths.tar_path.to_str().unwrap()).unwrap(),
@@ -2,7 +2,7 @@ use anyhow::{Context, Result};
 use log::{debug, info, trace};
 
 use crate::{
-    api::{ImageHandler, UpgradeRequest},
+    api::{ImageHandler, UpgradeRequest, UpgradeStage},
     sys_mgmt::{DMV_BOOT_IMG, DMV_HASH_IMG, DMV_ROOT_IMG, IMAGE_PERMISSION, NEED_BYTES},
     utils::*,
 };
@@ -12,26 +12,28 @@ pub struct DockerImageHandler<T: CommandExecutor> {
     pub container_name: String,
     pub executor: T,
     pub dmv: bool,
+    pub need_bytes: i64,
+    pub progress: ProgressTracker,
+    pub checkpoint: Checkpoint,
 }
 
 impl<T: CommandExecutor> ImageHandler<T> for DockerImageHandler<T> {
     fn download_image(&self, req: &UpgradeRequest) -> Result<UpgradeImageManager<T>> {
-        perpare_env(&self.paths, NEED_BYTES, IMAGE_PERMISSION)?;
-        self.get_image(req)?;
+        let step = self.checkpoint.resume_step(req);
+        if step >= Some(PrepareStep::ImageBuilt) && is_image_built(&self.paths, self.dmv) {
+            info!("Resume with the image built from {}", req.container_image);
+            return self.image_manager();
+        }
+        perpare_env(&self.paths, self.need_bytes, IMAGE_PERMISSION)?;
+        self.get_image(req, step)?;
         self.get_rootfs_archive(req)?;
 
-        if self.dmv {
-            return Ok(UpgradeImageManager::new(
-                self.paths.clone(),
-                PartitionInfo::default(),
-                self.executor.clone(),
-                self.dmv,
-            ));
+        let mut img_manager = self.image_manager()?;
+        if !self.dmv {
+            img_manager = img_manager.create_os_image(IMAGE_PERMISSION)?;
         }
-        let (_, next_partition_info) = get_partition_info(&self.executor)?;
-        let img_manager =
-            UpgradeImageManager::new(self.paths.clone(), next_partition_info, self.executor.clone(), false);
-        img_manager.create_os_image(IMAGE_PERMISSION)
+        self.checkpoint.save(req, PrepareStep::ImageBuilt);
+        Ok(img_manager)
     }
 }
 
@@ -42,6 +44,9 @@ impl Default for DockerImageHandler<RealCommandExecutor> {
             container_name: "kubeos-temp".into(),
             executor: RealCommandExecutor {},
             dmv: false,
+            need_bytes: NEED_BYTES,
+            progress: ProgressTracker::default(),
+            checkpoint: Checkpoint::default(),
         }
     }
 }
@@ -49,18 +54,47 @@ impl Default for DockerImageHandler<RealCommandExecutor> {
 impl<T: CommandExecutor> DockerImageHandler<T> {
     #[cfg(test)]
     pub fn new(paths: PreparePath, container_name: String, executor: T, dmv: bool) -> Self {
-        Self { paths, container_name, executor, dmv }
+        Self {
+            paths,
+            container_name,
+            executor,
+            dmv,
+            need_bytes: NEED_BYTES,
+            progress: ProgressTracker::default(),
+            checkpoint: Checkpoint::default(),
+        }
     }
 
-    fn get_image(&self, req: &UpgradeRequest) -> Result<()> {
+    fn image_manager(&self) -> Result<UpgradeImageManager<T>> {
+        // the partitions of dm-verity mode are installed by kubeos-dmv
+        let next_partition_info =
+            if self.dmv { PartitionInfo::default() } else { get_partition_info(&self.executor)?.1 };
+        Ok(UpgradeImageManager::new(self.paths.clone(), next_partition_info, self.executor.clone(), self.dmv)
+            .with_progress(self.progress.clone()))
+    }
+
+    // get_image pulls the image and checks its digest, the image pulled by the interrupted preparation is reused
+    fn get_image(&self, req: &UpgradeRequest, step: Option<PrepareStep>) -> Result<()> {
         let image_name = &req.container_image;
         is_valid_image_name(image_name)?;
         let cli = "docker";
+        if step >= Some(PrepareStep::Downloaded)
+            && check_oci_image_digest(cli, image_name, &req.check_sum, &self.executor).is_ok()
+        {
+            info!("Resume with the pulled image {}", image_name);
+            self.checkpoint.save(req, PrepareStep::Verified);
+            return Ok(());
+        }
+        self.checkpoint.clear();
         remove_image_if_exist(cli, image_name, &self.executor)?;
         info!("Start pulling image {}", image_name);
-        pull_image(cli, image_name, &self.executor)?;
+        self.progress.set_stage(UpgradeStage::Download, 0)?;
+        pull_image(cli, image_name, &self.executor, &self.progress)?;
+        self.checkpoint.save(req, PrepareStep::Downloaded);
         info!("Start checking image digest");
+        self.progress.set_stage(UpgradeStage::Verify, 0)?;
         check_oci_image_digest(cli, image_name, &req.check_sum, &self.executor)?;
+        self.checkpoint.save(req, PrepareStep::Verified);
         Ok(())
     }
 
@@ -93,30 +127,34 @@ impl<T: CommandExecutor> DockerImageHandler<T> {
 
     fn normal_copy_upgrade_files(&self, container_id: &str) -> Result<()> {
         debug!("Copy rootfs from container {} to {}", container_id, self.paths.update_path.display());
-        self.executor.run_command(
+        self.executor.run_command_cancellable(
             "docker",
             &[
                 "cp",
                 format!("{}:/{}", container_id, self.paths.rootfs_file).as_str(),
                 self.paths.update_path.to_str().unwrap(),
             ],
+            &self.progress,
         )?;
         Ok(())
     }
 
     fn dmv_copy_upgrade_files(&self, container_id: &str) -> Result<()> {
         debug!("Copy dm-verity upgrade files from container {} to {}", container_id, self.paths.persist_path.display());
-        self.executor.run_command(
+        self.executor.run_command_cancellable(
             "docker",
             &["cp", format!("{}:/{}", container_id, DMV_BOOT_IMG).as_str(), self.paths.update_path.to_str().unwrap()],
+            &self.progress,
         )?;
-        self.executor.run_command(
+        self.executor.run_command_cancellable(
             "docker",
             &["cp", format!("{}:/{}", container_id, DMV_ROOT_IMG).as_str(), self.paths.persist_path.to_str().unwrap()],
+            &self.progress,
         )?;
-        self.executor.run_command(
+        self.executor.run_command_cancellable(
             "docker",
             &["cp", format!("{}:/{}", container_id, DMV_HASH_IMG).as_str(), self.paths.persist_path.to_str().unwrap()],
+            &self.progress,
         )?;
         Ok(())
     }
@@ -218,7 +256,7 @@ mod tests {
             .returning(|_, _| Ok(command_output2.to_string()));
 
         let docker = DockerImageHandler::new(PreparePath::default(), "kubeos-temp".into(), mock_executor, false);
-        let result = docker.get_image(&req);
+        let result = docker.get_image(&req, None);
         assert!(result.is_ok());
     }
 
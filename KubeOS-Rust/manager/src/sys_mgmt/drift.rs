@@ -0,0 +1,227 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+use super::{
+    config::{display_value, ConfigTemplate},
+    values::{GRUB_CMDLINE_CURRENT, GRUB_CMDLINE_NEXT},
+};
+use crate::{
+    api::{ConfigDrift, Sysconfig},
+    utils::write_file_atomically,
+};
+
+/// AppliedConfigStore keeps the configs applied by the successful configure requests in a json file on the persist
+/// partition, merged by model and config path so that a key holds the value configured last
+#[derive(Debug, Clone)]
+pub struct AppliedConfigStore {
+    path: PathBuf,
+}
+
+impl AppliedConfigStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        AppliedConfigStore { path: path.as_ref().to_path_buf() }
+    }
+
+    /// Read the applied configs, nothing is applied if the file doesn't exist
+    pub fn load(&self) -> Result<Vec<Sysconfig>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read applied configs {}", self.path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse applied configs {}", self.path.display()))
+    }
+
+    /// Merge the configs of a successful configure request into the applied configs. grub.cmdline models are not
+    /// recorded, as the partition they configure changes with upgrades and rollbacks.
+    pub fn record(&self, template: &ConfigTemplate, configs: &[Sysconfig]) -> Result<()> {
+        let mut applied = self.load()?;
+        for config in configs {
+            if config.model == GRUB_CMDLINE_CURRENT || config.model == GRUB_CMDLINE_NEXT {
+                continue;
+            }
+            let Some(configuration) = template.get(&config.model) else {
+                continue;
+            };
+            let index =
+                match applied.iter().position(|a| a.model == config.model && a.config_path == config.config_path) {
+                    Some(index) => index,
+                    None => {
+                        applied.push(Sysconfig { contents: Default::default(), ..config.clone() });
+                        applied.len() - 1
+                    },
+                };
+            for (key, key_info) in config.contents.iter() {
+                if key.is_empty() {
+                    continue;
+                }
+                // the deleted keys of the models changing the running system have no value to compare with, so they
+                // are no longer checked
+                if key_info.operation == "delete" && configuration.changes_runtime() {
+                    applied[index].contents.remove(key);
+                    continue;
+                }
+                applied[index].contents.insert(key.clone(), key_info.clone());
+            }
+        }
+        applied.sort_by(|a, b| (&a.model, &a.config_path).cmp(&(&b.model, &b.config_path)));
+        debug!("Record applied configs: {:?}", applied);
+        write_file_atomically(&self.path, serde_json::to_string_pretty(&applied)?.as_bytes(), 0o600)
+            .with_context(|| format!("Failed to write applied configs {}", self.path.display()))
+    }
+}
+
+/// Compare the applied configs with the current values read back by the models, the models which can't be read are
+/// skipped with a warning
+pub fn check_drift(template: &ConfigTemplate, store: &AppliedConfigStore) -> Result<Vec<ConfigDrift>> {
+    let mut drifts = Vec::new();
+    for config in store.load()? {
+        let Some(configuration) = template.get(&config.model) else {
+            continue;
+        };
+        let mut keys: Vec<String> = config.contents.keys().cloned().collect();
+        keys.sort();
+        let current = match configuration.get_config(&config.config_path, &keys) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Failed to check the drift of {}: {:#}", config.model, e);
+                continue;
+            },
+        };
+        for key in keys {
+            let expected = &config.contents[&key];
+            let actual = current.get(&key).map(|key_info| &key_info.value);
+            let drifted = match actual {
+                Some(_) if expected.operation == "delete" => true,
+                Some(actual) => !configuration.value_matches(&expected.value, actual),
+                None => expected.operation != "delete",
+            };
+            if drifted {
+                drifts.push(ConfigDrift {
+                    model: config.model.clone(),
+                    config_path: config.config_path.clone(),
+                    expected: (expected.operation != "delete").then(|| display_value(&expected.value)),
+                    actual: actual.map(display_value),
+                    key,
+                });
+            }
+        }
+    }
+    Ok(drifts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        api::KeyInfo,
+        sys_mgmt::{
+            ContainerContainerd, GrubCmdline, KernelSysctl, KernelSysctlPersist, PamLimits, CONTAINER_CONTAINERD,
+            KERNEL_SYSCTL, KERNEL_SYSCTL_PERSIST, PAM_LIMTS,
+        },
+    };
+
+    #[test]
+    fn test_check_drift() {
+        let tmp_dir = TempDir::new().unwrap();
+        let proc_path = format!("{}/", tmp_dir.path().to_str().unwrap());
+        let sysctl_conf = tmp_dir.path().join("sysctl.conf").to_str().unwrap().to_string();
+        let containerd_conf = tmp_dir.path().join("config.toml").to_str().unwrap().to_string();
+        let limits_conf = tmp_dir.path().join("limits.conf").to_str().unwrap().to_string();
+        fs::write(tmp_dir.path().join("a"), "1\t2\n").unwrap();
+        fs::write(&sysctl_conf, "a=1\nc=3\n").unwrap();
+        fs::write(&containerd_conf, "[grpc]\nuid = 1\n[plugins]\nmirrors = [\"a\", \"b\"]\n").unwrap();
+        fs::write(&limits_conf, "* soft nofile 2048\n").unwrap();
+
+        let mut template: ConfigTemplate = HashMap::new();
+        template.insert(KERNEL_SYSCTL.to_string(), Box::new(KernelSysctl { proc_path }));
+        template.insert(KERNEL_SYSCTL_PERSIST.to_string(), Box::new(KernelSysctlPersist));
+        template.insert(CONTAINER_CONTAINERD.to_string(), Box::new(ContainerContainerd));
+        template.insert(PAM_LIMTS.to_string(), Box::new(PamLimits { config_path: limits_conf }));
+        template.insert(
+            GRUB_CMDLINE_CURRENT.to_string(),
+            Box::new(GrubCmdline { grub_path: "".to_string(), is_cur_partition: true }),
+        );
+        let config = |model: &str, config_path: &str, contents: &[(&str, serde_json::Value, &str)]| Sysconfig {
+            model: model.to_string(),
+            config_path: config_path.to_string(),
+            contents: contents
+                .iter()
+                .map(|(k, v, op)| (k.to_string(), KeyInfo { value: v.clone(), operation: op.to_string() }))
+                .collect(),
+        };
+        let store = AppliedConfigStore::new(tmp_dir.path().join("os-agent/applied-config.json"));
+        assert!(check_drift(&template, &store).unwrap().is_empty());
+        store
+            .record(
+                &template,
+                &[
+                    config(KERNEL_SYSCTL, "", &[("a", json!("1 2"), ""), ("b", json!(""), "delete")]),
+                    config(KERNEL_SYSCTL_PERSIST, &sysctl_conf, &[("a", json!(0), ""), ("b", json!(2), "")]),
+                    config(GRUB_CMDLINE_CURRENT, "", &[("quiet", json!(""), "")]),
+                ],
+            )
+            .unwrap();
+        store
+            .record(
+                &template,
+                &[
+                    config(KERNEL_SYSCTL_PERSIST, &sysctl_conf, &[("a", json!(1), ""), ("c", json!(""), "delete")]),
+                    config(
+                        CONTAINER_CONTAINERD,
+                        &containerd_conf,
+                        &[("grpc.uid", json!(1), ""), ("plugins.mirrors", json!(["b"]), "")],
+                    ),
+                    config(PAM_LIMTS, "", &[("*", json!("_._.2048"), "")]),
+                ],
+            )
+            .unwrap();
+        let applied = store.load().unwrap();
+        assert_eq!(applied.len(), 4);
+        assert_eq!(applied[1].model, KERNEL_SYSCTL);
+        assert_eq!(applied[1].contents.len(), 1);
+        assert_eq!(applied[2].contents.len(), 3);
+
+        let drift = |key: &str, expected: Option<&str>, actual: Option<&str>| ConfigDrift {
+            model: KERNEL_SYSCTL_PERSIST.to_string(),
+            config_path: sysctl_conf.clone(),
+            key: key.to_string(),
+            expected: expected.map(|v| v.to_string()),
+            actual: actual.map(|v| v.to_string()),
+        };
+        assert_eq!(
+            check_drift(&template, &store).unwrap(),
+            vec![drift("b", Some("2"), None), drift("c", None, Some("3"))]
+        );
+
+        fs::write(&sysctl_conf, "a=1\nb=2\n").unwrap();
+        fs::write(tmp_dir.path().join("a"), "0\n").unwrap();
+        let drifts = check_drift(&template, &store).unwrap();
+        assert_eq!(drifts.len(), 1);
+        assert_eq!(drifts[0].model, KERNEL_SYSCTL);
+        assert_eq!(drifts[0].actual, Some("0".to_string()));
+    }
+}
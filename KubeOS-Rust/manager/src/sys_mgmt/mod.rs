@@ -11,13 +11,21 @@
  */
 
 mod config;
+mod config_transaction;
 mod containerd_image;
+mod diagnostics;
 mod disk_image;
 mod docker_image;
+mod drift;
+mod preflight;
 mod values;
 
 pub use config::*;
+pub use config_transaction::*;
 pub use containerd_image::*;
+pub use diagnostics::*;
 pub use disk_image::*;
 pub use docker_image::*;
+pub use drift::*;
+pub use preflight::*;
 pub use values::*;
@@ -0,0 +1,337 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use reqwest::{blocking::Client, header::CONTENT_LENGTH};
+
+use super::disk_image::BUFFER;
+use crate::{
+    api::{
+        PreflightFailure, UpgradeRequest, CHECK_CERTIFICATES, CHECK_DISK_SPACE, CHECK_IMAGE_SOURCE, CHECK_IMAGE_TYPE,
+        CHECK_PARTITION, CHECK_RUNTIME, CHECK_VERSION, IMAGE_TYPE_CONTAINERD, IMAGE_TYPE_DISK, IMAGE_TYPE_DOCKER,
+    },
+    sys_mgmt::{DiskImageHandler, CERTS_PATH, NEED_BYTES, OS_RELEASE_PATH},
+    utils::*,
+};
+
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+const REACHABLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Preflight checks whether an upgrade request can be prepared on this node without changing anything, so that the
+/// node is not drained for an upgrade bound to fail
+pub struct Preflight<T: CommandExecutor> {
+    pub paths: PreparePath,
+    pub executor: T,
+    pub certs_path: String,
+    pub need_bytes: i64,
+    pub dmv: bool,
+    pub os_release_path: String,
+}
+
+impl Default for Preflight<RealCommandExecutor> {
+    fn default() -> Self {
+        Self {
+            paths: PreparePath::default(),
+            executor: RealCommandExecutor {},
+            certs_path: CERTS_PATH.to_string(),
+            need_bytes: NEED_BYTES,
+            dmv: false,
+            os_release_path: OS_RELEASE_PATH.to_string(),
+        }
+    }
+}
+
+impl<T: CommandExecutor> Preflight<T> {
+    /// Run all the checks, returns the failed ones
+    pub fn check(&self, req: &UpgradeRequest) -> Vec<PreflightFailure> {
+        let mut failures = Vec::new();
+        record(&mut failures, CHECK_VERSION, self.check_version(&req.version));
+        // kubeos-dmv switches the partitions in dm-verity mode
+        let next_partition =
+            if self.dmv { None } else { record(&mut failures, CHECK_PARTITION, self.check_next_partition()) };
+        match req.image_type.as_str() {
+            IMAGE_TYPE_CONTAINERD | IMAGE_TYPE_DOCKER => {
+                let runtime = if req.image_type == IMAGE_TYPE_DOCKER { "docker" } else { "ctr" };
+                let available = if is_command_available(runtime, &self.executor) {
+                    Ok(())
+                } else {
+                    Err(anyhow!("{} is not available", runtime))
+                };
+                record(&mut failures, CHECK_RUNTIME, available);
+                record(&mut failures, CHECK_IMAGE_SOURCE, check_registry(&req.container_image));
+                // the size of a container image is unknown before pulling it
+                record(&mut failures, CHECK_DISK_SPACE, self.check_space(self.need_bytes));
+            },
+            IMAGE_TYPE_DISK if self.dmv => {
+                record::<()>(
+                    &mut failures,
+                    CHECK_IMAGE_TYPE,
+                    Err(anyhow!("DM-Verity doesn't support disk image upgrade")),
+                );
+            },
+            IMAGE_TYPE_DISK => {
+                let handler = DiskImageHandler {
+                    paths: self.paths.clone(),
+                    executor: self.executor.clone(),
+                    certs_path: self.certs_path.clone(),
+                    dmv: self.dmv,
+                    progress: ProgressTracker::default(),
+                    checkpoint: Checkpoint::default(),
+                };
+                let check = if req.image_url.starts_with("https://") { CHECK_CERTIFICATES } else { CHECK_IMAGE_SOURCE };
+                let Some(client) = record(&mut failures, check, handler.client(req)) else {
+                    return failures;
+                };
+                let size = record(&mut failures, CHECK_IMAGE_SOURCE, image_size(&client, &req.image_url));
+                // the downloaded tar is written into an image as large as the next partition
+                let partition_size = next_partition.map(|p| p.size).unwrap_or_default();
+                if let Some(Some(size)) = size {
+                    let need_bytes =
+                        i64::try_from(size.saturating_add(BUFFER)).unwrap_or(i64::MAX).saturating_add(partition_size);
+                    record(&mut failures, CHECK_DISK_SPACE, self.check_space(need_bytes));
+                } else if size.is_some() {
+                    record(&mut failures, CHECK_DISK_SPACE, self.check_space(self.need_bytes));
+                }
+            },
+            _ => {
+                record::<()>(
+                    &mut failures,
+                    CHECK_IMAGE_TYPE,
+                    Err(anyhow!("Invalid image type \"{}\"", req.image_type)),
+                );
+            },
+        }
+        failures
+    }
+
+    fn check_version(&self, version: &str) -> Result<()> {
+        let current_version = get_os_version(&self.os_release_path)?;
+        if current_version == version {
+            bail!("Version {} is running already", version);
+        }
+        Ok(())
+    }
+
+    fn check_next_partition(&self) -> Result<PartitionInfo> {
+        let (_, next_partition) = get_partition_info(&self.executor)?;
+        let lsblk = self
+            .executor
+            .run_command_with_output("lsblk", &["-blno", "FSTYPE,SIZE", &next_partition.device])
+            .with_context(|| format!("Next partition {} does not exist", next_partition.device))?;
+        let fs_type = lsblk.split_whitespace().next().unwrap_or_default();
+        if fs_type != next_partition.fs_type {
+            bail!(
+                "File system \"{}\" of next partition {} mismatches \"{}\" of current partition",
+                fs_type,
+                next_partition.device,
+                next_partition.fs_type
+            );
+        }
+        Ok(next_partition)
+    }
+
+    fn check_space(&self, need_bytes: i64) -> Result<()> {
+        let available = get_available_space(&self.paths.persist_path)?;
+        if available < need_bytes {
+            bail!(
+                "{} bytes available in {}, {} bytes required",
+                available,
+                self.paths.persist_path.display(),
+                need_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+fn record<R>(failures: &mut Vec<PreflightFailure>, check: &str, result: Result<R>) -> Option<R> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            debug!("Preflight check {} failed: {:#}", check, e);
+            failures.push(PreflightFailure { check: check.to_string(), message: format!("{:#}", e) });
+            None
+        },
+    }
+}
+
+// image_size returns the size of the image given by the server, None if the server doesn't tell
+fn image_size(client: &Client, url: &str) -> Result<Option<u64>> {
+    let resp =
+        client.head(url).timeout(REACHABLE_TIMEOUT).send().with_context(|| format!("Failed to reach {}", url))?;
+    if !resp.status().is_success() {
+        bail!("{} returned {}", url, resp.status());
+    }
+    Ok(resp.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()))
+}
+
+fn check_registry(image: &str) -> Result<()> {
+    is_valid_image_name(image)?;
+    let registry = registry_of(image);
+    let address = if registry.contains(':') { registry.to_string() } else { format!("{}:443", registry) };
+    let addrs = address.to_socket_addrs().with_context(|| format!("Failed to resolve registry {}", registry))?;
+    for addr in addrs {
+        if TcpStream::connect_timeout(&addr, REACHABLE_TIMEOUT).is_ok() {
+            return Ok(());
+        }
+    }
+    bail!("Registry {} is unreachable", address)
+}
+
+// registry_of returns the registry of the image, the first component of the name is a registry only if it looks like
+// a host, otherwise the image is from Docker Hub
+fn registry_of(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => first,
+        _ => DOCKER_HUB_REGISTRY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::TcpListener};
+
+    use mockall::mock;
+
+    use super::*;
+    use crate::api::CertsInfo;
+
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    fn request(image_type: &str, container_image: &str, image_url: &str) -> UpgradeRequest {
+        UpgradeRequest {
+            version: "KubeOS v2".to_string(),
+            image_type: image_type.to_string(),
+            check_sum: "".to_string(),
+            container_image: container_image.to_string(),
+            image_url: image_url.to_string(),
+            flag_safe: true,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        }
+    }
+
+    fn mock_executor(next_fs_type: &'static str, runtime_available: bool) -> MockCommandExec {
+        let mut executor = MockCommandExec::new();
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, _| name == "findmnt")
+            .returning(|_, _| Ok("/dev/vda2".to_string()));
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, args| name == "lsblk" && args[2] == "/dev/vda2")
+            .returning(|_, _| Ok("ext4 1024".to_string()));
+        executor
+            .expect_run_command_with_output()
+            .withf(|name, args| name == "lsblk" && args[2] == "/dev/vda3")
+            .returning(move |_, _| Ok(format!("{} 1024", next_fs_type)));
+        executor.expect_run_command().returning(
+            move |_, _| {
+                if runtime_available {
+                    Ok(())
+                } else {
+                    bail!("not found")
+                }
+            },
+        );
+        executor.expect_clone().returning(|| mock_executor("ext4", true));
+        executor
+    }
+
+    fn checks(failures: &[PreflightFailure]) -> Vec<&str> {
+        failures.iter().map(|f| f.check.as_str()).collect()
+    }
+
+    #[test]
+    fn test_preflight() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let os_release = tmp_dir.path().join("os-release");
+        fs::write(&os_release, "PRETTY_NAME=\"KubeOS v1\"\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let registry = listener.local_addr().unwrap();
+        let preflight = |executor: MockCommandExec, need_bytes: i64| Preflight {
+            paths: PreparePath::new(tmp_dir.path()),
+            executor,
+            certs_path: tmp_dir.path().to_str().unwrap().to_string(),
+            need_bytes,
+            dmv: false,
+            os_release_path: os_release.to_str().unwrap().to_string(),
+        };
+
+        let image = format!("{}/kubeos/upgrade:v2", registry);
+        let failures = preflight(mock_executor("ext4", true), 1).check(&request("containerd", &image, ""));
+        assert_eq!(failures, vec![]);
+
+        let mut req = request("docker", "127.0.0.1:1/kubeos/upgrade:v2", "");
+        req.version = "KubeOS v1".to_string();
+        let failures = preflight(mock_executor("xfs", false), i64::MAX).check(&req);
+        assert_eq!(
+            checks(&failures),
+            vec![CHECK_VERSION, CHECK_PARTITION, CHECK_RUNTIME, CHECK_IMAGE_SOURCE, CHECK_DISK_SPACE]
+        );
+
+        let failures = preflight(mock_executor("ext4", true), 1).check(&request("invalid", "", ""));
+        assert_eq!(checks(&failures), vec![CHECK_IMAGE_TYPE]);
+    }
+
+    #[test]
+    fn test_preflight_disk() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let os_release = tmp_dir.path().join("os-release");
+        fs::write(&os_release, "PRETTY_NAME=\"KubeOS v1\"\n").unwrap();
+        let preflight = Preflight {
+            paths: PreparePath::new(tmp_dir.path()),
+            executor: mock_executor("ext4", true),
+            certs_path: tmp_dir.path().to_str().unwrap().to_string(),
+            need_bytes: 1,
+            dmv: false,
+            os_release_path: os_release.to_str().unwrap().to_string(),
+        };
+        let _small =
+            mockito::mock("HEAD", "/small.tar").with_status(200).with_header("content-length", "1024").create();
+        let _huge = mockito::mock("HEAD", "/huge.tar")
+            .with_status(200)
+            .with_header("content-length", &u64::MAX.to_string())
+            .create();
+        let url = |path: &str| format!("{}/{}", mockito::server_url(), path);
+
+        assert_eq!(preflight.check(&request("disk", "", &url("small.tar"))), vec![]);
+        assert_eq!(checks(&preflight.check(&request("disk", "", &url("huge.tar")))), vec![CHECK_DISK_SPACE]);
+        assert_eq!(checks(&preflight.check(&request("disk", "", &url("missing.tar")))), vec![CHECK_IMAGE_SOURCE]);
+        let failures = preflight.check(&request("disk", "", "https://127.0.0.1/os.tar"));
+        assert_eq!(checks(&failures), vec![CHECK_CERTIFICATES]);
+    }
+
+    #[test]
+    fn test_registry_of() {
+        assert_eq!(registry_of("docker.io/library/busybox:latest"), "docker.io");
+        assert_eq!(registry_of("localhost/kubeos:v2"), "localhost");
+        assert_eq!(registry_of("192.168.1.1:5000/kubeos:v2"), "192.168.1.1:5000");
+        assert_eq!(registry_of("kubeos/upgrade:v2"), DOCKER_HUB_REGISTRY);
+        assert_eq!(registry_of("busybox"), DOCKER_HUB_REGISTRY);
+    }
+}
@@ -12,11 +12,13 @@
 
 pub const KERNEL_SYSCTL: &str = "kernel.sysctl";
 pub const KERNEL_SYSCTL_PERSIST: &str = "kernel.sysctl.persist";
+pub const KERNEL_MODULES: &str = "kernel.modules";
 pub const GRUB_CMDLINE_CURRENT: &str = "grub.cmdline.current";
 pub const GRUB_CMDLINE_NEXT: &str = "grub.cmdline.next";
 pub const KUBERNETES_KUBELET: &str = "kubernetes.kubelet";
 pub const CONTAINER_CONTAINERD: &str = "container.containerd";
 pub const PAM_LIMTS: &str = "pam.limits";
+pub const SYSTEMD_UNIT: &str = "systemd.unit";
 
 pub const DEFAULT_PROC_PATH: &str = "/proc/sys/";
 pub const DEFAULT_KERNEL_CONFIG_PATH: &str = "/etc/sysctl.conf";
@@ -25,13 +27,24 @@ pub const DEFAULT_GRUBENV_PATH: &str = "/boot/efi/EFI/openEuler/grubenv";
 pub const DEFAULT_KUBELET_CONFIG_PATH: &str = "/var/lib/kubelet/config.yaml";
 pub const DEFAULT_CONTAINERD_CONFIG_PATH: &str = "/etc/containerd/config.toml";
 pub const DEFAULT_PAM_LIMITS_PATH: &str = "/etc/security/limits.conf";
+pub const DEFAULT_MODULES_LOAD_PATH: &str = "/etc/modules-load.d/kubeos.conf";
+pub const DEFAULT_MODPROBE_PATH: &str = "/etc/modprobe.d/kubeos.conf";
+pub const DEFAULT_SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+/// The drop-in file of systemd.unit, the drop-ins of the other names under <unit>.d are left alone
+pub const SYSTEMD_DROPIN_FILE: &str = "50-kubeos.conf";
 
 pub const PERSIST_DIR: &str = "/persist";
 pub const ROOTFS_ARCHIVE: &str = "os.tar";
 pub const UPDATE_DIR: &str = "KubeOS-Update";
 pub const MOUNT_DIR: &str = "kubeos-update";
+pub const KEXEC_MOUNT_DIR: &str = "kubeos-kexec";
 pub const OS_IMAGE_NAME: &str = "update.img";
 pub const CERTS_PATH: &str = "/etc/KubeOS/certs/";
+pub const OS_RELEASE_PATH: &str = "/etc/os-release";
+pub const JOURNAL_FILE: &str = "os-agent/history.jsonl";
+pub const SLOTS_FILE: &str = "os-agent/slots.json";
+pub const PREPARE_STATE_FILE: &str = "os-agent/prepare-state.json";
+pub const APPLIED_CONFIG_FILE: &str = "os-agent/applied-config.json";
 
 pub const DMV_BOOT_IMG: &str = "update-boot.img";
 pub const DMV_ROOT_IMG: &str = "update-root.img";
@@ -45,3 +58,8 @@ pub const ONLY_KEY: usize = 1;
 pub const KV_PAIR: usize = 2;
 pub const PAM_LIMITS_KV: usize = 4;
 pub const NEED_BYTES: i64 = 3 * 1024 * 1024 * 1024;
+pub const MAX_JOURNAL_SIZE: u64 = 1024 * 1024;
+/// grub.cfg only counts down from at most 3 boot attempts
+pub const MAX_BOOT_ATTEMPTS: u32 = 3;
+pub const GRUBENV_BOOT_COUNTER: &str = "boot_counter";
+pub const GRUBENV_BOOT_FALLBACK: &str = "boot_fallback_entry";
@@ -0,0 +1,181 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::common::{delete_file_or_dir, is_file_exist, write_file_atomically, PreparePath};
+use crate::{
+    api::UpgradeRequest,
+    sys_mgmt::{DMV_BOOT_IMG, DMV_HASH_IMG, DMV_ROOT_IMG},
+};
+
+/// PrepareStep is a step of the upgrade preparation, in the order they complete
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum PrepareStep {
+    Downloaded,
+    Verified,
+    ImageBuilt,
+    Installed,
+}
+
+/// PrepareState is the last completed step of the preparation for the upgrade request
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PrepareState {
+    pub version: String,
+    pub check_sum: String,
+    pub image_type: String,
+    pub step: PrepareStep,
+}
+
+impl PrepareState {
+    fn is_for(&self, req: &UpgradeRequest) -> bool {
+        self.version == req.version && self.check_sum == req.check_sum && self.image_type == req.image_type
+    }
+}
+
+/// Checkpoint persists the progress of the upgrade preparation, so that a retry of the same request after os-agent
+/// restarts resumes from the last completed step. The default checkpoint is disabled and always starts from scratch.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    path: Option<PathBuf>,
+}
+
+impl Checkpoint {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Checkpoint { path: Some(path.as_ref().to_path_buf()) }
+    }
+
+    pub fn load(&self) -> Result<Option<PrepareState>> {
+        let Some(path) = self.path.as_ref().filter(|p| p.exists()) else {
+            return Ok(None);
+        };
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read prepare state {}", path.display()))?;
+        let state = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse prepare state {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// The last completed step of the preparation for the request, None if the preparation has to start from scratch
+    pub fn resume_step(&self, req: &UpgradeRequest) -> Option<PrepareStep> {
+        match self.load() {
+            Ok(Some(state)) if state.is_for(req) => {
+                info!("Resume preparing for upgrading to version {} after step {:?}", req.version, state.step);
+                Some(state.step)
+            },
+            Ok(_) => None,
+            Err(e) => {
+                warn!("{:#}", e);
+                None
+            },
+        }
+    }
+
+    /// Record the completed step, failing to record only loses the chance to resume
+    pub fn save(&self, req: &UpgradeRequest, step: PrepareStep) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let state = PrepareState {
+            version: req.version.clone(),
+            check_sum: req.check_sum.clone(),
+            image_type: req.image_type.clone(),
+            step,
+        };
+        let result = serde_json::to_vec(&state)
+            .map_err(|e| e.into())
+            .and_then(|content| write_file_atomically(path, &content, 0o600));
+        if let Err(e) = result {
+            warn!("Failed to record prepare state {}: {:#}", path.display(), e);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = delete_file_or_dir(path) {
+                warn!("Failed to clear prepare state {}: {:#}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Whether the image to install is left by the previous preparation
+pub fn is_image_built(paths: &PreparePath, dmv: bool) -> bool {
+    if dmv {
+        [DMV_BOOT_IMG, DMV_ROOT_IMG, DMV_HASH_IMG].iter().all(|img| is_file_exist(paths.persist_path.join(img)))
+    } else {
+        is_file_exist(&paths.image_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::CertsInfo;
+
+    fn request(version: &str) -> UpgradeRequest {
+        UpgradeRequest {
+            version: version.to_string(),
+            check_sum: "sha256".to_string(),
+            image_type: "disk".to_string(),
+            container_image: "".to_string(),
+            image_url: "http://localhost/os.tar".to_string(),
+            flag_safe: true,
+            mtls: false,
+            certs: CertsInfo { ca_cert: "".to_string(), client_cert: "".to_string(), client_key: "".to_string() },
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("os-agent/prepare-state.json");
+        let checkpoint = Checkpoint::new(&path);
+        assert_eq!(checkpoint.resume_step(&request("v2")), None);
+
+        checkpoint.save(&request("v2"), PrepareStep::Downloaded);
+        checkpoint.save(&request("v2"), PrepareStep::ImageBuilt);
+        assert_eq!(checkpoint.resume_step(&request("v2")), Some(PrepareStep::ImageBuilt));
+        assert_eq!(checkpoint.load().unwrap().unwrap().version, "v2");
+        // a different request starts from scratch
+        assert_eq!(checkpoint.resume_step(&request("v3")), None);
+
+        checkpoint.clear();
+        assert!(!path.exists());
+        fs::write(&path, "invalid").unwrap();
+        assert_eq!(checkpoint.resume_step(&request("v2")), None);
+
+        // disabled checkpoint
+        let checkpoint = Checkpoint::default();
+        checkpoint.save(&request("v2"), PrepareStep::Verified);
+        assert_eq!(checkpoint.resume_step(&request("v2")), None);
+        assert!(PrepareStep::Downloaded < PrepareStep::Installed);
+    }
+
+    #[test]
+    fn test_is_image_built() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let paths = PreparePath::new(tmp_dir.path());
+        assert!(!is_image_built(&paths, false));
+        fs::write(&paths.image_path, "").unwrap();
+        assert!(is_image_built(&paths, false));
+        fs::write(paths.persist_path.join(DMV_BOOT_IMG), "").unwrap();
+        assert!(!is_image_built(&paths, true));
+    }
+}
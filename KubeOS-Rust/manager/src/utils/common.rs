@@ -11,17 +11,29 @@
  */
 
 use std::{
-    fs,
-    os::{linux::fs::MetadataExt, unix::fs::DirBuilderExt},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        fd::AsRawFd,
+        linux::fs::MetadataExt,
+        unix::fs::{DirBuilderExt, OpenOptionsExt},
+    },
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, info, trace};
-use nix::{mount, mount::MntFlags};
+use nix::{
+    fcntl::{posix_fadvise, PosixFadviseAdvice},
+    mount,
+    mount::MntFlags,
+};
+use sha2::{Digest, Sha256};
 
 use crate::{
-    sys_mgmt::{MOUNT_DIR, OS_IMAGE_NAME, PERSIST_DIR, ROOTFS_ARCHIVE, UPDATE_DIR},
+    sys_mgmt::{
+        GRUBENV_BOOT_COUNTER, GRUBENV_BOOT_FALLBACK, MOUNT_DIR, OS_IMAGE_NAME, PERSIST_DIR, ROOTFS_ARCHIVE, UPDATE_DIR,
+    },
     utils::CommandExecutor,
 };
 
@@ -48,7 +60,14 @@ pub struct PreparePath {
 
 impl Default for PreparePath {
     fn default() -> Self {
-        let persist_dir = Path::new(PERSIST_DIR);
+        PreparePath::new(PERSIST_DIR)
+    }
+}
+
+impl PreparePath {
+    /// Build the paths under the given persist directory instead of /persist
+    pub fn new<P: AsRef<Path>>(persist_dir: P) -> Self {
+        let persist_dir = persist_dir.as_ref();
         let update_pathbuf = persist_dir.join(UPDATE_DIR);
         Self {
             persist_path: persist_dir.to_path_buf(),
@@ -75,10 +94,7 @@ pub fn perpare_env(prepare_path: &PreparePath, need_bytes: i64, permission: u32)
 
 pub fn check_disk_size<P: AsRef<Path>>(need_bytes: i64, path: P) -> Result<()> {
     trace!("Check if there is enough disk space to upgrade");
-    let fs_stat = nix::sys::statfs::statfs(path.as_ref())?;
-    let available_blocks = i64::try_from(fs_stat.blocks_available())?;
-    let available_space = available_blocks * fs_stat.block_size();
-    if available_space < need_bytes {
+    if get_available_space(path)? < need_bytes {
         bail!("Space is not enough for downloading");
     }
     Ok(())
@@ -101,6 +117,62 @@ where
     Ok(())
 }
 
+/// Bytes available to unprivileged users in the file system of the path
+pub fn get_available_space<P: AsRef<Path>>(path: P) -> Result<i64> {
+    let fs_stat = nix::sys::statfs::statfs(path.as_ref())?;
+    let available_blocks = i64::try_from(fs_stat.blocks_available())?;
+    Ok(available_blocks * fs_stat.block_size())
+}
+
+/// Write the file through a temporary file renamed over it, so that a crash leaves either the old or the new content.
+/// The parent directory is created with mode 0700 if it doesn't exist.
+pub fn write_file_atomically<P: AsRef<Path>>(path: P, content: &[u8], mode: u32) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).mode(mode).open(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// The sha256 checksum of the file in lowercase hex, the same as sha256sum prints
+pub fn file_sha256<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The sha256 checksum of the first len bytes of the file or block device, which fails if it is shorter than len
+pub fn sha256_prefix<P: AsRef<Path>>(path: P, len: u64) -> Result<String> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let read =
+        io::copy(&mut file.take(len), &mut hasher).with_context(|| format!("Failed to read {}", path.display()))?;
+    if read < len {
+        bail!("{} has only {} of {} bytes", path.display(), read, len);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Flush the file or block device and drop its pages from the page cache, so that it is read from the disk next time
+pub fn drop_page_cache<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    file.sync_all().with_context(|| format!("Failed to flush the data written to {}", path.display()))?;
+    // only the clean pages are dropped, which are all of them once flushed
+    posix_fadvise(file.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+        .with_context(|| format!("Failed to drop the cached pages of {}", path.display()))?;
+    Ok(())
+}
+
 pub fn delete_file_or_dir<P: AsRef<Path>>(path: P) -> Result<()> {
     if is_file_exist(&path) {
         if fs::metadata(&path)?.is_file() {
@@ -161,6 +233,82 @@ pub fn switch_boot_menuentry<T: CommandExecutor>(
     Ok(())
 }
 
+/// BootTrial is a switch of the boot menuentry not committed yet. grub.cfg counts down the remaining attempts on every
+/// boot, and boots the fallback menuentry once they run out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootTrial {
+    pub remaining_attempts: u32,
+    pub fallback_menuentry: String,
+}
+
+// grub2-editenv takes "-" as the grubenv next to grub.cfg, which grub2-set-default modifies in bios mode
+fn grubenv_file(grub_env_path: &str) -> &str {
+    if get_boot_mode() == "uefi" {
+        grub_env_path
+    } else {
+        "-"
+    }
+}
+
+/// Switch to next_menuentry for at most the given boot attempts, grub boots fallback_menuentry again if the trial is
+/// not ended by end_boot_trial before the attempts run out
+pub fn start_boot_trial<T: CommandExecutor>(
+    command_executor: &T,
+    grub_env_path: &str,
+    next_menuentry: &str,
+    fallback_menuentry: &str,
+    attempts: u32,
+) -> Result<()> {
+    command_executor.run_command(
+        "grub2-editenv",
+        &[
+            grubenv_file(grub_env_path),
+            "set",
+            format!("{}={}", GRUBENV_BOOT_COUNTER, attempts).as_str(),
+            format!("{}={}", GRUBENV_BOOT_FALLBACK, fallback_menuentry).as_str(),
+        ],
+    )?;
+    if let Err(e) = switch_boot_menuentry(command_executor, grub_env_path, next_menuentry) {
+        end_boot_trial(command_executor, grub_env_path)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Remove the boot counter, the menuentry booted currently becomes the default one
+pub fn end_boot_trial<T: CommandExecutor>(command_executor: &T, grub_env_path: &str) -> Result<()> {
+    command_executor.run_command(
+        "grub2-editenv",
+        &[grubenv_file(grub_env_path), "unset", GRUBENV_BOOT_COUNTER, GRUBENV_BOOT_FALLBACK],
+    )
+}
+
+pub fn get_boot_trial<T: CommandExecutor>(command_executor: &T, grub_env_path: &str) -> Result<Option<BootTrial>> {
+    let grubenv = command_executor.run_command_with_output("grub2-editenv", &[grubenv_file(grub_env_path), "list"])?;
+    parse_boot_trial(&grubenv)
+}
+
+fn parse_boot_trial(grubenv: &str) -> Result<Option<BootTrial>> {
+    let (mut counter, mut fallback) = (None, None);
+    for line in grubenv.lines() {
+        // grub.cfg leaves the variables empty after falling back
+        match line.split_once('=') {
+            Some((GRUBENV_BOOT_COUNTER, value)) if !value.is_empty() => counter = Some(value),
+            Some((GRUBENV_BOOT_FALLBACK, value)) if !value.is_empty() => fallback = Some(value),
+            _ => {},
+        }
+    }
+    match (counter, fallback) {
+        (Some(counter), Some(fallback)) => Ok(Some(BootTrial {
+            remaining_attempts: counter
+                .parse()
+                .with_context(|| format!("Invalid {} \"{}\" in grubenv", GRUBENV_BOOT_COUNTER, counter))?,
+            fallback_menuentry: fallback.to_string(),
+        })),
+        _ => Ok(None),
+    }
+}
+
 pub fn get_boot_mode() -> String {
     if is_file_exist("/sys/firmware/efi") {
         "uefi".into()
@@ -173,8 +321,22 @@ pub fn is_dmv_mode<T: CommandExecutor>(c: &T) -> bool {
     c.run_command("veritysetup", &["status", "kubeos-root"]).is_ok()
 }
 
+/// get_os_version returns the PRETTY_NAME in os-release, which is the same as the osImage reported by kubelet
+pub fn get_os_version<P: AsRef<Path>>(os_release_path: P) -> Result<String> {
+    let os_release = fs::read_to_string(&os_release_path)
+        .with_context(|| format!("Failed to read {}", os_release_path.as_ref().display()))?;
+    for line in os_release.lines() {
+        if let Some(version) = line.trim().strip_prefix("PRETTY_NAME=") {
+            return Ok(version.trim_matches('"').to_string());
+        }
+    }
+    bail!("Failed to find PRETTY_NAME in {}", os_release_path.as_ref().display())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+
     use mockall::{mock, predicate::*};
     use tempfile::{NamedTempFile, TempDir};
 
@@ -272,6 +434,35 @@ mod tests {
         delete_file_or_dir(path).unwrap();
     }
 
+    #[test]
+    fn test_drop_page_cache() {
+        init();
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "hello").unwrap();
+        drop_page_cache(file.path()).unwrap();
+        assert_eq!(fs::read_to_string(file.path()).unwrap(), "hello");
+        assert!(drop_page_cache("/tmp/nonexist").is_err());
+    }
+
+    #[test]
+    fn test_file_sha256() {
+        init();
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "hello").unwrap();
+        assert_eq!(
+            file_sha256(file.path()).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(file_sha256("/tmp/nonexist-sha256").is_err());
+        assert_eq!(sha256_prefix(file.path(), 5).unwrap(), file_sha256(file.path()).unwrap());
+        write!(file, " world").unwrap();
+        assert_eq!(
+            sha256_prefix(file.path(), 5).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(sha256_prefix(file.path(), 100).is_err());
+    }
+
     #[test]
     fn test_switch_boot_menuentry() {
         init();
@@ -297,6 +488,37 @@ mod tests {
         switch_boot_menuentry(&mock, grubenv_path, next_menuentry).unwrap()
     }
 
+    #[test]
+    fn test_boot_trial() {
+        init();
+        let grubenv_path = "/boot/efi/EFI/openEuler/grubenv";
+        let grubenv_file = if get_boot_mode() == "uefi" { grubenv_path } else { "-" };
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command()
+            .withf(move |name, args| {
+                name == "grub2-editenv"
+                    && args == [grubenv_file, "set", "boot_counter=3", "boot_fallback_entry=A"].as_slice()
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock.expect_run_command()
+            .withf(|name, _| name == "grub2-set-default" || name == "grub2-editenv")
+            .times(1)
+            .returning(|_, _| Ok(()));
+        start_boot_trial(&mock, grubenv_path, "B", "A", 3).unwrap();
+
+        mock.expect_run_command_with_output()
+            .withf(move |name, args| name == "grub2-editenv" && args == [grubenv_file, "list"].as_slice())
+            .times(1)
+            .returning(|_, _| Ok("saved_entry=B\nboot_counter=2\nboot_fallback_entry=A".to_string()));
+        let trial = get_boot_trial(&mock, grubenv_path).unwrap();
+        assert_eq!(trial, Some(BootTrial { remaining_attempts: 2, fallback_menuentry: "A".to_string() }));
+
+        assert_eq!(parse_boot_trial("saved_entry=A\nboot_counter=\nboot_fallback_entry=").unwrap(), None);
+        assert_eq!(parse_boot_trial("saved_entry=A").unwrap(), None);
+        assert!(parse_boot_trial("boot_counter=x\nboot_fallback_entry=A").is_err());
+    }
+
     #[test]
     fn test_get_boot_mode() {
         init();
@@ -324,4 +546,17 @@ mod tests {
         let executor = RealCommandExecutor {};
         assert_eq!(is_dmv_mode(&executor), false);
     }
+
+    #[test]
+    fn test_get_os_version() {
+        init();
+        let mut os_release = NamedTempFile::new().unwrap();
+        writeln!(os_release, "NAME=KubeOS\nID=KubeOS\nPRETTY_NAME=\"KubeOS v2\"\nVERSION_ID=v2").unwrap();
+        assert_eq!(get_os_version(os_release.path()).unwrap(), "KubeOS v2");
+
+        let mut os_release = NamedTempFile::new().unwrap();
+        writeln!(os_release, "NAME=KubeOS\nID=KubeOS").unwrap();
+        assert!(get_os_version(os_release.path()).is_err());
+        assert!(get_os_version("/tmp/nonexist-os-release").is_err());
+    }
 }
@@ -14,7 +14,7 @@ use anyhow::{bail, Result};
 use log::{debug, info, trace};
 use regex::Regex;
 
-use super::executor::CommandExecutor;
+use super::{executor::CommandExecutor, progress::ProgressTracker};
 
 pub fn is_valid_image_name(image: &str) -> Result<()> {
     let pattern = r"^((?:[\w.-]+)(?::\d+)?/)*(?:[\w.-]+)((?::[\w_.-]+)?|(?:@sha256:[a-fA-F0-9]+)?)$";
@@ -90,20 +90,27 @@ pub fn get_oci_image_digest<T: CommandExecutor>(
     bail!("Failed to get digest from command output: {}", cmd_output)
 }
 
-pub fn pull_image<T: CommandExecutor>(runtime: &str, image_name: &str, executor: &T) -> Result<()> {
+/// Pull the image, the pulling command is killed once the upgrade preparation is cancelled
+pub fn pull_image<T: CommandExecutor>(
+    runtime: &str,
+    image_name: &str,
+    executor: &T,
+    progress: &ProgressTracker,
+) -> Result<()> {
     debug!("Pull image {}", image_name);
     match runtime {
         "crictl" => {
-            executor.run_command("crictl", &["pull", image_name])?;
+            executor.run_command_cancellable("crictl", &["pull", image_name], progress)?;
         },
         "ctr" => {
-            executor.run_command(
+            executor.run_command_cancellable(
                 "ctr",
                 &[&"-n", "k8s.io", "images", "pull", "--hosts-dir", "/etc/containerd/certs.d", image_name],
+                progress,
             )?;
         },
         "docker" => {
-            executor.run_command("docker", &["pull", image_name])?;
+            executor.run_command_cancellable("docker", &["pull", image_name], progress)?;
         },
         _ => {
             bail!("Container runtime {} cannot be recognized", runtime);
@@ -307,13 +314,13 @@ mod tests {
             .returning(|_, _| Ok(()));
 
         let image_name = "docker.io/nginx:latest";
-        let result = pull_image("crictl", image_name, &mock_executor);
+        let result = pull_image("crictl", image_name, &mock_executor, &ProgressTracker::default());
         assert!(result.is_ok());
-        let result = pull_image("ctr", image_name, &mock_executor);
+        let result = pull_image("ctr", image_name, &mock_executor, &ProgressTracker::default());
         assert!(result.is_ok());
-        let result = pull_image("docker", image_name, &mock_executor);
+        let result = pull_image("docker", image_name, &mock_executor, &ProgressTracker::default());
         assert!(result.is_ok());
-        let result = pull_image("aaa", image_name, &mock_executor);
+        let result = pull_image("aaa", image_name, &mock_executor, &ProgressTracker::default());
         assert!(result.is_err());
     }
 
@@ -10,14 +10,29 @@
  * See the Mulan PSL v2 for more details.
  */
 
-use std::process::Command;
+use std::{
+    io::Read,
+    process::{Command, Stdio},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
-use log::{debug, trace};
+use log::{debug, info, trace};
+
+use super::progress::ProgressTracker;
+
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 pub trait CommandExecutor: Clone {
     fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
     fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+
+    /// Run the command of the upgrade preparation, which is killed once the preparation is cancelled
+    fn run_command_cancellable<'a>(&self, name: &'a str, args: &[&'a str], progress: &ProgressTracker) -> Result<()> {
+        progress.check_cancelled()?;
+        self.run_command(name, args)
+    }
 }
 
 #[derive(Clone)]
@@ -47,6 +62,45 @@ impl CommandExecutor for RealCommandExecutor {
         debug!("run_command_with_output: {} {:?} done", name, args);
         Ok(stdout.trim_end_matches('\n').to_string())
     }
+
+    fn run_command_cancellable<'a>(&self, name: &'a str, args: &[&'a str], progress: &ProgressTracker) -> Result<()> {
+        trace!("run_command_cancellable: {} {:?}", name, args);
+        progress.check_cancelled()?;
+        let mut child = Command::new(name).args(args).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        // the output is read aside, or the command blocks once the pipe is full
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if progress.is_cancelled() {
+                info!("Kill command {} {:?} of the cancelled upgrade preparation", name, args);
+                // the command may have exited in the meantime
+                let _ = child.kill();
+                child.wait()?;
+                bail!("Upgrade preparation is cancelled, command {} {:?} is killed", name, args);
+            }
+            thread::sleep(CANCEL_CHECK_INTERVAL);
+        };
+        if !status.success() {
+            let stdout = stdout.join().unwrap_or_default();
+            let error_message = stderr.join().unwrap_or_default();
+            bail!("Failed to run command: {} {:?}, stdout: \"{}\", stderr: \"{}\"", name, args, stdout, error_message);
+        }
+        debug!("run_command_cancellable: {} {:?} done", name, args);
+        Ok(())
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(reader: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut reader) = reader {
+            let _ = reader.read_to_end(&mut output);
+        }
+        String::from_utf8_lossy(&output).to_string()
+    })
 }
 
 #[cfg(test)]
@@ -75,6 +129,28 @@ mod tests {
         assert!(out.is_err());
     }
 
+    #[test]
+    fn test_run_command_cancellable() {
+        init();
+        let executor: RealCommandExecutor = RealCommandExecutor {};
+        let progress = ProgressTracker::default();
+        progress.start("v2");
+        executor.run_command_cancellable("sh", &["-c", "seq 100000"], &progress).unwrap();
+        let err = executor.run_command_cancellable("sh", &["-c", "echo failed >&2; exit 1"], &progress).unwrap_err();
+        assert!(err.to_string().contains("stderr: \"failed\n\""));
+
+        let canceller = progress.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            canceller.cancel().unwrap();
+        });
+        let start = std::time::Instant::now();
+        let err = executor.run_command_cancellable("sleep", &["10"], &progress).unwrap_err();
+        assert!(err.to_string().contains("is killed"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(executor.run_command_cancellable("true", &[], &progress).is_err());
+    }
+
     #[test]
     fn test_run_command() {
         init();
@@ -14,28 +14,49 @@ use std::{
     fs::{self, Permissions},
     os::unix::fs::PermissionsExt,
     path::PathBuf,
+    time::Instant,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use log::{debug, info};
 
 use super::{
     clean_env,
-    common::{delete_file_or_dir, PreparePath},
+    common::{delete_file_or_dir, drop_page_cache, file_sha256, sha256_prefix, PreparePath},
     executor::CommandExecutor,
+    metrics::{result_label, IMAGE_BUILD_DURATION, IMAGE_INSTALL_DURATION},
     partition::PartitionInfo,
+    progress::ProgressTracker,
 };
+use crate::api::UpgradeStage;
+
+/// ImageDigest is the sha256 checksum and size of the partition image installed to the next partition
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageDigest {
+    pub sha256: String,
+    pub bytes: u64,
+}
 
 pub struct UpgradeImageManager<T: CommandExecutor> {
     pub paths: PreparePath,
     pub next_partition: PartitionInfo,
     pub executor: T,
     pub dmv: bool,
+    pub progress: ProgressTracker,
 }
 
 impl<T: CommandExecutor> UpgradeImageManager<T> {
     pub fn new(paths: PreparePath, next_partition: PartitionInfo, executor: T, dmv: bool) -> Self {
-        Self { paths, next_partition, executor, dmv }
+        Self { paths, next_partition, executor, dmv, progress: ProgressTracker::default() }
+    }
+
+    pub fn with_progress(mut self, progress: ProgressTracker) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    fn image_size(&self) -> u64 {
+        u64::try_from(self.next_partition.size).unwrap_or_default()
     }
 
     fn image_path_str(&self) -> Result<&str> {
@@ -58,8 +79,11 @@ impl<T: CommandExecutor> UpgradeImageManager<T> {
 
         debug!("Create image {}, count {}", image_str, count);
 
-        self.executor
-            .run_command("dd", &["if=/dev/zero", &format!("of={}", image_str), "bs=2M", &format!("count={}", count)])?;
+        self.executor.run_command_cancellable(
+            "dd",
+            &["if=/dev/zero", &format!("of={}", image_str), "bs=2M", &format!("count={}", count)],
+            &self.progress,
+        )?;
         fs::set_permissions(&self.paths.image_path, Permissions::from_mode(permission))?;
         Ok(())
     }
@@ -67,9 +91,10 @@ impl<T: CommandExecutor> UpgradeImageManager<T> {
     pub fn format_image(&self) -> Result<()> {
         let image_str = self.image_path_str()?;
         debug!("Format image {}", image_str);
-        self.executor.run_command(
+        self.executor.run_command_cancellable(
             format!("mkfs.{}", self.next_partition.fs_type).as_str(),
             &["-L", format!("ROOT-{}", self.next_partition.menuentry).as_str(), image_str],
+            &self.progress,
         )?;
         Ok(())
     }
@@ -78,7 +103,7 @@ impl<T: CommandExecutor> UpgradeImageManager<T> {
         let image_str = self.image_path_str()?;
         let mount_str = self.mount_path_str()?;
         debug!("Mount {} to {}", image_str, mount_str);
-        self.executor.run_command("mount", &["-o", "loop", image_str, mount_str])?;
+        self.executor.run_command_cancellable("mount", &["-o", "loop", image_str, mount_str], &self.progress)?;
         Ok(())
     }
 
@@ -86,37 +111,83 @@ impl<T: CommandExecutor> UpgradeImageManager<T> {
         let tar_str = self.tar_path_str()?;
         let mount_str = self.mount_path_str()?;
         debug!("Extract {} to mounted path {}", tar_str, mount_str);
-        self.executor.run_command("tar", &["-xvf", tar_str, "-C", mount_str])?;
+        self.executor.run_command_cancellable("tar", &["-xvf", tar_str, "-C", mount_str], &self.progress)?;
         Ok(())
     }
 
     pub fn create_os_image(self, permission: u32) -> Result<Self> {
+        let start = Instant::now();
+        let result = self.build_os_image(permission);
+        IMAGE_BUILD_DURATION.observe_since(&[result_label(&result)], start);
+        result.map(|_| self)
+    }
+
+    fn build_os_image(&self, permission: u32) -> Result<()> {
+        self.progress.set_stage(UpgradeStage::BuildImage, self.image_size())?;
         self.create_image_file(permission)?;
+        self.progress.set_bytes(self.image_size());
+        // the commands building the image are killed once the preparation is cancelled
         self.format_image()?;
         self.mount_image()?;
         self.extract_tar_to_image()?;
         // Pass empty image_path to clean_env but avoid deleting the upgrade image
         clean_env(&self.paths.update_path, &self.paths.mount_path, &PathBuf::new())?;
-        Ok(self)
+        Ok(())
+    }
+
+    /// Install the image to the next partition, returns the digest of the written data unless in dm-verity mode
+    pub fn install(&self) -> Result<Option<ImageDigest>> {
+        let start = Instant::now();
+        let result = self.install_image();
+        IMAGE_INSTALL_DURATION.observe_since(&[result_label(&result)], start);
+        result
     }
 
-    pub fn install(&self) -> Result<()> {
+    fn install_image(&self) -> Result<Option<ImageDigest>> {
         if self.dmv {
+            self.progress.set_stage(UpgradeStage::Install, 0)?;
             info!("Dm-verity mode, installing boot, root and hash images");
             self.executor.run_command("/usr/bin/kubeos-dmv", &["upgrade"])?;
             info!("Next boot, root and hash partitions are overwritten and unable to rollback to the previous version anymore if the eviction of node fails");
-            return Ok(());
+            return Ok(None);
         }
+        self.progress.set_stage(UpgradeStage::Install, self.image_size())?;
         let image_str = self.image_path_str()?;
         let device = self.next_partition.device.as_str();
-        self.executor
-            .run_command("dd", &[format!("if={}", image_str).as_str(), format!("of={}", device).as_str(), "bs=8M"])?;
+        let digest =
+            ImageDigest { sha256: file_sha256(image_str)?, bytes: fs::metadata(&self.paths.image_path)?.len() };
+        // the data is written to the device directly and flushed before dd exits, so that a write error fails dd
+        self.executor.run_command(
+            "dd",
+            &[
+                format!("if={}", image_str).as_str(),
+                format!("of={}", device).as_str(),
+                "bs=8M",
+                "oflag=direct",
+                "conv=fsync",
+            ],
+        )?;
+        self.progress.set_bytes(self.image_size());
         debug!("Install image {} to {} done", image_str, device);
         info!(
             "Device {} is overwritten and unable to rollback to the previous version anymore if the eviction of node fails",
             device
         );
+        self.verify_install(&digest)?;
         delete_file_or_dir(image_str)?;
+        Ok(Some(digest))
+    }
+
+    // verify_install reads the written range of the device back from the disk, as a silent write error is only found
+    // when booting the partition otherwise. The cached pages are dropped first, or the memory is read instead
+    fn verify_install(&self, digest: &ImageDigest) -> Result<()> {
+        let device = self.next_partition.device.as_str();
+        drop_page_cache(device)?;
+        let written = sha256_prefix(device, digest.bytes)?;
+        if written != digest.sha256 {
+            bail!("Data written to {} is corrupt, sha256 {} mismatches the image {}", device, written, digest.sha256);
+        }
+        info!("Verified the data written to {}, sha256: {}", device, written);
         Ok(())
     }
 }
@@ -155,11 +226,15 @@ mod tests {
         init();
         // create a dir in tmp dir
         let tmp_dir = "/tmp/test_update_image_manager";
-        let img_path = format!("{}/test_image", tmp_dir);
+        // the image is built in the persist path, out of the update path cleaned after building
+        let persist_dir = tempfile::tempdir().unwrap();
+        let img_path = persist_dir.path().join("test_image").to_str().unwrap().to_string();
         let mut temp_file = NamedTempFile::new().unwrap();
         write!(temp_file, "test content").unwrap(); // Writing s
         fs::create_dir(tmp_dir).unwrap();
         let clone_img_path = img_path.clone();
+        let device = NamedTempFile::new().unwrap();
+        let device_path = device.path().to_str().unwrap().to_string();
 
         let mut mock = MockCommandExec::new();
         //mock create_image_file
@@ -191,10 +266,17 @@ mod tests {
             .returning(|_, _| Ok(()));
 
         //mock install->dd
+        let (src, dst) = (img_path.clone(), device_path.clone());
         mock.expect_run_command()
-            .withf(|name, _| name == "dd")
+            .withf(|name, args| name == "dd" && args.contains(&"oflag=direct") && args.contains(&"conv=fsync"))
             .times(1) // Expect it to be called once
-            .returning(|_, _| Ok(()));
+            .returning(move |_, _| {
+                // leave the partition larger than the image
+                let mut data = fs::read(&src).unwrap();
+                data.extend_from_slice(b"stale data of the previous version");
+                fs::write(&dst, data).unwrap();
+                Ok(())
+            });
 
         let img_manager = UpgradeImageManager::new(
             PreparePath {
@@ -206,7 +288,7 @@ mod tests {
                 rootfs_file: "image.tar".into(),
             },
             PartitionInfo {
-                device: "/dev/sda3".into(),
+                device: device_path.clone(),
                 fs_type: "ext4".into(),
                 menuentry: "B".into(),
                 size: 13000245248,
@@ -215,10 +297,59 @@ mod tests {
             false,
         );
 
-        let img_manager = img_manager.create_os_image(0o755).unwrap();
-        let result = img_manager.install();
-        assert!(result.is_ok());
+        let progress = ProgressTracker::default();
+        let img_manager = img_manager.with_progress(progress.clone()).create_os_image(0o755).unwrap();
+        assert_eq!(progress.get().stage, UpgradeStage::BuildImage);
+        let digest = img_manager.install().unwrap().unwrap();
+        assert_eq!(digest.bytes, "test content".len() as u64);
+        assert_eq!(digest.sha256, sha256_prefix(&device_path, digest.bytes).unwrap());
+        assert_eq!(progress.get().stage, UpgradeStage::Install);
+        assert_eq!(progress.get().current_bytes, 13000245248);
 
         assert_eq!(Path::new(&tmp_dir).exists(), false);
     }
+
+    #[test]
+    fn test_install_corrupt() {
+        init();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let paths = PreparePath::new(tmp_dir.path());
+        fs::write(&paths.image_path, "test content").unwrap();
+        let device = NamedTempFile::new().unwrap();
+        let device_path = device.path().to_str().unwrap().to_string();
+        let dst = device_path.clone();
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command().withf(|name, _| name == "dd").returning(move |_, _| {
+            fs::write(&dst, "test c0ntent").unwrap();
+            Ok(())
+        });
+        let next_partition = PartitionInfo { device: device_path, menuentry: "B".into(), ..Default::default() };
+        let img_manager = UpgradeImageManager::new(paths.clone(), next_partition, mock, false);
+        let err = img_manager.install().unwrap_err();
+        assert!(err.to_string().contains("is corrupt"));
+        // the image is kept for a retry
+        assert!(paths.image_path.exists());
+    }
+
+    #[test]
+    fn test_build_cancelled() {
+        init();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let paths = PreparePath::new(tmp_dir.path());
+        let progress = ProgressTracker::default();
+        progress.start("v2");
+        let (image_path, canceller) = (paths.image_path.clone(), progress.clone());
+        let mut mock = MockCommandExec::new();
+        // the preparation is cancelled while the image file is being created
+        mock.expect_run_command().withf(|name, _| name == "dd").times(1).returning(move |_, _| {
+            fs::write(&image_path, "").unwrap();
+            canceller.cancel()
+        });
+        mock.expect_run_command().withf(|name, _| name.starts_with("mkfs")).times(0);
+        let next_partition = PartitionInfo { fs_type: "ext4".into(), menuentry: "B".into(), ..Default::default() };
+        let img_manager = UpgradeImageManager::new(paths, next_partition, mock, false).with_progress(progress.clone());
+        let err = img_manager.create_os_image(0o600).err().unwrap();
+        assert!(err.to_string().contains("cancelled"));
+        assert_eq!(progress.get().stage, UpgradeStage::Cancelling);
+    }
 }
@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+
+use super::{is_command_available, CommandExecutor, PartitionInfo};
+
+/// BootEntry is the kernel, initrd and kernel parameters of a menuentry in grub.cfg, the paths are relative to the
+/// root partition of the menuentry
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootEntry {
+    pub kernel: String,
+    pub initrd: Option<String>,
+    pub cmdline: String,
+}
+
+/// Parse the linux and initrd commands of the menuentry from grub.cfg
+pub fn parse_boot_entry(grub_cfg: &str, menuentry: &str) -> Result<BootEntry> {
+    let header = format!("menuentry '{}'", menuentry);
+    let mut lines = grub_cfg.lines().map(|line| line.trim()).skip_while(|line| !line.starts_with(&header));
+    if lines.next().is_none() {
+        bail!("Failed to find menuentry {} in grub.cfg", menuentry);
+    }
+    let (mut kernel, mut cmdline, mut initrd) = (None, String::new(), None);
+    for line in lines.take_while(|line| *line != "}") {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("linux") | Some("linuxefi") => {
+                kernel = words.next().map(|k| k.to_string());
+                cmdline = words.collect::<Vec<&str>>().join(" ");
+            },
+            Some("initrd") | Some("initrdefi") => initrd = words.next().map(|i| i.to_string()),
+            _ => {},
+        }
+    }
+    let Some(kernel) = kernel else {
+        bail!("Failed to find the linux command of menuentry {} in grub.cfg", menuentry);
+    };
+    Ok(BootEntry { kernel, initrd, cmdline })
+}
+
+/// Load the kernel of the partition for the next kexec reboot. The kernel and initrd are read from the partition
+/// mounted read-only on mount_path, root= is set to the partition device if grub.cfg doesn't specify it.
+pub fn kexec_load<T: CommandExecutor, P: AsRef<Path>>(
+    executor: &T,
+    grub_cfg_path: &str,
+    partition: &PartitionInfo,
+    mount_path: P,
+) -> Result<()> {
+    if !is_command_available("kexec", executor) {
+        bail!("kexec is not available");
+    }
+    let grub_cfg =
+        fs::read_to_string(grub_cfg_path).with_context(|| format!("Failed to read grub.cfg {}", grub_cfg_path))?;
+    let mut entry = parse_boot_entry(&grub_cfg, &partition.menuentry)?;
+    if !entry.cmdline.split_whitespace().any(|param| param.starts_with("root=")) {
+        entry.cmdline = format!("root={} {}", partition.device, entry.cmdline).trim_end().to_string();
+    }
+    debug!("Boot entry of menuentry {}: {:?}", partition.menuentry, entry);
+
+    let mount_path = mount_path.as_ref();
+    fs::create_dir_all(mount_path)?;
+    let mount_str = mount_path.to_str().context("Failed to convert mount path to string")?;
+    executor.run_command("mount", &["-o", "ro", &partition.device, mount_str])?;
+    let result = load_entry(executor, mount_path, &entry);
+    executor.run_command("umount", &[mount_str])?;
+    result?;
+    info!("Loaded kernel {} of partition {} for kexec", entry.kernel, partition.device);
+    Ok(())
+}
+
+fn load_entry<T: CommandExecutor>(executor: &T, mount_path: &Path, entry: &BootEntry) -> Result<()> {
+    let in_partition = |path: &str| mount_path.join(path.trim_start_matches('/')).to_string_lossy().to_string();
+    let mut args = vec!["-l".to_string(), in_partition(&entry.kernel)];
+    if let Some(initrd) = &entry.initrd {
+        args.push(format!("--initrd={}", in_partition(initrd)));
+    }
+    args.push(format!("--command-line={}", entry.cmdline));
+    executor.run_command("kexec", &args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::mock;
+
+    use super::*;
+
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    const GRUB_CFG: &str = "
+menuentry 'A' --class KubeOS --unrestricted $menuentry_id_option 'KubeOS-A' {
+        set root='hd0,gpt2'
+        linux   /boot/vmlinuz root=/dev/sda2 ro rootfstype=ext4 quiet
+        initrd  /boot/initramfs.img
+}
+
+menuentry 'B' --class KubeOS --unrestricted $menuentry_id_option 'KubeOS-B' {
+        set root='hd0,gpt3'
+        linux   /boot/vmlinuz ro rootfstype=ext4 quiet
+}
+";
+
+    #[test]
+    fn test_parse_boot_entry() {
+        let entry = parse_boot_entry(GRUB_CFG, "A").unwrap();
+        assert_eq!(
+            entry,
+            BootEntry {
+                kernel: "/boot/vmlinuz".to_string(),
+                initrd: Some("/boot/initramfs.img".to_string()),
+                cmdline: "root=/dev/sda2 ro rootfstype=ext4 quiet".to_string(),
+            }
+        );
+        let entry = parse_boot_entry(GRUB_CFG, "B").unwrap();
+        assert_eq!(entry.initrd, None);
+        assert!(parse_boot_entry(GRUB_CFG, "C").is_err());
+        assert!(parse_boot_entry("menuentry 'A' {\n}\n", "A").is_err());
+    }
+
+    #[test]
+    fn test_kexec_load() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let grub_cfg_path = tmp_dir.path().join("grub.cfg");
+        fs::write(&grub_cfg_path, GRUB_CFG).unwrap();
+        let grub_cfg_path = grub_cfg_path.to_str().unwrap();
+        let mount_path = tmp_dir.path().join("kexec");
+        let mount_str = mount_path.to_str().unwrap().to_string();
+        let partition =
+            PartitionInfo { device: "/dev/sda3".to_string(), menuentry: "B".to_string(), ..Default::default() };
+
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command().withf(|name, _| name == "/bin/sh").times(1).returning(|_, _| Ok(()));
+        let expected_mount = mount_str.clone();
+        mock.expect_run_command()
+            .withf(move |name, args| name == "mount" && args == ["-o", "ro", "/dev/sda3", expected_mount.as_str()])
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let expected_kernel = format!("{}/boot/vmlinuz", mount_str);
+        mock.expect_run_command()
+            .withf(move |name, args| {
+                name == "kexec"
+                    && args
+                        == ["-l", expected_kernel.as_str(), "--command-line=root=/dev/sda3 ro rootfstype=ext4 quiet"]
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock.expect_run_command().withf(|name, _| name == "umount").times(1).returning(|_, _| Ok(()));
+        kexec_load(&mock, grub_cfg_path, &partition, &mount_path).unwrap();
+
+        let mut mock = MockCommandExec::new();
+        mock.expect_run_command().withf(|name, _| name == "/bin/sh").returning(|_, _| bail!("not found"));
+        assert!(kexec_load(&mock, grub_cfg_path, &partition, &mount_path).is_err());
+    }
+}
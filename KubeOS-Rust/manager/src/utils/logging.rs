@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    cell::RefCell,
+    env,
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use env_logger::{Builder, Env, Target};
+use log::Record;
+use serde_json::{json, Value};
+
+/// Logs are written as json lines if this environment variable is "json", otherwise as plain text
+pub const LOG_FORMAT_ENV: &str = "KUBEOS_LOG_FORMAT";
+pub const LOG_FORMAT_JSON: &str = "json";
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static REQUEST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Initialize the logger writing to stdout, the level is set by RUST_LOG and defaults to default_filter
+pub fn init_logger(default_filter: &str) {
+    let mut builder = Builder::from_env(Env::default().default_filter_or(default_filter));
+    builder.target(Target::Stdout);
+    if env::var(LOG_FORMAT_ENV).is_ok_and(|format| format == LOG_FORMAT_JSON) {
+        builder.format(|buf, record| {
+            let line = json_line(record, buf.timestamp_millis().to_string(), request_id());
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// RequestScope attaches the request id to the json logs emitted by the current thread until it is dropped
+pub struct RequestScope {
+    previous: Option<String>,
+}
+
+impl RequestScope {
+    pub fn enter(id: Option<String>) -> Self {
+        RequestScope { previous: REQUEST_ID.with(|current| current.replace(id)) }
+    }
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        REQUEST_ID.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// The id of the request being served by the current thread
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Generate an id correlating the logs of a request across os-proxy and os-agent, such as "proxy-18c2a3e4f51-7"
+pub fn new_request_id(prefix: &str) -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+    format!("{}-{:x}-{}", prefix, millis, REQUEST_SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+fn json_line(record: &Record, time: String, request_id: Option<String>) -> Value {
+    let mut line = json!({
+        "time": time,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(id) = request_id {
+        line["request_id"] = Value::String(id);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_request_scope() {
+        assert_eq!(request_id(), None);
+        {
+            let _scope = RequestScope::enter(Some("a".to_string()));
+            {
+                let _scope = RequestScope::enter(Some("b".to_string()));
+                assert_eq!(request_id(), Some("b".to_string()));
+            }
+            assert_eq!(request_id(), Some("a".to_string()));
+        }
+        assert_eq!(request_id(), None);
+        assert_ne!(new_request_id("proxy"), new_request_id("proxy"));
+        assert!(new_request_id("proxy").starts_with("proxy-"));
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = |request_id| {
+            json_line(
+                &Record::builder()
+                    .args(format_args!("run_command: {} {:?}", "ls", ["-l"]))
+                    .level(Level::Debug)
+                    .target("manager::utils::executor")
+                    .build(),
+                "2024-01-01T00:00:00.000Z".to_string(),
+                request_id,
+            )
+        };
+        assert_eq!(
+            line(Some("proxy-1-0".to_string())),
+            json!({
+                "time": "2024-01-01T00:00:00.000Z",
+                "level": "DEBUG",
+                "target": "manager::utils::executor",
+                "message": "run_command: ls [\"-l\"]",
+                "request_id": "proxy-1-0",
+            })
+        );
+        assert!(line(None).get("request_id").is_none());
+    }
+}
@@ -0,0 +1,338 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Buckets in seconds for the duration of downloading, building and installing images
+pub const DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+pub static DOWNLOAD_BYTES: Counter =
+    Counter::new("kubeos_agent_download_bytes", "Bytes of upgrade images downloaded by os-agent.", &[]);
+pub static DOWNLOAD_DURATION: Histogram = Histogram::new(
+    "kubeos_agent_download_duration_seconds",
+    "Time taken to download and verify upgrade images.",
+    &["result"],
+    DURATION_BUCKETS,
+);
+pub static IMAGE_BUILD_DURATION: Histogram = Histogram::new(
+    "kubeos_agent_image_build_duration_seconds",
+    "Time taken to build the root image of the next partition.",
+    &["result"],
+    DURATION_BUCKETS,
+);
+pub static IMAGE_INSTALL_DURATION: Histogram = Histogram::new(
+    "kubeos_agent_image_install_duration_seconds",
+    "Time taken to install the image to the next partition.",
+    &["result"],
+    DURATION_BUCKETS,
+);
+pub static CONFIGURE_CALLS: Counter =
+    Counter::new("kubeos_agent_configure_calls", "Configurations applied by os-agent.", &["model", "result"]);
+
+/// Metric is a metric family which can be exposed in the OpenMetrics text format
+pub trait Metric: Sync {
+    fn encode(&self, out: &mut String);
+}
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Counter { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        self.add(label_values, 1);
+    }
+
+    pub fn add(&self, label_values: &[&str], value: u64) {
+        debug_assert_eq!(label_values.len(), self.labels.len(), "label values mismatch of {}", self.name);
+        *lock(&self.values).entry(to_key(label_values)).or_default() += value;
+    }
+
+    pub fn get(&self, label_values: &[&str]) -> u64 {
+        lock(&self.values).get(&to_key(label_values)).copied().unwrap_or_default()
+    }
+}
+
+impl Metric for Counter {
+    fn encode(&self, out: &mut String) {
+        encode_header(out, self.name, "counter", self.help);
+        let values = lock(&self.values);
+        // a counter without labels is exposed even if it has never been increased
+        if values.is_empty() && self.labels.is_empty() {
+            let _ = writeln!(out, "{}_total 0", self.name);
+        }
+        for (key, value) in values.iter() {
+            let _ = writeln!(out, "{}_total{} {}", self.name, format_labels(self.labels, key, None), value);
+        }
+    }
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, HistogramValue>>,
+}
+
+struct HistogramValue {
+    // observations of each bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramValue {
+    fn new(buckets: usize) -> Self {
+        HistogramValue { counts: vec![0; buckets], sum: 0.0, count: 0 }
+    }
+}
+
+impl Histogram {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Self {
+        Histogram { name, help, labels, buckets, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        debug_assert_eq!(label_values.len(), self.labels.len(), "label values mismatch of {}", self.name);
+        let mut values = lock(&self.values);
+        let entry = values.entry(to_key(label_values)).or_insert_with(|| HistogramValue::new(self.buckets.len()));
+        if let Some(i) = self.buckets.iter().position(|bound| value <= *bound) {
+            entry.counts[i] += 1;
+        }
+        entry.sum += value;
+        entry.count += 1;
+    }
+
+    /// Observe the seconds elapsed since start
+    pub fn observe_since(&self, label_values: &[&str], start: Instant) {
+        self.observe(label_values, start.elapsed().as_secs_f64());
+    }
+
+    pub fn count(&self, label_values: &[&str]) -> u64 {
+        lock(&self.values).get(&to_key(label_values)).map(|v| v.count).unwrap_or_default()
+    }
+
+    fn encode_value(&self, out: &mut String, key: &[String], value: &HistogramValue) {
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(value.counts.iter()) {
+            cumulative += count;
+            let le = format!("{:?}", bound);
+            let _ = writeln!(out, "{}_bucket{} {}", self.name, format_labels(self.labels, key, Some(&le)), cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{} {}", self.name, format_labels(self.labels, key, Some("+Inf")), value.count);
+        let _ = writeln!(out, "{}_sum{} {:?}", self.name, format_labels(self.labels, key, None), value.sum);
+        let _ = writeln!(out, "{}_count{} {}", self.name, format_labels(self.labels, key, None), value.count);
+    }
+}
+
+impl Metric for Histogram {
+    fn encode(&self, out: &mut String) {
+        encode_header(out, self.name, "histogram", self.help);
+        let values = lock(&self.values);
+        if values.is_empty() && self.labels.is_empty() {
+            self.encode_value(out, &[], &HistogramValue::new(self.buckets.len()));
+        }
+        for (key, value) in values.iter() {
+            self.encode_value(out, key, value);
+        }
+    }
+}
+
+/// The value of the "result" label for the outcome of an operation
+pub fn result_label<T, E>(result: &std::result::Result<T, E>) -> &'static str {
+    if result.is_ok() {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+/// Encode the metrics in the OpenMetrics text format
+pub fn encode_metrics(metrics: &[&dyn Metric]) -> String {
+    let mut out = String::new();
+    for metric in metrics {
+        metric.encode(&mut out);
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Spawn a thread serving the metrics on http://address/metrics
+pub fn serve_metrics(address: &str, metrics: &'static [&'static dyn Metric]) -> Result<()> {
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("Failed to listen on {} for metrics", address))?;
+    info!("Serving metrics on http://{}/metrics", address);
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_scrape(stream, metrics) {
+                        debug!("Failed to serve metrics: {:#}", e);
+                    }
+                },
+                Err(e) => warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn handle_scrape(mut stream: TcpStream, metrics: &[&dyn Metric]) -> Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, the request has no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default().split('?').next().unwrap_or_default();
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", OPENMETRICS_CONTENT_TYPE, encode_metrics(metrics)),
+        ("GET", _) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Method Not Allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn encode_header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn format_labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut labels: Vec<String> =
+        names.iter().zip(values.iter()).map(|(name, value)| format!("{}=\"{}\"", name, escape(value))).collect();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{}\"", le));
+    }
+    if labels.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", labels.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn to_key(label_values: &[&str]) -> Vec<String> {
+    label_values.iter().map(|v| v.to_string()).collect()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let calls = Counter::new("test_calls", "Test calls.", &["method", "result"]);
+        let bytes = Counter::new("test_bytes", "Test bytes.", &[]);
+        let duration = Histogram::new("test_duration_seconds", "Test duration.", &["result"], &[1.0, 10.0]);
+        calls.inc(&["upgrade", "success"]);
+        calls.inc(&["upgrade", "success"]);
+        calls.inc(&["con\"fig\\ure", "failure"]);
+        duration.observe(&["success"], 0.5);
+        duration.observe(&["success"], 5.0);
+        duration.observe(&["success"], 20.0);
+        assert_eq!(calls.get(&["upgrade", "success"]), 2);
+        assert_eq!(duration.count(&["success"]), 3);
+
+        let expected = r#"# TYPE test_calls counter
+# HELP test_calls Test calls.
+test_calls_total{method="con\"fig\\ure",result="failure"} 1
+test_calls_total{method="upgrade",result="success"} 2
+# TYPE test_bytes counter
+# HELP test_bytes Test bytes.
+test_bytes_total 0
+# TYPE test_duration_seconds histogram
+# HELP test_duration_seconds Test duration.
+test_duration_seconds_bucket{result="success",le="1.0"} 1
+test_duration_seconds_bucket{result="success",le="10.0"} 2
+test_duration_seconds_bucket{result="success",le="+Inf"} 3
+test_duration_seconds_sum{result="success"} 25.5
+test_duration_seconds_count{result="success"} 3
+# EOF
+"#;
+        assert_eq!(encode_metrics(&[&calls, &bytes, &duration]), expected);
+    }
+
+    #[test]
+    fn test_serve_metrics() {
+        static TEST_SCRAPES: Counter = Counter::new("test_scrapes", "Test scrapes.", &[]);
+        static TEST_METRICS: [&dyn Metric; 1] = [&TEST_SCRAPES];
+        TEST_SCRAPES.add(&[], 3);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        serve_metrics(&address.to_string(), &TEST_METRICS).unwrap();
+
+        let scrape = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(OPENMETRICS_CONTENT_TYPE));
+        assert!(response.ends_with("test_scrapes_total 3\n# EOF\n"));
+        assert!(scrape("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(scrape("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+        assert!(serve_metrics("invalid address", &TEST_METRICS).is_err());
+    }
+}
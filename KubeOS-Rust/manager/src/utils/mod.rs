@@ -10,14 +10,30 @@
  * See the Mulan PSL v2 for more details.
  */
 
+mod checkpoint;
 mod common;
 mod container_image;
 mod executor;
 mod image_manager;
+mod journal;
+mod kexec;
+mod logging;
+mod metrics;
 mod partition;
+mod progress;
+mod slots;
+mod systemd;
 
+pub use checkpoint::*;
 pub use common::*;
 pub use container_image::*;
 pub use executor::*;
 pub use image_manager::*;
+pub use journal::*;
+pub use kexec::*;
+pub use logging::*;
+pub use metrics::*;
 pub use partition::*;
+pub use progress::*;
+pub use slots::*;
+pub use systemd::*;
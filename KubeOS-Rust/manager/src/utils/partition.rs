@@ -12,10 +12,11 @@
 
 use anyhow::{bail, Context, Result};
 use log::trace;
+use serde::{Deserialize, Serialize};
 
 use super::executor::CommandExecutor;
 
-#[derive(PartialEq, Debug, Default)]
+#[derive(Deserialize, Serialize, PartialEq, Debug, Default, Clone)]
 pub struct PartitionInfo {
     pub device: String,
     pub menuentry: String,
@@ -0,0 +1,262 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{debug, info};
+
+use crate::api::{ProgressResponse, UpgradeStage};
+
+/// ProgressTracker records the stage and byte counts of a running upgrade preparation, it can be cloned and shared
+/// between the rpc server and the background preparation job.
+#[derive(Clone, Default)]
+pub struct ProgressTracker {
+    inner: Arc<Mutex<ProgressState>>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    progress: ProgressResponse,
+    running: bool,
+    cancelled: bool,
+    last_update: Option<Instant>,
+}
+
+impl ProgressTracker {
+    pub fn start(&self, version: &str) {
+        let mut state = self.lock();
+        *state = ProgressState {
+            progress: ProgressResponse { version: version.to_string(), ..Default::default() },
+            running: true,
+            cancelled: false,
+            last_update: Some(Instant::now()),
+        };
+    }
+
+    /// Enter the next stage of the preparation, fails if the preparation has been cancelled
+    pub fn set_stage(&self, stage: UpgradeStage, total_bytes: u64) -> Result<()> {
+        let mut state = self.lock();
+        if state.cancelled {
+            bail!("Upgrade preparation is cancelled");
+        }
+        debug!("Upgrade preparation enters stage {:?}, total bytes: {}", stage, total_bytes);
+        state.progress.stage = stage;
+        state.progress.current_bytes = 0;
+        state.progress.total_bytes = total_bytes;
+        state.last_update = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        let mut state = self.lock();
+        state.progress.current_bytes += bytes;
+        state.last_update = Some(Instant::now());
+    }
+
+    pub fn set_bytes(&self, bytes: u64) {
+        let mut state = self.lock();
+        state.progress.current_bytes = bytes;
+        state.last_update = Some(Instant::now());
+    }
+
+    pub fn finish(&self) {
+        let mut state = self.lock();
+        state.progress.stage = UpgradeStage::Done;
+        state.running = false;
+    }
+
+    pub fn fail(&self, error: String) {
+        let mut state = self.lock();
+        state.progress.stage = if state.cancelled { UpgradeStage::Cancelled } else { UpgradeStage::Failed };
+        state.progress.error = Some(error);
+        state.running = false;
+    }
+
+    /// Request the running preparation to stop, the installation to the next partition can not be cancelled. The stage
+    /// is Cancelling until the preparation stops and fails as Cancelled
+    pub fn cancel(&self) -> Result<()> {
+        let mut state = self.lock();
+        if !state.running {
+            bail!("No upgrade preparation is running");
+        }
+        if state.progress.stage == UpgradeStage::Install {
+            bail!("Image is being installed to the next partition and can not be cancelled");
+        }
+        info!("Cancel preparing for upgrading to version: {}", state.progress.version);
+        state.cancelled = true;
+        state.progress.stage = UpgradeStage::Cancelling;
+        Ok(())
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.lock().cancelled
+    }
+
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("Upgrade preparation is cancelled");
+        }
+        Ok(())
+    }
+
+    pub fn get(&self) -> ProgressResponse {
+        self.lock().progress.clone()
+    }
+
+    /// How long the running preparation has made no progress, None if no preparation is running
+    pub fn stalled_for(&self) -> Option<Duration> {
+        let state = self.lock();
+        if !state.running {
+            return None;
+        }
+        state.last_update.map(|t| t.elapsed())
+    }
+
+    /// Describe the running preparation, for example "downloading update v2 40%"
+    pub fn status(&self) -> Option<String> {
+        let state = self.lock();
+        if !state.running {
+            return None;
+        }
+        let progress = &state.progress;
+        let action = match progress.stage {
+            UpgradeStage::Download => "downloading",
+            UpgradeStage::Verify => "verifying",
+            UpgradeStage::BuildImage => "building image of",
+            UpgradeStage::Install => "installing",
+            UpgradeStage::Cancelling => "cancelling",
+            _ => "preparing",
+        };
+        let mut status = format!("{} update {}", action, progress.version);
+        if let Some(percent) =
+            (progress.current_bytes.min(progress.total_bytes) * 100).checked_div(progress.total_bytes)
+        {
+            status.push_str(&format!(" {}%", percent));
+        }
+        Some(status)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ProgressState> {
+        // a poisoned progress is still valid to be read and updated
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// ProgressWriter counts the bytes written to the inner writer into the progress tracker, and stops writing once the
+/// preparation is cancelled
+pub struct ProgressWriter<'a, W: Write> {
+    inner: W,
+    progress: &'a ProgressTracker,
+}
+
+impl<'a, W: Write> ProgressWriter<'a, W> {
+    pub fn new(inner: W, progress: &'a ProgressTracker) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.progress.is_cancelled() {
+            return Err(io::Error::other("upgrade preparation is cancelled"));
+        }
+        let n = self.inner.write(buf)?;
+        self.progress.add_bytes(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_tracker() {
+        let progress = ProgressTracker::default();
+        assert_eq!(progress.get().stage, UpgradeStage::Idle);
+        progress.start("v2");
+        progress.set_stage(UpgradeStage::Download, 10).unwrap();
+        let shared = progress.clone();
+        let mut writer = ProgressWriter::new(Vec::new(), &shared);
+        writer.write_all(b"hello").unwrap();
+        let res = progress.get();
+        assert_eq!(res.version, "v2");
+        assert_eq!(res.stage, UpgradeStage::Download);
+        assert_eq!(res.current_bytes, 5);
+        assert_eq!(res.total_bytes, 10);
+
+        progress.set_stage(UpgradeStage::Install, 20).unwrap();
+        assert_eq!(progress.get().current_bytes, 0);
+        progress.fail("install failed".to_string());
+        let res = progress.get();
+        assert_eq!(res.stage, UpgradeStage::Failed);
+        assert_eq!(res.error, Some("install failed".to_string()));
+
+        progress.start("v3");
+        assert_eq!(progress.get(), ProgressResponse { version: "v3".to_string(), ..Default::default() });
+        progress.finish();
+        assert_eq!(progress.get().stage, UpgradeStage::Done);
+    }
+
+    #[test]
+    fn test_progress_status() {
+        let progress = ProgressTracker::default();
+        assert_eq!(progress.status(), None);
+        assert_eq!(progress.stalled_for(), None);
+        progress.start("v2");
+        assert_eq!(progress.status(), Some("preparing update v2".to_string()));
+        progress.set_stage(UpgradeStage::Download, 200).unwrap();
+        progress.add_bytes(80);
+        assert_eq!(progress.status(), Some("downloading update v2 40%".to_string()));
+        assert!(progress.stalled_for().unwrap() < Duration::from_secs(60));
+        progress.set_stage(UpgradeStage::Install, 0).unwrap();
+        assert_eq!(progress.status(), Some("installing update v2".to_string()));
+        progress.finish();
+        assert_eq!(progress.status(), None);
+        assert_eq!(progress.stalled_for(), None);
+    }
+
+    #[test]
+    fn test_cancel_progress() {
+        let progress = ProgressTracker::default();
+        assert!(progress.cancel().is_err());
+
+        progress.start("v2");
+        progress.set_stage(UpgradeStage::Download, 10).unwrap();
+        progress.cancel().unwrap();
+        assert_eq!(progress.get().stage, UpgradeStage::Cancelling);
+        assert!(progress.check_cancelled().is_err());
+        let mut writer = ProgressWriter::new(Vec::new(), &progress);
+        assert!(writer.write_all(b"hello").is_err());
+        assert!(progress.set_stage(UpgradeStage::Verify, 10).is_err());
+        progress.fail("cancelled".to_string());
+        assert_eq!(progress.get().stage, UpgradeStage::Cancelled);
+        assert!(progress.cancel().is_err());
+
+        // installation can not be cancelled
+        progress.start("v3");
+        assert!(progress.check_cancelled().is_ok());
+        progress.set_stage(UpgradeStage::Install, 10).unwrap();
+        assert!(progress.cancel().is_err());
+        progress.finish();
+        assert_eq!(progress.get().stage, UpgradeStage::Done);
+    }
+}
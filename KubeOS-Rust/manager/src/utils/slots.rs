@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+
+use super::{
+    common::{sha256_prefix, write_file_atomically},
+    executor::CommandExecutor,
+    partition::{get_partition_info, PartitionInfo},
+};
+use crate::api::{SlotInfo, SlotMetadata, SlotState, VerifySlotResponse};
+
+/// SlotStore keeps the metadata of the images installed to the A/B partitions in a json file on the persist
+/// partition, which is shared by both partitions. The metadata is keyed by the menuentry of the partition.
+#[derive(Debug, Clone)]
+pub struct SlotStore {
+    path: PathBuf,
+}
+
+impl SlotStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        SlotStore { path: path.as_ref().to_path_buf() }
+    }
+
+    /// Read the metadata of all slots, no slot is recorded if the file doesn't exist
+    pub fn load(&self) -> Result<HashMap<String, SlotMetadata>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read slot metadata {}", self.path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Failed to parse slot metadata {}", self.path.display()))
+    }
+
+    pub fn get(&self, menuentry: &str) -> Result<Option<SlotMetadata>> {
+        Ok(self.load()?.remove(menuentry))
+    }
+
+    /// Record the metadata of the slot, the file is replaced atomically
+    pub fn set(&self, menuentry: &str, metadata: SlotMetadata) -> Result<()> {
+        let mut slots = self.load()?;
+        debug!("Record slot {}: {:?}", menuentry, metadata);
+        slots.insert(menuentry.to_string(), metadata);
+        write_file_atomically(&self.path, serde_json::to_string_pretty(&slots)?.as_bytes(), 0o600)
+            .with_context(|| format!("Failed to write slot metadata {}", self.path.display()))
+    }
+
+    /// Mark the slot as being installed, the slot stays corrupt if the install never finishes
+    pub fn begin_install(&self, menuentry: &str, mut metadata: SlotMetadata) -> Result<()> {
+        metadata.state = SlotState::Installing;
+        self.set(menuentry, metadata)
+    }
+
+    pub fn finish_install(&self, menuentry: &str, mut metadata: SlotMetadata) -> Result<()> {
+        metadata.state = SlotState::Installed;
+        metadata.install_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        self.set(menuentry, metadata)
+    }
+}
+
+/// List the current and the next partition with their recorded metadata
+pub fn list_slots<T: CommandExecutor>(executor: &T, store: &SlotStore) -> Result<Vec<SlotInfo>> {
+    let (current_partition, next_partition) = get_partition_info(executor)?;
+    let mut records = store.load()?;
+    let mut slots = Vec::new();
+    for (partition, current) in [(current_partition, true), (next_partition, false)] {
+        let metadata = records.remove(&partition.menuentry);
+        let state = slot_state(executor, &partition.device, current, metadata.as_ref())?;
+        slots.push(SlotInfo { menuentry: partition.menuentry, device: partition.device, current, state, metadata });
+    }
+    slots.sort_by(|a, b| a.menuentry.cmp(&b.menuentry));
+    Ok(slots)
+}
+
+/// Get the state of the slot, a slot without file system is empty whatever is recorded
+pub fn slot_state<T: CommandExecutor>(
+    executor: &T,
+    device: &str,
+    current: bool,
+    metadata: Option<&SlotMetadata>,
+) -> Result<SlotState> {
+    let state = metadata.map(|m| m.state).unwrap_or_default();
+    // the running partition always has a file system
+    if current || state == SlotState::Installing {
+        return Ok(state);
+    }
+    let fs_type = executor.run_command_with_output("lsblk", &["-no", "FSTYPE", device])?;
+    if fs_type.trim().is_empty() {
+        return Ok(SlotState::Empty);
+    }
+    Ok(state)
+}
+
+/// Read the data of the partition back and compare it with the image recorded as installed to it
+pub fn verify_partition(partition: &PartitionInfo, metadata: &SlotMetadata) -> Result<VerifySlotResponse> {
+    if metadata.image_sha256.is_empty() {
+        bail!("No image checksum is recorded for partition {}", partition.menuentry);
+    }
+    let actual_sha256 = sha256_prefix(&partition.device, metadata.image_bytes)?;
+    debug!("Partition {} sha256: {}, expected: {}", partition.menuentry, actual_sha256, metadata.image_sha256);
+    Ok(VerifySlotResponse {
+        menuentry: partition.menuentry.clone(),
+        device: partition.device.clone(),
+        matched: actual_sha256 == metadata.image_sha256,
+        expected_sha256: metadata.image_sha256.clone(),
+        actual_sha256,
+    })
+}
+
+/// Compare versions by the numbers in them, such as "KubeOS 1.0.10" is newer than "KubeOS 1.0.9"
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let numbers = |version: &str| -> Vec<u64> {
+        version.split(|c: char| !c.is_ascii_digit()).filter_map(|n| n.parse().ok()).collect()
+    };
+    numbers(a).cmp(&numbers(b)).then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::mock;
+
+    use super::*;
+
+    mock! {
+        pub CommandExec{}
+        impl CommandExecutor for CommandExec {
+            fn run_command<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<()>;
+            fn run_command_with_output<'a>(&self, name: &'a str, args: &[&'a str]) -> Result<String>;
+        }
+        impl Clone for CommandExec {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    fn metadata(version: &str) -> SlotMetadata {
+        SlotMetadata {
+            version: version.to_string(),
+            check_sum: "sha256".to_string(),
+            image_type: "disk".to_string(),
+            image_source: "https://example.com/os.tar".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_slot_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store = SlotStore::new(tmp_dir.path().join("os-agent/slots.json"));
+        assert!(store.load().unwrap().is_empty());
+
+        store.begin_install("B", metadata("KubeOS 1.0.2")).unwrap();
+        assert_eq!(store.get("B").unwrap().unwrap().state, SlotState::Installing);
+        store.finish_install("B", metadata("KubeOS 1.0.2")).unwrap();
+        store.finish_install("A", metadata("KubeOS 1.0.1")).unwrap();
+        let slot = store.get("B").unwrap().unwrap();
+        assert_eq!(slot.state, SlotState::Installed);
+        assert_eq!(slot.version, "KubeOS 1.0.2");
+        assert!(slot.install_time > 0);
+        assert_eq!(store.load().unwrap().len(), 2);
+        assert_eq!(store.get("C").unwrap(), None);
+
+        fs::write(tmp_dir.path().join("os-agent/slots.json"), "invalid").unwrap();
+        assert!(store.load().is_err());
+    }
+
+    #[test]
+    fn test_list_slots() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store = SlotStore::new(tmp_dir.path().join("slots.json"));
+        store.finish_install("B", metadata("KubeOS 1.0.2")).unwrap();
+        let mock_executor = |next_fs_type: &'static str| {
+            let mut mock = MockCommandExec::new();
+            mock.expect_run_command_with_output()
+                .withf(|name, _| name == "findmnt")
+                .returning(|_, _| Ok("/dev/sda3".to_string()));
+            mock.expect_run_command_with_output()
+                .withf(|name, args| name == "lsblk" && args[0] == "-blno")
+                .returning(|_, _| Ok("ext4 1024".to_string()));
+            mock.expect_run_command_with_output()
+                .withf(|name, args| name == "lsblk" && args == ["-no", "FSTYPE", "/dev/sda2"])
+                .returning(move |_, _| Ok(next_fs_type.to_string()));
+            mock
+        };
+
+        let slots = list_slots(&mock_executor("ext4"), &store).unwrap();
+        assert_eq!(slots.len(), 2);
+        assert_eq!((slots[0].menuentry.as_str(), slots[0].current, slots[0].state), ("A", false, SlotState::Unknown));
+        assert_eq!((slots[1].menuentry.as_str(), slots[1].current, slots[1].state), ("B", true, SlotState::Installed));
+        assert_eq!(slots[1].metadata.as_ref().unwrap().version, "KubeOS 1.0.2");
+
+        let slots = list_slots(&mock_executor(""), &store).unwrap();
+        assert_eq!(slots[0].state, SlotState::Empty);
+    }
+
+    #[test]
+    fn test_verify_partition() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let device = tmp_dir.path().join("sda2");
+        fs::write(&device, "hello world").unwrap();
+        let partition = PartitionInfo {
+            menuentry: "A".to_string(),
+            device: device.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let mut metadata = metadata("KubeOS 1.0.1");
+        assert!(verify_partition(&partition, &metadata).is_err());
+
+        metadata.image_sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string();
+        metadata.image_bytes = 5;
+        let res = verify_partition(&partition, &metadata).unwrap();
+        assert!(res.matched);
+        assert_eq!(res.actual_sha256, res.expected_sha256);
+
+        fs::write(&device, "hellO world").unwrap();
+        assert!(!verify_partition(&partition, &metadata).unwrap().matched);
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("KubeOS 1.0.10", "KubeOS 1.0.9"), Ordering::Greater);
+        assert_eq!(compare_versions("KubeOS 2", "KubeOS 1.9.9"), Ordering::Greater);
+        assert_eq!(compare_versions("KubeOS 1.0.2", "KubeOS 1.0.2"), Ordering::Equal);
+        assert_eq!(compare_versions("KubeOS 1.0", "KubeOS 1.0.1"), Ordering::Less);
+    }
+}
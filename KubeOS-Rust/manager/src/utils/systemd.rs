@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    env,
+    ffi::OsStr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+// how often the status is reported if the watchdog is not enabled
+const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Send the state, such as "READY=1", to systemd, returns false if the service is not started with Type=notify
+pub fn sd_notify(state: &str) -> Result<bool> {
+    match env::var_os(NOTIFY_SOCKET) {
+        Some(socket_path) => {
+            notify_to(&socket_path, state)?;
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+fn notify_to(socket_path: &OsStr, state: &str) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let path = socket_path.as_bytes();
+    // a leading '@' stands for a socket in the abstract namespace
+    match path.strip_prefix(b"@") {
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+        None => socket.send_to(state.as_bytes(), socket_path),
+    }
+    .with_context(|| format!("Failed to notify systemd through {:?}", socket_path))?;
+    Ok(())
+}
+
+/// The interval of WatchdogSec= configured in the service, None if the watchdog is not enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog_interval(env::var(WATCHDOG_USEC).ok(), env::var(WATCHDOG_PID).ok())
+}
+
+fn parse_watchdog_interval(usec: Option<String>, pid: Option<String>) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok()?;
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Spawn a thread reporting the status returned by check to systemd, and sending keep-alives while check reports
+/// healthy. Once unhealthy, the keep-alives stop and systemd restarts the service after WatchdogSec.
+pub fn spawn_watchdog<F>(check: F) -> Result<()>
+where
+    F: Fn() -> (String, bool) + Send + 'static,
+{
+    if env::var_os(NOTIFY_SOCKET).is_none() {
+        return Ok(());
+    }
+    let watchdog = watchdog_interval();
+    // ping twice in every watchdog interval as systemd suggests
+    let interval = watchdog.map(|i| i / 2).unwrap_or(DEFAULT_STATUS_INTERVAL);
+    debug!("Start reporting to systemd every {:?}, watchdog enabled: {}", interval, watchdog.is_some());
+    thread::Builder::new().name("sd-watchdog".to_string()).spawn(move || loop {
+        let (status, healthy) = check();
+        let mut state = format!("STATUS={}", status);
+        if watchdog.is_some() {
+            if healthy {
+                state.push_str("\nWATCHDOG=1");
+            } else {
+                warn!("Stop sending keep-alives to systemd: {}", status);
+            }
+        }
+        if let Err(e) = sd_notify(&state) {
+            warn!("{:#}", e);
+        }
+        thread::sleep(interval);
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_to() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("notify.sock");
+        let server = UnixDatagram::bind(&socket_path).unwrap();
+        notify_to(socket_path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        let name = format!("kubeos-test-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let server = UnixDatagram::bind_addr(&addr).unwrap();
+        notify_to(OsStr::new(&format!("@{}", name)), "WATCHDOG=1").unwrap();
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+
+        assert!(notify_to(tmp_dir.path().join("not-exist.sock").as_os_str(), "READY=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_watchdog_interval() {
+        let pid = std::process::id().to_string();
+        assert_eq!(parse_watchdog_interval(Some("30000000".into()), None), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog_interval(Some("30000000".into()), Some(pid)), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog_interval(Some("30000000".into()), Some("1".into())), None);
+        assert_eq!(parse_watchdog_interval(Some("0".into()), None), None);
+        assert_eq!(parse_watchdog_interval(Some("xxx".into()), None), None);
+        assert_eq!(parse_watchdog_interval(None, None), None);
+    }
+}
@@ -10,17 +10,34 @@
  * See the Mulan PSL v2 for more details.
  */
 
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
 
 use agent_error::Error;
 use cli::{
     client::Client,
     method::{
-        callable_method::RpcMethod, configure::ConfigureMethod, prepare_upgrade::PrepareUpgradeMethod,
+        callable_method::RpcMethod, cancel::CancelMethod, configure::ConfigureMethod,
+        get_capabilities::GetCapabilitiesMethod, get_drift::GetDriftMethod, get_progress::GetProgressMethod,
+        preflight::PreflightMethod, prepare_upgrade::PrepareUpgradeMethod, request::is_method_unimplemented,
         rollback::RollbackMethod, upgrade::UpgradeMethod,
     },
 };
-use manager::api::{CertsInfo, ConfigureRequest, KeyInfo as AgentKeyInfo, Sysconfig as AgentSysconfig, UpgradeRequest};
+use log::{debug, info, warn};
+use manager::{
+    api::{
+        AgentStatus, CapabilitiesResponse, CertsInfo, ConfigDrift, ConfigureRequest, KeyInfo as AgentKeyInfo,
+        Sysconfig as AgentSysconfig, UpgradeRequest, UpgradeStage, FEATURE_CANCEL, FEATURE_DRIFT, FEATURE_PREFLIGHT,
+        FEATURE_PROGRESS, FEATURE_WAIT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    utils::{new_request_id, RequestScope},
+};
+
+use super::values::{AGENT_LOCK_WAIT, PREPARE_UPGRADE_TIMEOUT};
 
 pub struct UpgradeInfo {
     pub version: String,
@@ -50,40 +67,192 @@ pub struct KeyInfo {
     pub operation: String,
 }
 
+/// The state of the upgrade preparation, which os-proxy checks again on the next reconcile while it is running
+#[derive(Debug, PartialEq)]
+pub enum PrepareStatus {
+    Ready,
+    Preparing,
+}
+
 pub trait AgentMethod {
-    fn prepare_upgrade_method(&self, upgrade_info: UpgradeInfo) -> Result<(), Error>;
+    fn prepare_upgrade_method(&self, upgrade_info: UpgradeInfo) -> Result<PrepareStatus, Error>;
     fn upgrade_method(&self) -> Result<(), Error>;
     fn rollback_method(&self) -> Result<(), Error>;
     fn configure_method(&self, config_info: ConfigInfo) -> Result<(), Error>;
+    /// The configured keys drifted on the node, None if os-agent doesn't check the drift
+    fn drift_method(&self) -> Result<Option<Vec<ConfigDrift>>, Error>;
 }
 pub trait AgentCall {
-    fn call_agent<T: RpcMethod + 'static>(&self, client: &Client, method: T) -> Result<(), Error>;
+    fn call_agent<T: RpcMethod + 'static>(&self, client: &Client, method: T) -> Result<T::Response, Error>;
 }
 
 pub struct AgentClient<T: AgentCall> {
     pub agent_client: Client,
     pub agent_call_client: T,
+    // the preparation started or taken over by os-proxy, which is polled by the reconciles until it finishes
+    preparing: Mutex<Option<Preparation>>,
+}
+
+struct Preparation {
+    version: String,
+    started: Instant,
 }
 
 impl<T: AgentCall> AgentClient<T> {
     pub fn new<P: AsRef<Path>>(socket_path: P, agent_call_client: T) -> Self {
-        AgentClient { agent_client: Client::new(socket_path), agent_call_client }
+        AgentClient { agent_client: Client::new(socket_path), agent_call_client, preparing: Mutex::new(None) }
+    }
+
+    fn preparing(&self) -> MutexGuard<'_, Option<Preparation>> {
+        self.preparing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // capabilities negotiates with os-agent before sending requests whose shapes depend on the agent version,
+    // os-agent of older versions without get_capabilities is treated as legacy
+    fn capabilities(&self) -> Result<CapabilitiesResponse, Error> {
+        let capabilities = match self.agent_call_client.call_agent(&self.agent_client, GetCapabilitiesMethod::default())
+        {
+            Ok(capabilities) => capabilities,
+            Err(Error::AgentError { source }) if is_method_unimplemented(&source) => {
+                info!("os-agent does not support get_capabilities, assume it is a legacy version");
+                CapabilitiesResponse::legacy()
+            },
+            Err(e) => return Err(e),
+        };
+        if !capabilities.is_compatible() {
+            return Err(Error::Unsupported {
+                message: format!(
+                    "os-agent {} supports protocol versions {}-{}, but os-proxy supports {}-{}",
+                    capabilities.agent_version,
+                    capabilities.min_protocol_version,
+                    capabilities.protocol_version,
+                    MIN_PROTOCOL_VERSION,
+                    PROTOCOL_VERSION
+                ),
+            });
+        }
+        Ok(capabilities)
+    }
+
+    // poll_prepare_upgrade checks the preparation of version in os-agent. It returns None if there is no preparation
+    // of version to wait for and none being cancelled, so that a new one is started. The preparation os-proxy was waiting for fails if os-agent
+    // is not preparing version any more, e.g. os-agent restarted, or it doesn't finish within PREPARE_UPGRADE_TIMEOUT
+    fn poll_prepare_upgrade(
+        &self,
+        capabilities: &CapabilitiesResponse,
+        version: &str,
+    ) -> Result<Option<PrepareStatus>, Error> {
+        let progress = self.agent_call_client.call_agent(&self.agent_client, GetProgressMethod::default())?;
+        let mut preparing = self.preparing();
+        let waiting = preparing.as_ref().is_some_and(|p| p.version == version);
+        let is_version = progress.version == version;
+        match progress.stage {
+            UpgradeStage::Done if is_version => {
+                *preparing = None;
+                Ok(Some(PrepareStatus::Ready))
+            },
+            UpgradeStage::Failed | UpgradeStage::Cancelled if is_version && waiting => {
+                *preparing = None;
+                let message = progress.error.unwrap_or_else(|| format!("the preparation is {:?}", progress.stage));
+                Err(Error::PrepareUpgrade { message })
+            },
+            UpgradeStage::Download | UpgradeStage::Verify | UpgradeStage::BuildImage | UpgradeStage::Install => {
+                if !is_version {
+                    *preparing = None;
+                    return Err(Error::PrepareUpgrade {
+                        message: format!(
+                            "os-agent is preparing upgrade to {} rather than {}",
+                            progress.version, version
+                        ),
+                    });
+                }
+                // the preparation started before os-proxy restarted is taken over
+                if !waiting {
+                    *preparing = Some(Preparation { version: version.to_string(), started: Instant::now() });
+                }
+                let started = preparing.as_ref().map(|p| p.started).unwrap_or_else(Instant::now);
+                if started.elapsed() > PREPARE_UPGRADE_TIMEOUT {
+                    *preparing = None;
+                    drop(preparing);
+                    self.cancel_prepare_upgrade(capabilities);
+                    return Err(Error::PrepareUpgrade {
+                        message: format!(
+                            "preparing upgrade to {} didn't finish in {}s, stuck in stage {:?}",
+                            version,
+                            PREPARE_UPGRADE_TIMEOUT.as_secs(),
+                            progress.stage
+                        ),
+                    });
+                }
+                info!(
+                    "Preparing upgrade to {}, stage: {:?}, progress: {}/{} bytes",
+                    version, progress.stage, progress.current_bytes, progress.total_bytes
+                );
+                Ok(Some(PrepareStatus::Preparing))
+            },
+            // the cancelled preparation holds os-agent until it stops, its result is known once it is Cancelled
+            UpgradeStage::Cancelling => {
+                info!("Waiting for os-agent to cancel preparing upgrade to {}", progress.version);
+                Ok(Some(PrepareStatus::Preparing))
+            },
+            stage if waiting => {
+                *preparing = None;
+                Err(Error::PrepareUpgrade {
+                    message: format!(
+                        "os-agent stopped preparing upgrade to {}, its progress is {:?} of version \"{}\"",
+                        version, stage, progress.version
+                    ),
+                })
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // cancel_prepare_upgrade stops the preparation which takes too long, so that it doesn't hold os-agent
+    fn cancel_prepare_upgrade(&self, capabilities: &CapabilitiesResponse) {
+        if !capabilities.has_feature(FEATURE_CANCEL) {
+            return;
+        }
+        if let Err(e) = self.agent_call_client.call_agent(&self.agent_client, CancelMethod::default()) {
+            warn!("Failed to cancel the upgrade preparation: {}", e);
+        }
     }
 }
 
 #[derive(Default)]
 pub struct AgentCallClient {}
 impl AgentCall for AgentCallClient {
-    fn call_agent<T: RpcMethod + 'static>(&self, client: &Client, method: T) -> Result<(), Error> {
-        match method.call(client) {
-            Ok(_resp) => Ok(()),
+    fn call_agent<T: RpcMethod + 'static>(&self, client: &Client, method: T) -> Result<T::Response, Error> {
+        // os-agent logs the call with the same id
+        let request_id = new_request_id("proxy");
+        let _scope = RequestScope::enter(Some(request_id.clone()));
+        debug!("Call os-agent method {}", method.command_name());
+        match method.call_with_request_id(client, Some(&request_id)) {
+            Ok(resp) => Ok(resp),
             Err(e) => Err(Error::AgentError { source: e }),
         }
     }
 }
 
 impl<T: AgentCall> AgentMethod for AgentClient<T> {
-    fn prepare_upgrade_method(&self, upgrade_info: UpgradeInfo) -> Result<(), Error> {
+    fn prepare_upgrade_method(&self, upgrade_info: UpgradeInfo) -> Result<PrepareStatus, Error> {
+        let capabilities = self.capabilities()?;
+        if !capabilities.supports_image_type(&upgrade_info.image_type) {
+            return Err(Error::Unsupported {
+                message: format!(
+                    "image type {} is not supported by os-agent, supported: {}",
+                    upgrade_info.image_type,
+                    capabilities.image_types.join(", ")
+                ),
+            });
+        }
+        // os-agent reports the preparation running in the background by get_progress
+        if capabilities.has_feature(FEATURE_PROGRESS) {
+            if let Some(status) = self.poll_prepare_upgrade(&capabilities, &upgrade_info.version)? {
+                return Ok(status);
+            }
+        }
+        let version = upgrade_info.version.clone();
         let upgrade_request = UpgradeRequest {
             version: upgrade_info.version,
             image_type: upgrade_info.image_type,
@@ -98,8 +267,31 @@ impl<T: AgentCall> AgentMethod for AgentClient<T> {
                 client_key: upgrade_info.clientkey,
             },
         };
-        match self.agent_call_client.call_agent(&self.agent_client, PrepareUpgradeMethod::new(upgrade_request)) {
-            Ok(_resp) => Ok(()),
+        // the node is drained after the preparation, so check the request first to avoid draining for nothing
+        if capabilities.has_feature(FEATURE_PREFLIGHT) {
+            let resp =
+                self.agent_call_client.call_agent(&self.agent_client, PreflightMethod::new(upgrade_request.clone()))?;
+            if !resp.failures.is_empty() {
+                let failures: Vec<String> =
+                    resp.failures.iter().map(|f| format!("{}: {}", f.check, f.message)).collect();
+                return Err(Error::Preflight { message: failures.join("; ") });
+            }
+        }
+        let mut method = PrepareUpgradeMethod::new(upgrade_request);
+        // wait for the running request of os-agent, such as a configuration, rather than requeueing
+        if capabilities.has_feature(FEATURE_WAIT) {
+            method.set_wait(AGENT_LOCK_WAIT.as_secs());
+        }
+        match self.agent_call_client.call_agent(&self.agent_client, method) {
+            // os-agent of older versions returns after the upgrade is ready
+            Ok(resp) if resp.status == AgentStatus::UpgradeReady || !capabilities.has_feature(FEATURE_PROGRESS) => {
+                Ok(PrepareStatus::Ready)
+            },
+            Ok(_resp) => {
+                info!("os-agent started preparing upgrade to {}", version);
+                *self.preparing() = Some(Preparation { version, started: Instant::now() });
+                Ok(PrepareStatus::Preparing)
+            },
             Err(e) => Err(e),
         }
     }
@@ -119,6 +311,18 @@ impl<T: AgentCall> AgentMethod for AgentClient<T> {
     }
 
     fn configure_method(&self, config_info: ConfigInfo) -> Result<(), Error> {
+        let capabilities = self.capabilities()?;
+        let unsupported: Vec<&str> = config_info
+            .configs
+            .iter()
+            .map(|config| config.model.as_str())
+            .filter(|model| !capabilities.supports_config_model(model))
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(Error::Unsupported {
+                message: format!("config models {} are not supported by os-agent", unsupported.join(", ")),
+            });
+        }
         let mut agent_configs: Vec<AgentSysconfig> = Vec::new();
         for config in config_info.configs {
             let mut contents_tmp: HashMap<String, AgentKeyInfo> = HashMap::new();
@@ -134,12 +338,23 @@ impl<T: AgentCall> AgentMethod for AgentClient<T> {
                 contents: contents_tmp,
             })
         }
-        let config_request = ConfigureRequest { configs: agent_configs };
-        match self.agent_call_client.call_agent(&self.agent_client, ConfigureMethod::new(config_request)) {
+        let mut method = ConfigureMethod::new(ConfigureRequest { configs: agent_configs });
+        if capabilities.has_feature(FEATURE_WAIT) {
+            method.set_wait(AGENT_LOCK_WAIT.as_secs());
+        }
+        match self.agent_call_client.call_agent(&self.agent_client, method) {
             Ok(_resp) => Ok(()),
             Err(e) => Err(e),
         }
     }
+
+    fn drift_method(&self) -> Result<Option<Vec<ConfigDrift>>, Error> {
+        if !self.capabilities()?.has_feature(FEATURE_DRIFT) {
+            return Ok(None);
+        }
+        let resp = self.agent_call_client.call_agent(&self.agent_client, GetDriftMethod::default())?;
+        Ok(Some(resp.drifts))
+    }
 }
 
 pub mod agent_error {
@@ -149,5 +364,125 @@ pub mod agent_error {
     pub enum Error {
         #[error("{source}")]
         AgentError { source: anyhow::Error },
+
+        #[error("Failed to prepare upgrade: {message}")]
+        PrepareUpgrade { message: String },
+
+        #[error("Request is not supported by os-agent: {message}")]
+        Unsupported { message: String },
+
+        #[error("Preflight checks failed: {message}")]
+        Preflight { message: String },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use manager::api::{ProgressResponse, Response, IMAGE_TYPE_CONTAINERD};
+    use mockall::Sequence;
+
+    use super::*;
+    use crate::controller::apiserver_mock::MockAgentCallClient;
+
+    fn upgrade_info() -> UpgradeInfo {
+        UpgradeInfo {
+            version: "v2".to_string(),
+            image_type: IMAGE_TYPE_CONTAINERD.to_string(),
+            check_sum: String::new(),
+            container_image: "kubeos:v2".to_string(),
+            imageurl: String::new(),
+            flagsafe: false,
+            mtls: false,
+            cacert: String::new(),
+            clientcert: String::new(),
+            clientkey: String::new(),
+        }
+    }
+
+    fn progress(version: &str, stage: UpgradeStage) -> ProgressResponse {
+        ProgressResponse { version: version.to_string(), stage, ..Default::default() }
+    }
+
+    #[test]
+    fn test_prepare_upgrade_method() {
+        let mut call = MockAgentCallClient::new();
+        call.expect_call_agent::<GetCapabilitiesMethod>().returning(|_x, _y| {
+            Ok(CapabilitiesResponse {
+                protocol_version: PROTOCOL_VERSION,
+                features: vec![FEATURE_PROGRESS.to_string()],
+                ..CapabilitiesResponse::legacy()
+            })
+        });
+        let mut seq = Sequence::new();
+        // the preparation is started, and polled while it is running
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("", UpgradeStage::Idle)));
+        call.expect_call_agent::<PrepareUpgradeMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(Response { status: AgentStatus::UpgradePreparing }));
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v2", UpgradeStage::Download)));
+        // os-agent restarted and lost the preparation, which is started again on the next reconcile
+        for _ in 0..2 {
+            call.expect_call_agent::<GetProgressMethod>()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_x, _y| Ok(progress("", UpgradeStage::Idle)));
+        }
+        call.expect_call_agent::<PrepareUpgradeMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(Response { status: AgentStatus::UpgradePreparing }));
+        // the preparation of another version doesn't make the requested one ready
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v3", UpgradeStage::Done)));
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v2", UpgradeStage::Done)));
+        // no preparation is started until the cancelled one stops
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v1", UpgradeStage::Cancelling)));
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v1", UpgradeStage::Cancelled)));
+        call.expect_call_agent::<PrepareUpgradeMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(Response { status: AgentStatus::UpgradePreparing }));
+        // the preparation waited for fails once its cancellation finishes
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v2", UpgradeStage::Cancelling)));
+        call.expect_call_agent::<GetProgressMethod>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_x, _y| Ok(progress("v2", UpgradeStage::Cancelled)));
+
+        let client = AgentClient::new("test", call);
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Preparing);
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Preparing);
+        let err = client.prepare_upgrade_method(upgrade_info()).unwrap_err();
+        assert!(err.to_string().contains("os-agent stopped preparing upgrade to v2"));
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Preparing);
+        let err = client.prepare_upgrade_method(upgrade_info()).unwrap_err();
+        assert!(err.to_string().contains("Done of version \"v3\""));
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Ready);
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Preparing);
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Preparing);
+        assert_eq!(client.prepare_upgrade_method(upgrade_info()).unwrap(), PrepareStatus::Preparing);
+        let err = client.prepare_upgrade_method(upgrade_info()).unwrap_err();
+        assert!(err.to_string().contains("Cancelled"));
     }
 }
@@ -62,7 +62,7 @@ impl Default for OSInstanceStatusPatch {
         OSInstanceStatusPatch {
             api_version: OSINSTANCE_API_VERSION.to_string(),
             kind: OSINSTANCE_KIND.to_string(),
-            status: Some(OSInstanceStatus { sysconfigs: None, upgradeconfigs: None }),
+            status: Some(OSInstanceStatus { sysconfigs: None, upgradeconfigs: None, drifts: None }),
         }
     }
 }
@@ -16,7 +16,8 @@ use anyhow::Result;
 use cli::{
     client::Client,
     method::{
-        callable_method::RpcMethod, configure::ConfigureMethod, prepare_upgrade::PrepareUpgradeMethod,
+        callable_method::RpcMethod, configure::ConfigureMethod, get_capabilities::GetCapabilitiesMethod,
+        get_progress::GetProgressMethod, preflight::PreflightMethod, prepare_upgrade::PrepareUpgradeMethod,
         rollback::RollbackMethod, upgrade::UpgradeMethod,
     },
 };
@@ -28,6 +29,10 @@ use kube::{
     core::{ListMeta, ObjectList},
     Client as KubeClient, Resource, ResourceExt,
 };
+use manager::api::{
+    self as agent_api, AgentStatus, CapabilitiesResponse, PreflightResponse, ProgressResponse, UpgradeStage,
+    FEATURE_PREFLIGHT, FEATURE_PROGRESS, FEATURE_WAIT, PROTOCOL_VERSION,
+};
 use mockall::mock;
 use serde_json::json;
 
@@ -496,9 +501,7 @@ pub mod mock_error {
 mock! {
     pub AgentCallClient{}
     impl AgentCall for AgentCallClient{
-        fn call_agent<T: RpcMethod + 'static>(&self, client:&Client, method: T) -> Result<(), agent_error::Error> {
-                Ok(())
-            }
+        fn call_agent<T: RpcMethod + 'static>(&self, client:&Client, method: T) -> Result<T::Response, agent_error::Error>;
     }
 
 }
@@ -508,10 +511,31 @@ impl<T: ApplyApi, U: AgentCall> ProxyController<T, U> {
         let mock_k8s_client = KubeClient::new(mock_service, "default");
         let mock_api_client = ControllerClient::new(mock_k8s_client.clone());
         let mut mock_agent_call_client = MockAgentCallClient::new();
-        mock_agent_call_client.expect_call_agent::<UpgradeMethod>().returning(|_x, _y| Ok(()));
-        mock_agent_call_client.expect_call_agent::<PrepareUpgradeMethod>().returning(|_x, _y| Ok(()));
-        mock_agent_call_client.expect_call_agent::<RollbackMethod>().returning(|_x, _y| Ok(()));
-        mock_agent_call_client.expect_call_agent::<ConfigureMethod>().returning(|_x, _y| Ok(()));
+        mock_agent_call_client.expect_call_agent::<GetCapabilitiesMethod>().returning(|_x, _y| {
+            Ok(CapabilitiesResponse {
+                protocol_version: PROTOCOL_VERSION,
+                features: vec![FEATURE_PROGRESS.to_string(), FEATURE_PREFLIGHT.to_string(), FEATURE_WAIT.to_string()],
+                ..CapabilitiesResponse::legacy()
+            })
+        });
+        mock_agent_call_client
+            .expect_call_agent::<UpgradeMethod>()
+            .returning(|_x, _y| Ok(agent_api::Response { status: AgentStatus::Upgraded }));
+        mock_agent_call_client
+            .expect_call_agent::<PreflightMethod>()
+            .returning(|_x, _y| Ok(PreflightResponse { failures: Vec::new() }));
+        mock_agent_call_client
+            .expect_call_agent::<PrepareUpgradeMethod>()
+            .returning(|_x, _y| Ok(agent_api::Response { status: AgentStatus::UpgradePreparing }));
+        mock_agent_call_client.expect_call_agent::<GetProgressMethod>().returning(|_x, _y| {
+            Ok(ProgressResponse { version: "KubeOS v2".to_string(), stage: UpgradeStage::Done, ..Default::default() })
+        });
+        mock_agent_call_client
+            .expect_call_agent::<RollbackMethod>()
+            .returning(|_x, _y| Ok(agent_api::Response { status: AgentStatus::Rollbacked }));
+        mock_agent_call_client
+            .expect_call_agent::<ConfigureMethod>()
+            .returning(|_x, _y| Ok(agent_api::Response { status: AgentStatus::Configured }));
         let mock_agent_client = AgentClient::new("test", mock_agent_call_client);
         let proxy_controller: ProxyController<ControllerClient, MockAgentCallClient> =
             ProxyController::new(mock_k8s_client, mock_api_client, mock_agent_client);
@@ -540,6 +564,7 @@ impl OSInstance {
             status: Some(OSInstanceStatus {
                 sysconfigs: Some(Configs { version: Some(String::from("v1")), configs: None }),
                 upgradeconfigs: Some(Configs { version: Some(String::from("v1")), configs: None }),
+                drifts: None,
             }),
         }
     }
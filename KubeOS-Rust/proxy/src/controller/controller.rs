@@ -10,7 +10,7 @@
  * See the Mulan PSL v2 for more details.
  */
 
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, sync::Mutex, time::Instant};
 
 use anyhow::Result;
 use drain::drain_os;
@@ -21,20 +21,25 @@ use kube::{
     runtime::controller::{Context, ReconcilerAction},
     Client, ResourceExt,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use manager::utils::Counter;
 use reconciler_error::Error;
 
 use super::{
-    agentclient::{AgentCall, AgentClient, AgentMethod, ConfigInfo, KeyInfo, Sysconfig, UpgradeInfo},
+    agentclient::{AgentCall, AgentClient, AgentMethod, ConfigInfo, KeyInfo, PrepareStatus, Sysconfig, UpgradeInfo},
     apiclient::ApplyApi,
-    crd::{Configs, Content, OSInstance, OS},
+    crd::{Configs, Content, Drift, OSInstance, OSInstanceStatus, OS},
     utils::{check_version, get_config_version, ConfigOperation, ConfigType},
     values::{
-        LABEL_CONFIGURING, LABEL_UPGRADING, NODE_STATUS_CONFIG, NODE_STATUS_IDLE, NO_REQUEUE, OPERATION_TYPE_ROLLBACK,
-        OPERATION_TYPE_UPGRADE, OSINSTANCE_NAMESPACE, REQUEUE_ERROR, REQUEUE_NORMAL,
+        DRIFT_REPORT_INTERVAL, LABEL_CONFIGURING, LABEL_UPGRADING, NODE_STATUS_CONFIG, NODE_STATUS_IDLE, NO_REQUEUE,
+        OPERATION_TYPE_ROLLBACK, OPERATION_TYPE_UPGRADE, OSINSTANCE_NAMESPACE, REQUEUE_ERROR, REQUEUE_NORMAL,
+        REQUEUE_PREPARING,
     },
 };
 
+pub static RECONCILES: Counter =
+    Counter::new("kubeos_proxy_reconciles", "Reconciles of the OS resource by os-proxy.", &["result"]);
+
 pub async fn reconcile<T: ApplyApi, U: AgentCall>(
     os: OS,
     ctx: Context<ProxyController<T, U>>,
@@ -61,6 +66,7 @@ pub async fn reconcile<T: ApplyApi, U: AgentCall>(
         }
     } else {
         debug!("osinstance correspending os name is None, not in upgrading or configuring");
+        proxy_controller.report_drift(&osinstance).await;
         return Ok(REQUEUE_NORMAL);
     }
 
@@ -122,6 +128,8 @@ pub async fn reconcile<T: ApplyApi, U: AgentCall>(
                     ConfigType::SysConfig,
                 )
                 .await?;
+        } else if osinstance.spec.nodestatus == NODE_STATUS_IDLE {
+            proxy_controller.report_drift(&osinstance).await;
         }
     } else {
         if os_cr.spec.opstype == NODE_STATUS_CONFIG {
@@ -155,7 +163,7 @@ pub async fn reconcile<T: ApplyApi, U: AgentCall>(
                 return Ok(REQUEUE_NORMAL);
             }
             proxy_controller.set_config(&mut osinstance, ConfigType::UpgradeConfig).await?;
-            proxy_controller.upgrade_node(os_cr, &node).await?;
+            return proxy_controller.upgrade_node(os_cr, &node).await;
         }
     }
     Ok(REQUEUE_NORMAL)
@@ -177,11 +185,13 @@ pub struct ProxyController<T: ApplyApi, U: AgentCall> {
     k8s_client: Client,
     controller_client: T,
     agent_client: AgentClient<U>,
+    // the sysconfigs version of the node and the time its drift was reported last
+    drift_reported: Mutex<Option<(String, Instant)>>,
 }
 
 impl<T: ApplyApi, U: AgentCall> ProxyController<T, U> {
     pub fn new(k8s_client: Client, controller_client: T, agent_client: AgentClient<U>) -> Self {
-        ProxyController { k8s_client, controller_client, agent_client }
+        ProxyController { k8s_client, controller_client, agent_client, drift_reported: Mutex::new(None) }
     }
 }
 
@@ -312,7 +322,57 @@ impl<T: ApplyApi, U: AgentCall> ProxyController<T, U> {
         Ok(())
     }
 
-    async fn upgrade_node(&self, os_cr: &OS, node: &Node) -> Result<(), Error> {
+    // report_drift updates the configuration drift of the idle node in the osinstance status when it changes, failing
+    // to get the drift doesn't fail the reconcile. The drift is reported once in DRIFT_REPORT_INTERVAL, or once the
+    // sysconfigs version changes.
+    async fn report_drift(&self, osinstance: &OSInstance) {
+        if !drift_report_due(&self.drift_reported, get_config_version(osinstance.spec.sysconfigs.as_ref())) {
+            return;
+        }
+        let drifts = match self.agent_client.drift_method() {
+            Ok(Some(drifts)) => drifts,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to get the configuration drift: {}", e);
+                return;
+            },
+        };
+        let drifts: Vec<Drift> = drifts
+            .into_iter()
+            .map(|drift| Drift {
+                model: Some(drift.model),
+                configpath: Some(drift.config_path).filter(|path| !path.is_empty()),
+                key: Some(drift.key),
+                expected: drift.expected,
+                actual: drift.actual,
+            })
+            .collect();
+        let drifts = if drifts.is_empty() { None } else { Some(drifts) };
+        if osinstance.status.as_ref().and_then(|status| status.drifts.as_ref()) == drifts.as_ref() {
+            return;
+        }
+        if let Some(drifts) = drifts.as_ref() {
+            warn!("{} configured keys drifted on node {}", drifts.len(), osinstance.name());
+        }
+        let Some(namespace) = osinstance.namespace() else {
+            return;
+        };
+        let mut status = osinstance.status.clone().unwrap_or(OSInstanceStatus {
+            sysconfigs: None,
+            upgradeconfigs: None,
+            drifts: None,
+        });
+        status.drifts = drifts;
+        if let Err(e) =
+            self.controller_client.update_osinstance_status(&osinstance.name(), &namespace, &Some(status)).await
+        {
+            warn!("Failed to update the configuration drift of osinstance {}: {}", osinstance.name(), e);
+        }
+    }
+
+    // upgrade_node starts preparing the upgrade, and drains and upgrades the node once the preparation is ready. The
+    // reconcile is requeued to poll the preparation until then
+    async fn upgrade_node(&self, os_cr: &OS, node: &Node) -> Result<ReconcilerAction, Error> {
         debug!("start upgrade node");
         match os_cr.spec.opstype.as_str() {
             OPERATION_TYPE_UPGRADE => {
@@ -330,7 +390,8 @@ impl<T: ApplyApi, U: AgentCall> ProxyController<T, U> {
                 };
 
                 match self.agent_client.prepare_upgrade_method(upgrade_info) {
-                    Ok(_resp) => {},
+                    Ok(PrepareStatus::Ready) => {},
+                    Ok(PrepareStatus::Preparing) => return Ok(REQUEUE_PREPARING),
                     Err(e) => {
                         return Err(Error::Agent { source: e });
                     },
@@ -357,7 +418,7 @@ impl<T: ApplyApi, U: AgentCall> ProxyController<T, U> {
                 return Err(Error::Operation { value: os_cr.spec.opstype.clone() });
             },
         }
-        Ok(())
+        Ok(REQUEUE_NORMAL)
     }
 
     async fn evict_node(&self, node_name: &str, evict_pod_force: bool) -> Result<(), Error> {
@@ -471,13 +532,25 @@ pub mod reconciler_error {
     }
 }
 
+// drift_report_due checks whether the drift of the node with the sysconfigs version is to be reported, and records
+// the report if so
+fn drift_report_due(reported: &Mutex<Option<(String, Instant)>>, version: String) -> bool {
+    let mut reported = reported.lock().unwrap_or_else(|e| e.into_inner());
+    if reported.as_ref().is_some_and(|(v, at)| *v == version && at.elapsed() < DRIFT_REPORT_INTERVAL) {
+        return false;
+    }
+    *reported = Some((version, Instant::now()));
+    true
+}
+
 #[cfg(test)]
 mod test {
-    use std::env;
+    use std::{env, sync::Mutex, time::Instant};
 
-    use super::{error_policy, reconcile, Context, OSInstance, ProxyController, OS};
+    use super::{drift_report_due, error_policy, reconcile, Context, OSInstance, ProxyController, OS};
     use crate::controller::{
         apiserver_mock::{timeout_after_5s, MockAgentCallClient, Testcases},
+        values::DRIFT_REPORT_INTERVAL,
         ControllerClient,
     };
 
@@ -599,4 +672,15 @@ mod test {
         reconcile(os, context.clone()).await.expect("reconciler");
         timeout_after_5s(mocksrv).await;
     }
+
+    #[test]
+    fn test_drift_report_due() {
+        let reported = Mutex::new(None);
+        assert!(drift_report_due(&reported, "v1".to_string()));
+        assert!(!drift_report_due(&reported, "v1".to_string()));
+        // a new sysconfigs version is reported at once
+        assert!(drift_report_due(&reported, "v2".to_string()));
+        *reported.lock().unwrap() = Some(("v2".to_string(), Instant::now() - DRIFT_REPORT_INTERVAL));
+        assert!(drift_report_due(&reported, "v2".to_string()));
+    }
 }
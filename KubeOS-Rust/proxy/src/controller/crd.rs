@@ -59,6 +59,17 @@ pub struct OSInstanceSpec {
 pub struct OSInstanceStatus {
     pub sysconfigs: Option<Configs>,
     pub upgradeconfigs: Option<Configs>,
+    pub drifts: Option<Vec<Drift>>,
+}
+
+/// Drift is a configured key whose value on the node no longer matches the value configured last
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq, JsonSchema)]
+pub struct Drift {
+    pub model: Option<String>,
+    pub configpath: Option<String>,
+    pub key: Option<String>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq, JsonSchema)]
@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) Huawei Technologies Co., Ltd. 2024. All rights reserved.
+ * KubeOS is licensed under the Mulan PSL v2.
+ * You can use this software according to the terms and conditions of the Mulan PSL v2.
+ * You may obtain a copy of Mulan PSL v2 at:
+ *     http://license.coscl.org.cn/MulanPSL2
+ * THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR
+ * PURPOSE.
+ * See the Mulan PSL v2 for more details.
+ */
+
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Liveness records the last time os-proxy made progress by finishing a reconcile
+#[derive(Clone)]
+pub struct Liveness {
+    inner: Arc<Mutex<LivenessState>>,
+}
+
+struct LivenessState {
+    last_active: Instant,
+    status: String,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness {
+            inner: Arc::new(Mutex::new(LivenessState { last_active: Instant::now(), status: "starting".to_string() })),
+        }
+    }
+}
+
+impl Liveness {
+    pub fn touch(&self, status: String) {
+        let mut state = self.lock();
+        state.last_active = Instant::now();
+        state.status = status;
+    }
+
+    /// Returns the status and whether os-proxy has been active within the stall timeout
+    pub fn check(&self, stall_timeout: Duration) -> (String, bool) {
+        let state = self.lock();
+        let inactive = state.last_active.elapsed();
+        if inactive > stall_timeout {
+            return (format!("{}, no progress for {}s", state.status, inactive.as_secs()), false);
+        }
+        (state.status.clone(), true)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LivenessState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_liveness() {
+        let liveness = Liveness::default();
+        assert_eq!(liveness.check(Duration::from_secs(60)), ("starting".to_string(), true));
+        liveness.clone().touch("reconcile succeeded".to_string());
+        assert_eq!(liveness.check(Duration::from_secs(60)), ("reconcile succeeded".to_string(), true));
+        thread::sleep(Duration::from_millis(20));
+        let (status, healthy) = liveness.check(Duration::from_millis(10));
+        assert!(status.starts_with("reconcile succeeded, no progress for"));
+        assert!(!healthy);
+    }
+}
@@ -16,11 +16,13 @@ mod apiclient;
 mod apiserver_mock;
 mod controller;
 mod crd;
+mod liveness;
 mod utils;
 mod values;
 
 pub use agentclient::{AgentCallClient, AgentClient};
 pub use apiclient::ControllerClient;
-pub use controller::{error_policy, reconcile, ProxyController};
+pub use controller::{error_policy, reconcile, ProxyController, RECONCILES};
 pub use crd::OS;
-pub use values::SOCK_PATH;
+pub use liveness::Liveness;
+pub use values::{METRICS_ADDRESS_ENV, RECONCILE_STALL_TIMEOUT, SOCK_PATH};
@@ -125,6 +125,7 @@ impl ConfigType {
                     osinstance.status = Some(OSInstanceStatus {
                         upgradeconfigs: osinstance.spec.upgradeconfigs.clone(),
                         sysconfigs: None,
+                        drifts: None,
                     })
                 }
             },
@@ -132,8 +133,11 @@ impl ConfigType {
                 if let Some(osi_status) = &mut osinstance.status {
                     osi_status.sysconfigs = osinstance.spec.sysconfigs.clone();
                 } else {
-                    osinstance.status =
-                        Some(OSInstanceStatus { upgradeconfigs: None, sysconfigs: osinstance.spec.sysconfigs.clone() })
+                    osinstance.status = Some(OSInstanceStatus {
+                        upgradeconfigs: None,
+                        sysconfigs: osinstance.spec.sysconfigs.clone(),
+                        drifts: None,
+                    })
                 }
             },
         }
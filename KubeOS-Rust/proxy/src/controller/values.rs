@@ -29,7 +29,19 @@ pub const OPERATION_TYPE_UPGRADE: &str = "upgrade";
 pub const OPERATION_TYPE_ROLLBACK: &str = "rollback";
 
 pub const SOCK_PATH: &str = "/run/os-agent/os-agent.sock";
+pub const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// the upgrade preparation is cancelled if it is still running after the timeout
+pub const PREPARE_UPGRADE_TIMEOUT: Duration = Duration::from_secs(3600);
+// os-agent supporting the wait feature blocks the requests until its running request finishes within the timeout
+pub const AGENT_LOCK_WAIT: Duration = Duration::from_secs(60);
+pub const RECONCILE_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+// the drift of the idle node is reported at most once in the interval unless its sysconfigs version changes
+pub const DRIFT_REPORT_INTERVAL: Duration = Duration::from_secs(300);
+// metrics are served on the address in this environment variable, such as 0.0.0.0:9102, disabled if it is not set
+pub const METRICS_ADDRESS_ENV: &str = "METRICS_ADDRESS";
 
 pub const REQUEUE_NORMAL: ReconcilerAction = ReconcilerAction { requeue_after: Some(Duration::from_secs(15)) };
 pub const REQUEUE_ERROR: ReconcilerAction = ReconcilerAction { requeue_after: Some(Duration::from_secs(1)) };
+// the running upgrade preparation is polled by requeueing rather than blocking the reconcile
+pub const REQUEUE_PREPARING: ReconcilerAction = ReconcilerAction { requeue_after: Some(PROGRESS_POLL_INTERVAL) };
 pub const NO_REQUEUE: ReconcilerAction = ReconcilerAction { requeue_after: None };
@@ -10,6 +10,8 @@
  * See the Mulan PSL v2 for more details.
  */
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use futures::{stream, StreamExt};
 use k8s_openapi::api::core::v1::{Pod, PodSpec, PodStatus};
 use kube::{
@@ -18,6 +20,7 @@ use kube::{
     Api, Client, ResourceExt,
 };
 use log::{debug, error, info};
+use manager::utils::Counter;
 use reqwest::StatusCode;
 use tokio::time::{sleep, Duration, Instant};
 use tokio_retry::{
@@ -38,6 +41,11 @@ pub const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
 pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(20);
 pub const MAX_RETRIES_TIMES: usize = 10;
 
+pub static DRAINED_PODS: Counter =
+    Counter::new("kubeos_proxy_drained_pods", "Pods evicted from the node by os-proxy.", &["result"]);
+pub static EVICTION_RETRIES: Counter =
+    Counter::new("kubeos_proxy_eviction_retries", "Retries of evicting pods by os-proxy.", &["reason"]);
+
 pub async fn drain_os(client: &Client, node_name: &str, force: bool) -> Result<(), error::DrainError> {
     let pods_list = get_pods_deleted(client, node_name, force).await?;
 
@@ -45,9 +53,9 @@ pub async fn drain_os(client: &Client, node_name: &str, force: bool) -> Result<(
         .for_each_concurrent(MAX_EVICT_POD_NUM, move |pod| {
             let k8s_client = client.clone();
             async move {
-                if evict_pod(&k8s_client, &pod, force).await.is_ok() {
-                    wait_for_deletion(&k8s_client, &pod).await.ok();
-                }
+                let drained = evict_pod(&k8s_client, &pod, force).await.is_ok()
+                    && wait_for_deletion(&k8s_client, &pod).await.is_ok();
+                DRAINED_PODS.inc(&[if drained { "drained" } else { "failed" }]);
             }
         })
         .await;
@@ -93,9 +101,13 @@ async fn evict_pod(k8s_client: &kube::Client, pod: &Pod, force: bool) -> Result<
     let error_handling_strategy =
         if force { ErrorHandleStrategy::RetryStrategy } else { ErrorHandleStrategy::TolerateStrategy };
 
+    let attempts = AtomicUsize::new(0);
     RetryIf::spawn(
         error_handling_strategy.retry_strategy(),
         || async {
+            if attempts.fetch_add(1, Ordering::Relaxed) > 0 {
+                EVICTION_RETRIES.inc(&["error"]);
+            }
             loop {
                 let eviction_result = pod_api.evict(&pod.name_any(), &EvictParams::default()).await;
 
@@ -127,6 +139,7 @@ async fn evict_pod(k8s_client: &kube::Client, pod: &Pod, force: bool) -> Result<
                                     e,
                                     EVERY_EVICTION_RETRY.as_secs_f64()
                                 );
+                                EVICTION_RETRIES.inc(&["internal_server_error"]);
                                 sleep(EVERY_EVICTION_RETRY).await;
                                 continue;
                             }
@@ -136,6 +149,7 @@ async fn evict_pod(k8s_client: &kube::Client, pod: &Pod, force: bool) -> Result<
                                     e,
                                     EVERY_EVICTION_RETRY.as_secs_f64()
                                 );
+                                EVICTION_RETRIES.inc(&["too_many_requests"]);
                                 sleep(EVERY_EVICTION_RETRY).await;
                                 continue;
                             }
@@ -10,37 +10,58 @@
  * See the Mulan PSL v2 for more details.
  */
 
+use std::env;
+
 use anyhow::Result;
-use env_logger::{Builder, Env, Target};
+use drain::{DRAINED_PODS, EVICTION_RETRIES};
 use futures::StreamExt;
 use kube::{
     api::{Api, ListParams},
     client::Client,
     runtime::controller::{Context, Controller},
 };
-use log::{error, info};
+use log::{error, info, warn};
+use manager::utils::{init_logger, result_label, sd_notify, serve_metrics, spawn_watchdog, Metric};
 mod controller;
 use controller::{
-    error_policy, reconcile, AgentCallClient, AgentClient, ControllerClient, ProxyController, OS, SOCK_PATH,
+    error_policy, reconcile, AgentCallClient, AgentClient, ControllerClient, Liveness, ProxyController,
+    METRICS_ADDRESS_ENV, OS, RECONCILES, RECONCILE_STALL_TIMEOUT, SOCK_PATH,
 };
 
 const PROXY_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+static PROXY_METRICS: [&dyn Metric; 3] = [&RECONCILES, &DRAINED_PODS, &EVICTION_RETRIES];
 #[tokio::main]
 async fn main() -> Result<()> {
-    Builder::from_env(Env::default().default_filter_or("proxy=info")).target(Target::Stdout).init();
+    init_logger("proxy=info");
     let client = Client::try_default().await?;
     let os: Api<OS> = Api::all(client.clone());
     let controller_client = ControllerClient::new(client.clone());
     let agent_call_client = AgentCallClient::default();
+    let liveness = Liveness::default();
     let agent_client = AgentClient::new(SOCK_PATH, agent_call_client);
     let proxy_controller = ProxyController::new(client, controller_client, agent_client);
     info!("os-proxy version is {}, start renconcile", PROXY_VERSION.unwrap_or("Not Found"));
+    if let Err(e) = sd_notify("READY=1\nSTATUS=starting") {
+        warn!("{:#}", e);
+    }
+    if let Ok(address) = env::var(METRICS_ADDRESS_ENV) {
+        serve_metrics(&address, &PROXY_METRICS)?;
+    }
+    let watchdog_liveness = liveness.clone();
+    spawn_watchdog(move || watchdog_liveness.check(RECONCILE_STALL_TIMEOUT))?;
     Controller::new(os, ListParams::default())
         .run(reconcile, error_policy, Context::new(proxy_controller))
-        .for_each(|res| async move {
-            match res {
-                Ok(_o) => {},
-                Err(e) => error!("reconcile failed: {}", e.to_string()),
+        .for_each(|res| {
+            let liveness = liveness.clone();
+            async move {
+                RECONCILES.inc(&[result_label(&res)]);
+                match res {
+                    Ok(_o) => liveness.touch("reconcile succeeded".to_string()),
+                    Err(e) => {
+                        error!("reconcile failed: {}", e.to_string());
+                        liveness.touch(format!("reconcile failed: {}", e));
+                    },
+                }
             }
         })
         .await;
@@ -127,6 +127,22 @@ type OSInstanceStatus struct {
 	SysConfigs SysConfigs `json:"sysconfigs"`
 	// +kubebuilder:validation:Optional
 	UpgradeConfigs SysConfigs `json:"upgradeconfigs"`
+	// +kubebuilder:validation:Optional
+	Drifts []Drift `json:"drifts,omitempty"`
+}
+
+// Drift defines a configured key whose value on the node no longer matches the value configured last
+type Drift struct {
+	// +kubebuilder:validation:Optional
+	Model string `json:"model"`
+	// +kubebuilder:validation:Optional
+	ConfigPath string `json:"configpath"`
+	// +kubebuilder:validation:Optional
+	Key string `json:"key"`
+	// +kubebuilder:validation:Optional
+	Expected string `json:"expected,omitempty"`
+	// +kubebuilder:validation:Optional
+	Actual string `json:"actual,omitempty"`
 }
 
 // OSInstanceSpec defines desired state of OS